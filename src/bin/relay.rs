@@ -0,0 +1,47 @@
+extern crate clap;
+extern crate tokio;
+
+use clap::{App, Arg, ErrorKind};
+use p2p::relay::RelayServer;
+use std::net::SocketAddr;
+
+#[tokio::main]
+pub async fn main() {
+    let matches = App::new("Peer-to-peer relay server")
+        .version("0.0.1")
+        .about("Relays traffic between peers which can't reach each other")
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .help("Address the relay listens on")
+                .default_value("0.0.0.0:3479"),
+        )
+        .arg(
+            Arg::with_name("public-ip")
+                .short("p")
+                .long("public-ip")
+                .help("Address peers reach the relay on, required when listening on all interfaces")
+                .takes_value(true)
+                .required(false),
+        )
+        .get_matches();
+    let listen: SocketAddr = matches
+        .value_of("listen")
+        .unwrap()
+        .parse()
+        .expect("Invalid listen address");
+    let public_ip = matches
+        .value_of("public-ip")
+        .map(|ip| ip.parse().expect("Invalid public address"));
+    if public_ip.is_none() && listen.ip().is_unspecified() {
+        clap::Error::with_description(
+            "--public-ip is required when listening on all interfaces",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let server = RelayServer::bind(listen, public_ip).await.unwrap();
+    println!("Relaying on {}", server.local_addr().unwrap());
+    server.run().await.unwrap();
+}
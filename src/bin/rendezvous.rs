@@ -0,0 +1,28 @@
+extern crate clap;
+extern crate tokio;
+
+use clap::{App, Arg};
+use p2p::rendezvous::RendezvousServer;
+
+#[tokio::main]
+pub async fn main() {
+    let matches = App::new("Peer-to-peer rendezvous server")
+        .version("0.0.1")
+        .about("Exchanges the addresses of peers which share a short code")
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .help("Address the rendezvous server listens on")
+                .default_value("0.0.0.0:3480"),
+        )
+        .get_matches();
+    let listen = matches
+        .value_of("listen")
+        .unwrap()
+        .parse()
+        .expect("Invalid listen address");
+    let server = RendezvousServer::bind(listen).await.unwrap();
+    println!("Waiting for peers on {}", server.local_addr().unwrap());
+    server.run().await.unwrap();
+}
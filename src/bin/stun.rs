@@ -0,0 +1,39 @@
+extern crate clap;
+extern crate tokio;
+
+use clap::{App, Arg};
+use p2p::stun::StunServer;
+
+#[tokio::main]
+pub async fn main() {
+    let matches = App::new("Peer-to-peer STUN server")
+        .version("0.0.1")
+        .about("Tells peers their external address")
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .help("Address the server listens on")
+                .default_value("0.0.0.0:3478"),
+        )
+        .arg(
+            Arg::with_name("alternate-port")
+                .short("a")
+                .long("alternate-port")
+                .help("Second port, enables NAT filtering detection (RFC 5780)")
+                .takes_value(true)
+                .required(false),
+        )
+        .get_matches();
+    let listen = matches
+        .value_of("listen")
+        .unwrap()
+        .parse()
+        .expect("Invalid listen address");
+    let alternate_port = matches
+        .value_of("alternate-port")
+        .map(|port| port.parse().expect("Invalid alternate port"));
+    let server = StunServer::bind(listen, alternate_port).await.unwrap();
+    println!("Serving STUN on {}", server.local_addr().unwrap());
+    server.run().await.unwrap();
+}
@@ -1,13 +1,13 @@
 use std::iter;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Bitfield {
     bytes: Vec<u8>,
 }
 
 impl Bitfield {
     pub fn new() -> Self {
-        Self { bytes: Vec::new() }
+        Self::default()
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Self {
@@ -45,11 +45,11 @@ impl Bitfield {
         let required_size = idx / 8 + 1;
         if self.bytes.len() < required_size {
             self.bytes
-                .extend(iter::repeat(0).take(required_size - self.bytes.len()))
+                .extend(iter::repeat_n(0, required_size - self.bytes.len()))
         }
     }
 
-    pub fn iter(&self) -> BitfieldIterator {
+    pub fn iter(&self) -> BitfieldIterator<'_> {
         BitfieldIterator {
             bitfield: self,
             idx: 0,
@@ -0,0 +1,116 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// We only ever use a single component (RFC 8445 calls the RTP stream component 1)
+const COMPONENT_ID: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// Address of a local interface
+    Host,
+    /// Address assigned by the NAT, as seen by the STUN server
+    ServerReflexive,
+    /// Address assigned by the NAT, as seen by the peer during connectivity checks
+    PeerReflexive,
+    /// Address a symmetric NAT will probably assign next
+    Predicted,
+    /// Port the gateway forwards to us, after we asked it with PCP, NAT-PMP or UPnP
+    PortMapped,
+    /// Address allocated on a relay server
+    Relayed,
+}
+
+impl CandidateKind {
+    /// Type preferences recommended by RFC 8445 section 5.1.2.2
+    fn type_preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            //As good as a public host address
+            CandidateKind::PortMapped => 120,
+            CandidateKind::PeerReflexive => 110,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Predicted => 50,
+            CandidateKind::Relayed => 0,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            CandidateKind::Host => 0,
+            CandidateKind::ServerReflexive => 1,
+            CandidateKind::PeerReflexive => 2,
+            CandidateKind::Relayed => 3,
+            CandidateKind::Predicted => 4,
+            CandidateKind::PortMapped => 5,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(CandidateKind::Host),
+            1 => Some(CandidateKind::ServerReflexive),
+            2 => Some(CandidateKind::PeerReflexive),
+            3 => Some(CandidateKind::Relayed),
+            4 => Some(CandidateKind::Predicted),
+            5 => Some(CandidateKind::PortMapped),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub address: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// Candidates are listed in order of preference, `index` is the position in that list
+    pub fn new(kind: CandidateKind, address: SocketAddr, index: usize) -> Self {
+        Self {
+            kind,
+            address,
+            priority: priority(kind, 65535 - index.min(65535) as u32),
+        }
+    }
+}
+
+/// Candidate priority as defined in RFC 8445 section 5.1.2.1
+pub fn priority(kind: CandidateKind, local_preference: u32) -> u32 {
+    (kind.type_preference() << 24) + (local_preference << 8) + (256 - COMPONENT_ID)
+}
+
+/// Candidate pair priority as defined in RFC 8445 section 6.1.2.3
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (1 << 32) * g.min(d) + 2 * g.max(d) + if g > d { 1 } else { 0 }
+}
+
+/// Addresses of the interfaces which are used to reach the internet, with the given port.
+/// IPv6 addresses are only included if the socket is dual-stack
+pub fn host_addresses(local: SocketAddr) -> Vec<SocketAddr> {
+    let mut targets = Vec::new();
+    if local.is_ipv6() {
+        targets.push("[2001:4860:4860::8888]:53");
+    }
+    targets.push("8.8.8.8:53");
+    targets
+        .into_iter()
+        .filter_map(|target| {
+            let target: SocketAddr = target.parse().ok()?;
+            let bind: SocketAddr = match target.ip() {
+                IpAddr::V4(_) => "0.0.0.0:0".parse().ok()?,
+                IpAddr::V6(_) => "[::]:0".parse().ok()?,
+            };
+            //Connecting a UDP socket sends nothing, but picks the outgoing interface
+            let socket = UdpSocket::bind(bind).ok()?;
+            socket.connect(target).ok()?;
+            let ip = socket.local_addr().ok()?.ip();
+            match ip {
+                IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) == 0xfe80 => None,
+                ip if ip.is_loopback() || ip.is_unspecified() => None,
+                ip => Some(SocketAddr::new(ip, local.port())),
+            }
+        })
+        .collect()
+}
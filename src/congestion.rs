@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+const INITIAL_WINDOW: f64 = 16.0;
+const MIN_WINDOW: f64 = 4.0;
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+
+/// Decides how many chunks may be in flight and how fast they are sent
+pub trait CongestionController: Send {
+    /// Maximum number of unacknowledged chunks
+    fn window(&self) -> u32;
+    /// Time to wait between two chunk sends
+    fn pacing_interval(&self) -> Duration;
+    /// `count` chunks were acknowledged, `rtt` is the newest round trip sample
+    fn on_ack(&mut self, count: u32, rtt: Duration);
+    /// `count` chunks were lost. Called once per loss event
+    fn on_loss(&mut self, count: u32);
+}
+
+/// Creates a controller from its command line name
+pub fn from_name(name: &str) -> Option<Box<dyn CongestionController>> {
+    match name {
+        "aimd" => Some(Box::new(Aimd::new())),
+        "vegas" => Some(Box::new(Vegas::new())),
+        "none" => Some(Box::new(Unlimited {})),
+        _ => None,
+    }
+}
+
+fn smooth(srtt: Option<Duration>, sample: Duration) -> Duration {
+    match srtt {
+        Some(srtt) => (srtt * 7 + sample) / 8,
+        None => sample,
+    }
+}
+
+fn pace(rtt: Option<Duration>, window: f64) -> Duration {
+    rtt.unwrap_or(INITIAL_RTT).div_f64(window)
+}
+
+/// Additive increase, multiplicative decrease with slow start (TCP Reno like)
+pub struct Aimd {
+    window: f64,
+    threshold: f64,
+    srtt: Option<Duration>,
+}
+
+impl Aimd {
+    pub fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            threshold: f64::INFINITY,
+            srtt: None,
+        }
+    }
+}
+
+impl Default for Aimd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionController for Aimd {
+    fn window(&self) -> u32 {
+        self.window as u32
+    }
+
+    fn pacing_interval(&self) -> Duration {
+        pace(self.srtt, self.window)
+    }
+
+    fn on_ack(&mut self, count: u32, rtt: Duration) {
+        self.srtt = Some(smooth(self.srtt, rtt));
+        if self.window < self.threshold {
+            self.window += count as f64;
+        } else {
+            self.window += count as f64 / self.window;
+        }
+    }
+
+    fn on_loss(&mut self, _count: u32) {
+        self.threshold = (self.window / 2.0).max(MIN_WINDOW);
+        self.window = self.threshold;
+    }
+}
+
+/// Delay based controller (TCP Vegas like). Keeps the number of chunks
+/// queued at the bottleneck between `ALPHA` and `BETA`
+pub struct Vegas {
+    window: f64,
+    base_rtt: Option<Duration>,
+    srtt: Option<Duration>,
+}
+
+impl Vegas {
+    const ALPHA: f64 = 2.0;
+    const BETA: f64 = 4.0;
+
+    pub fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            base_rtt: None,
+            srtt: None,
+        }
+    }
+}
+
+impl Default for Vegas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionController for Vegas {
+    fn window(&self) -> u32 {
+        self.window as u32
+    }
+
+    fn pacing_interval(&self) -> Duration {
+        pace(self.srtt, self.window)
+    }
+
+    fn on_ack(&mut self, count: u32, rtt: Duration) {
+        let base_rtt = self.base_rtt.map_or(rtt, |base| base.min(rtt));
+        self.base_rtt = Some(base_rtt);
+        self.srtt = Some(smooth(self.srtt, rtt));
+        //Estimated number of our chunks sitting in queues
+        let queued = self.window * (1.0 - base_rtt.as_secs_f64() / rtt.as_secs_f64().max(1e-6));
+        let step = count as f64 / self.window;
+        if queued < Self::ALPHA {
+            self.window += step;
+        } else if queued > Self::BETA {
+            self.window = (self.window - step).max(MIN_WINDOW);
+        }
+    }
+
+    fn on_loss(&mut self, _count: u32) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+}
+
+/// No congestion control at all. Only useful for comparisons
+pub struct Unlimited {}
+
+impl CongestionController for Unlimited {
+    fn window(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn pacing_interval(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn on_ack(&mut self, _count: u32, _rtt: Duration) {}
+
+    fn on_loss(&mut self, _count: u32) {}
+}
@@ -0,0 +1,21 @@
+use crate::{message::Messages, stun};
+
+/// Kinds of datagrams which share our socket
+pub enum Packet {
+    Message(Messages),
+    /// Answers of STUN servers
+    Stun(Vec<u8>),
+    Unknown,
+}
+
+/// Tells the kind of a received datagram. Our messages start with `message::MAGIC`,
+/// which can never be the start of a STUN message
+pub fn demultiplex(datagram: &[u8]) -> Packet {
+    if stun::is_stun(datagram) {
+        return Packet::Stun(datagram.to_vec());
+    }
+    match Messages::from_datagram(datagram) {
+        Some(message) => Packet::Message(message),
+        None => Packet::Unknown,
+    }
+}
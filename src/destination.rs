@@ -0,0 +1,177 @@
+use crate::{error::Error, manifest::MAX_PATH_SIZE};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Longest name of a single file or directory on common file systems
+const MAX_NAME_SIZE: usize = 255;
+/// Names which refer to devices on Windows, with any extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+/// Files are written here until they are verified
+const PART_EXTENSION: &str = "part";
+
+/// What happens when a received file already exists
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// The file is saved under a new name, like `name (1).txt`
+    Rename,
+    Overwrite,
+    /// The sender is told that the file is not wanted
+    Skip,
+    /// The user decides for each file
+    Ask,
+}
+
+impl Collision {
+    pub const NAMES: [&'static str; 4] = ["rename", "overwrite", "skip", "ask"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rename" => Some(Self::Rename),
+            "overwrite" => Some(Self::Overwrite),
+            "skip" => Some(Self::Skip),
+            "ask" => Some(Self::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// Checks a relative path supplied by the peer, which must stay inside the download directory.
+/// Anything which could mean something else on one of the platforms is refused
+pub fn sanitize(name: &str) -> Result<PathBuf, Error> {
+    let refuse = || Error::new(&format!("Refusing the unsafe path {:?}", name));
+    if name.is_empty() || name.len() > MAX_PATH_SIZE {
+        return Err(refuse());
+    }
+    let mut path = PathBuf::new();
+    for component in name.split('/') {
+        let stem = component.split('.').next().unwrap_or_default();
+        if component.is_empty()
+            || component == "."
+            || component == ".."
+            || component.len() > MAX_NAME_SIZE
+            || component.ends_with('.')
+            || component.ends_with(' ')
+            || component
+                .chars()
+                .any(|c| c.is_control() || c == '\\' || c == ':')
+            || RESERVED_NAMES.contains(&stem.to_ascii_lowercase().as_str())
+        {
+            return Err(refuse());
+        }
+        path.push(component);
+    }
+    Ok(path)
+}
+
+/// Where a file is written until it is verified
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PART_EXTENSION);
+    path.with_file_name(name)
+}
+
+fn is_link(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+/// Where received files go, and what happens to the ones which already exist
+pub struct Destination {
+    dir: PathBuf,
+    collision: Collision,
+}
+
+impl Destination {
+    pub fn new(dir: PathBuf, collision: Collision) -> Self {
+        Self { dir, collision }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Location of `name` inside the download directory. Symbolic links on the way are
+    /// refused, they could point anywhere
+    pub fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        let relative = sanitize(name)?;
+        let mut path = self.dir.clone();
+        for component in relative.components() {
+            path.push(component);
+            if is_link(&path) {
+                return Err(Error::new(&format!(
+                    "Refusing to write through the symbolic link {}",
+                    path.display()
+                )));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Path the file `name` is saved at, following the collision policy.
+    /// Returns None if the file is skipped
+    pub async fn choose(&self, name: &str) -> Result<Option<PathBuf>, Error> {
+        let path = self.path(name)?;
+        let collision = match (path.exists(), self.collision) {
+            (false, _) => return Ok(Some(path)),
+            (true, Collision::Ask) => ask(&path).await?,
+            (true, collision) => collision,
+        };
+        let path = match collision {
+            Collision::Overwrite => path,
+            Collision::Skip => return Ok(None),
+            _ => free_name(&path),
+        };
+        if is_link(&part_path(&path)) {
+            return Err(Error::new(&format!(
+                "Refusing to write through the symbolic link {}",
+                part_path(&path).display()
+            )));
+        }
+        Ok(Some(path))
+    }
+}
+
+/// The first of `name (1).ext`, `name (2).ext`, ... which does not exist yet
+fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|i| path.with_file_name(format!("{} ({}){}", stem, i, extension)))
+        .find(|candidate| !candidate.exists() && !is_link(candidate))
+        .unwrap()
+}
+
+/// Asks the user what to do with the existing file at `path`
+async fn ask(path: &Path) -> Result<Collision, Error> {
+    let mut line_stream = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!(
+            "{} already exists. [r]ename, [o]verwrite or [s]kip? ",
+            path.display()
+        );
+        std::io::stdout().flush()?;
+        let line = match line_stream.next_line().await? {
+            Some(line) => line,
+            None => {
+                println!("No answer, skipping the file");
+                return Ok(Collision::Skip);
+            }
+        };
+        match line.trim() {
+            "r" | "rename" => return Ok(Collision::Rename),
+            "o" | "overwrite" => return Ok(Collision::Overwrite),
+            "s" | "skip" => return Ok(Collision::Skip),
+            _ => println!("Invalid choice"),
+        }
+    }
+}
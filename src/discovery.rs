@@ -0,0 +1,184 @@
+use crate::{
+    error::Error,
+    message::{printable, AnnounceMessage, ConnectRequestMessage, Messages},
+    networking::{map_to_family, unmap, RECEIVE_BUFFER_SIZE},
+    obfuscator::AddressInfo,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// Multicast group receivers announce themselves to
+pub const DISCOVERY_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 80, 80), 45380);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_RTO: Duration = Duration::from_millis(250);
+/// The receiver has to confirm the request first
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Receiver found on the LAN
+#[derive(Clone)]
+pub struct Peer {
+    /// Name the receiver announced, only with printable characters
+    pub name: String,
+    pub info: AddressInfo,
+}
+
+/// Announces us until it is dropped
+pub struct Announcer {
+    _stop: oneshot::Sender<()>,
+}
+
+/// Announces our code `info` under `name` to the group every second
+pub fn announce(name: &str, info: AddressInfo, group: SocketAddrV4) -> Result<Announcer, Error> {
+    let mut socket = UdpSocket::from_std(std::net::UdpSocket::bind("0.0.0.0:0")?)?;
+    let announcement = Messages::Announce(AnnounceMessage {
+        name: name.into(),
+        info,
+    })
+    .to_datagram();
+    let (stop, mut stopped) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = &mut stopped => break,
+                _ = interval.tick() => {
+                    socket.send_to(&announcement, &SocketAddr::V4(group)).await.ok();
+                }
+            }
+        }
+    });
+    Ok(Announcer { _stop: stop })
+}
+
+/// Listens for announcements on the LAN
+pub struct Browser {
+    socket: UdpSocket,
+}
+
+impl Browser {
+    /// Joins the multicast `group`. Several browsers on one host can share the port
+    pub fn bind(group: SocketAddrV4) -> Result<Self, Error> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()).into())?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket.into_udp_socket())?;
+        Ok(Self { socket })
+    }
+
+    /// Waits for the next announcement
+    pub async fn next(&mut self) -> Result<Peer, Error> {
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            let (size, _) = self.socket.recv_from(&mut buf).await?;
+            if let Some(Messages::Announce(msg)) = Messages::from_datagram(&buf[..size]) {
+                return Ok(Peer {
+                    name: printable(&msg.name),
+                    info: msg.info,
+                });
+            }
+        }
+    }
+
+    /// Waits for the receiver called `name`
+    pub async fn find(&mut self, name: &str) -> Result<Peer, Error> {
+        loop {
+            let peer = self.next().await?;
+            if peer.name == name {
+                return Ok(peer);
+            }
+        }
+    }
+
+    /// Every receiver announced within `duration`, sorted by name
+    pub async fn browse(&mut self, duration: Duration) -> Vec<Peer> {
+        let mut peers = HashMap::new();
+        tokio::time::timeout(duration, async {
+            while let Ok(peer) = self.next().await {
+                peers.insert(peer.name.clone(), peer);
+            }
+        })
+        .await
+        .ok();
+        let mut peers: Vec<Peer> = peers.into_values().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        peers
+    }
+}
+
+/// Asks `peer` to connect to our code `info`. The peer answers by starting its
+/// connectivity checks, the first of them is dropped here and repeated by the peer
+pub async fn request_connection(
+    socket: &mut UdpSocket,
+    peer: &Peer,
+    name: &str,
+    info: &AddressInfo,
+) -> Result<(), Error> {
+    let local_addr = socket.local_addr()?;
+    let request = Messages::ConnectRequest(ConnectRequestMessage {
+        name: name.into(),
+        info: info.clone(),
+    })
+    .to_datagram();
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    let started = Instant::now();
+    while started.elapsed() < REQUEST_TIMEOUT {
+        for candidate in peer.info.candidates.iter() {
+            //IPv4 sockets can't reach IPv6 addresses
+            if local_addr.is_ipv4() && candidate.address.is_ipv6() {
+                continue;
+            }
+            let target = map_to_family(candidate.address, local_addr);
+            socket.send_to(&request, &target).await.ok();
+        }
+        let answer = tokio::time::timeout(REQUEST_RTO, async {
+            loop {
+                let (_, from) = socket.recv_from(&mut buf).await?;
+                let from = unmap(from);
+                if peer.info.candidates.iter().any(|c| c.address == from) {
+                    return Ok::<(), Error>(());
+                }
+            }
+        })
+        .await;
+        if let Ok(result) = answer {
+            return result;
+        }
+    }
+    Err(Error::timeout(&format!("{} did not answer", peer.name)))
+}
+
+/// Whether `ip` can only be reached from the local network: private,
+/// unique local, link-local or loopback addresses
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Name we are announced under, unless another one is chosen
+pub fn default_name() -> String {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(name) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+            return name.trim().into();
+        }
+    }
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "p2p".into())
+}
@@ -1,16 +1,43 @@
 use std::fmt::Display;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    /// The peer stopped answering
+    Timeout,
+    /// The connection failed or was closed
+    Disconnected,
+}
+
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
     message: String,
 }
 
 impl Error {
     pub fn new(msg: &str) -> Self {
+        Self::with_kind(ErrorKind::Other, msg)
+    }
+
+    pub fn timeout(msg: &str) -> Self {
+        Self::with_kind(ErrorKind::Timeout, msg)
+    }
+
+    pub fn disconnected(msg: &str) -> Self {
+        Self::with_kind(ErrorKind::Disconnected, msg)
+    }
+
+    pub fn with_kind(kind: ErrorKind, msg: &str) -> Self {
         Self {
+            kind,
             message: msg.into(),
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 
 macro_rules! implement_error {
@@ -18,6 +45,7 @@ macro_rules! implement_error {
         impl From<$t> for Error {
             fn from(e: $t) -> Self {
                 Self {
+                    kind: ErrorKind::Other,
                     message: format!(concat!($message, ": {}"), e),
                 }
             }
@@ -28,6 +56,14 @@ macro_rules! implement_error {
 implement_error!(bs58::decode::Error, "Base58 error");
 implement_error!(std::io::Error, "IO error");
 implement_error!(std::array::TryFromSliceError, "Array conversion error");
+implement_error!(std::num::TryFromIntError, "Integer conversion error");
+implement_error!(igd::SearchError, "UPnP gateway search error");
+implement_error!(igd::AddPortError, "UPnP port mapping error");
+implement_error!(igd::AddAnyPortError, "UPnP port mapping error");
+implement_error!(igd::GetExternalIpError, "UPnP external address error");
+implement_error!(igd::RemovePortError, "UPnP port removal error");
+implement_error!(snow::Error, "Noise error");
+implement_error!(chacha20poly1305::Error, "Encryption error");
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
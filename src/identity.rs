@@ -0,0 +1,257 @@
+use crate::{error::Error, words::WORDS};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+/// Signatures of other protocols over the same bytes are refused
+const DOMAIN: &[u8] = b"p2p identity";
+const IDENTITY_FILE: &str = "identity";
+const KNOWN_PEERS_FILE: &str = "known_peers";
+/// Words of a fingerprint, 64 bits of the hash of the key
+const FINGERPRINT_WORDS: usize = 8;
+
+pub type PublicKey = [u8; PUBLIC_KEY_SIZE];
+
+/// Directory of the identity and the known peers, following the conventions of the platform
+pub fn config_dir() -> PathBuf {
+    let env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(dir) = env("XDG_CONFIG_HOME") {
+        return Path::new(&dir).join("p2p");
+    }
+    if let Some(dir) = env("APPDATA") {
+        return Path::new(&dir).join("p2p");
+    }
+    match env("HOME") {
+        Some(home) => Path::new(&home).join(".config").join("p2p"),
+        None => PathBuf::from(".p2p"),
+    }
+}
+
+/// Long-term key of this installation, the partner recognizes us by it
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    /// Reads the identity in `dir`, creating one on the first run
+    pub fn load_or_create(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(IDENTITY_FILE);
+        if path.exists() {
+            let encoded = fs::read_to_string(&path)?;
+            let secret = bs58::decode(encoded.trim()).into_vec()?;
+            let secret = secret.as_slice().try_into()?;
+            return Ok(Self {
+                key: SigningKey::from_bytes(secret),
+            });
+        }
+        let identity = Self::generate();
+        fs::create_dir_all(dir)?;
+        let mut file = private_file(&path)?;
+        writeln!(
+            file,
+            "{}",
+            bs58::encode(identity.key.to_bytes()).into_string()
+        )?;
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// Signs the hash of the handshake of a connection, which proves that we are on it
+    pub fn sign(&self, binding: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.key.sign(&[DOMAIN, binding].concat()).to_bytes()
+    }
+}
+
+/// Whether `signature` of `key` was made by `Identity::sign` over `binding`
+pub fn verify(key: &PublicKey, binding: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+    let key = match VerifyingKey::from_bytes(key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let signature = Signature::from_bytes(signature);
+    key.verify(&[DOMAIN, binding].concat(), &signature).is_ok()
+}
+
+/// Words derived from `key`, short enough to be compared by both users
+pub fn fingerprint(key: &PublicKey) -> String {
+    let hash = Sha256::digest(key);
+    let words: Vec<&str> = hash[..FINGERPRINT_WORDS]
+        .iter()
+        .map(|b| WORDS[*b as usize])
+        .collect();
+    words.join("-")
+}
+
+#[cfg(unix)]
+fn private_file(path: &Path) -> Result<fs::File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &Path) -> Result<fs::File, Error> {
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?)
+}
+
+/// Outcome of checking a key against the known peers
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trust {
+    /// The key belongs to the nickname
+    Known,
+    /// First contact, the key was not seen before
+    Unknown,
+    /// The nickname belongs to another key
+    Changed(PublicKey),
+}
+
+/// Keys of peers we talked to before, like `known_hosts` of SSH.
+/// Each line holds a nickname and the base58 key
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: Vec<(String, PublicKey)>,
+}
+
+impl KnownPeers {
+    /// Reads the known peers in `dir`, lines which can't be read are skipped
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(KNOWN_PEERS_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let peers = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let nickname = fields.next()?;
+                let key = bs58::decode(fields.next()?).into_vec().ok()?;
+                Some((nickname.into(), key.as_slice().try_into().ok()?))
+            })
+            .collect();
+        Ok(Self { path, peers })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Nickname of `key`, if we know it
+    pub fn nickname(&self, key: &PublicKey) -> Option<&str> {
+        self.peers
+            .iter()
+            .find(|(_, known)| known == key)
+            .map(|(nickname, _)| nickname.as_str())
+    }
+
+    /// Checks `key` against the one stored for `nickname`
+    pub fn check(&self, nickname: &str, key: &PublicKey) -> Trust {
+        match self.peers.iter().find(|(known, _)| known == nickname) {
+            Some((_, known)) if known == key => Trust::Known,
+            Some((_, known)) => Trust::Changed(*known),
+            None => Trust::Unknown,
+        }
+    }
+
+    /// Remembers `key` under `nickname`
+    pub fn add(&mut self, nickname: &str, key: PublicKey) -> Result<(), Error> {
+        if nickname.is_empty() || nickname.contains(char::is_whitespace) {
+            return Err(Error::new("Nicknames can't contain spaces"));
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", nickname, bs58::encode(key).into_string())?;
+        self.peers.push((nickname.into(), key));
+        Ok(())
+    }
+
+    /// Decides whether to go on with the partner presenting `key`. New keys are trusted on first
+    /// use and remembered under `nickname`, a known nickname with a different key is refused
+    pub fn trust(&mut self, nickname: Option<&str>, key: &PublicKey) -> Result<(), Error> {
+        //Names of peers on the local network can contain anything
+        let nickname = nickname.and_then(sanitize_nickname);
+        let nickname = match nickname.as_deref() {
+            Some(nickname) => nickname,
+            None => {
+                match self.nickname(key) {
+                    Some(known) => println!("Your partner is {}", known),
+                    None => {
+                        println!("Your partner's fingerprint is {}", fingerprint(key));
+                        println!("Use --peer <nickname> to remember it");
+                    }
+                }
+                return Ok(());
+            }
+        };
+        match self.check(nickname, key) {
+            Trust::Known => println!("Your partner is {}", nickname),
+            Trust::Unknown => {
+                println!("First contact with {}", nickname);
+                println!("Fingerprint: {}", fingerprint(key));
+                println!("Compare it with your partner, it is checked on every later connection");
+                self.add(nickname, *key)?;
+            }
+            Trust::Changed(known) => {
+                println!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                println!(
+                    "@  WARNING: {} PRESENTED A DIFFERENT IDENTITY KEY!",
+                    nickname
+                );
+                println!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                println!(
+                    "Someone could be posing as {}, or it was reinstalled",
+                    nickname
+                );
+                println!("Known fingerprint:     {}", fingerprint(&known));
+                println!("Presented fingerprint: {}", fingerprint(key));
+                println!(
+                    "If your partner confirms the new fingerprint, remove {} from {}",
+                    nickname,
+                    self.path.display()
+                );
+                return Err(Error::new(&format!(
+                    "The identity of {} changed, the connection was aborted",
+                    nickname
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `name` with its whitespace replaced, so it can be stored in the known peers file
+pub fn sanitize_nickname(name: &str) -> Option<String> {
+    let nickname = name.split_whitespace().collect::<Vec<_>>().join("-");
+    if nickname.is_empty() {
+        return None;
+    }
+    Some(nickname)
+}
@@ -0,0 +1,59 @@
+use crate::error::Error;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+pub const HASH_SIZE: usize = 32;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// SHA-256 of a whole file
+pub type FileHash = [u8; HASH_SIZE];
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 of `bytes`, as used by zip and ethernet
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes.iter() {
+        crc = (crc >> 8) ^ CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+/// SHA-256 of everything `reader` returns
+pub fn hash_reader<T: Read>(reader: &mut T) -> Result<FileHash, Error> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    loop {
+        let size = reader.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buf[..size]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Hex form of `hash` for the user
+pub fn to_hex(hash: &FileHash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
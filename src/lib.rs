@@ -0,0 +1,27 @@
+pub mod bitfield;
+pub mod candidate;
+pub mod congestion;
+pub mod demux;
+pub mod destination;
+pub mod discovery;
+pub mod error;
+pub mod identity;
+pub mod integrity;
+pub mod manifest;
+pub mod merkle;
+pub mod message;
+pub mod mtu;
+pub mod nat;
+pub mod networking;
+pub mod obfuscator;
+pub mod pake;
+pub mod portmap;
+pub mod receiver;
+pub mod relay;
+pub mod rendezvous;
+pub mod resume;
+pub mod secure;
+pub mod stun;
+mod test;
+pub mod transmitter;
+pub mod words;
@@ -1,25 +1,351 @@
 extern crate clap;
-extern crate stunclient;
 extern crate tokio;
 
-pub mod bitfield;
-pub mod error;
-pub mod message;
-pub mod networking;
-pub mod obfuscator;
-mod receiver;
-mod test;
-mod transmitter;
-
 use clap::{App, Arg};
 use clipboard::{ClipboardContext, ClipboardProvider};
-use obfuscator::AddressInfo;
-use std::{io::Write, net::IpAddr, net::SocketAddr, path::Path};
-use tokio::io::AsyncBufReadExt;
+use p2p::{
+    candidate::{self, Candidate, CandidateKind},
+    congestion,
+    demux::{self, Packet},
+    destination::{Collision, Destination},
+    discovery,
+    error::Error,
+    identity::{self, Identity, KnownPeers},
+    message::{printable, Messages, RelayAllocateMessage, RendezvousRegisterMessage},
+    nat,
+    networking::{self, ConnectionState, Introduction},
+    obfuscator::{AddressInfo, Session},
+    pake::{self, SECRET_SIZE},
+    portmap::{Lease, PortMapper},
+    receiver, relay,
+    rendezvous::{self, Code},
+    stun, transmitter,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+};
 use tokio::io::BufReader;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, Lines};
+use tokio::net::UdpSocket;
 use tokio::time::Duration;
 
 const PING_INTERVAL: u64 = 10;
+/// How long receivers on the LAN are listed before the user picks one
+const BROWSE_TIME: Duration = Duration::from_secs(3);
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Several servers are needed to detect symmetric NATs
+const STUN_SERVERS: [&str; 3] = [
+    "stun.l.google.com:19302",
+    "stun1.l.google.com:19302",
+    "stun2.l.google.com:19302",
+];
+
+/// Resolves the STUN servers, keeping one address per family of each
+fn resolve_stun_servers(names: &[&str], local: SocketAddr) -> Vec<SocketAddr> {
+    let mut servers = Vec::new();
+    for name in names.iter() {
+        let mut addresses: Vec<SocketAddr> = match name.to_socket_addrs() {
+            Ok(addresses) => addresses
+                //IPv4 sockets can't reach IPv6 servers
+                .filter(|server| local.is_ipv6() || server.is_ipv4())
+                .collect(),
+            Err(_) => {
+                println!("Could not resolve the STUN server {}", name);
+                continue;
+            }
+        };
+        addresses.sort_by_key(|server| server.is_ipv6());
+        addresses.dedup_by_key(|server| server.is_ipv6());
+        servers.extend(addresses);
+    }
+    servers
+}
+
+/// Collects the addresses the peer might reach us on, in order of preference.
+/// Returns the candidates and the reflexive addresses with the STUN servers which have to be kept alive
+async fn gather_candidates(
+    socket: &mut UdpSocket,
+    stun_servers: &[SocketAddr],
+    prefer_ipv6: bool,
+    local_only: bool,
+) -> (Vec<Candidate>, Vec<(SocketAddr, SocketAddr)>) {
+    let local = socket.local_addr().unwrap();
+    let (mut hosts, mut reflexive) = if local_only {
+        //Only used for hacky, local debugging
+        let mut hosts = vec![SocketAddr::new("127.0.0.1".parse().unwrap(), local.port())];
+        if local.is_ipv6() {
+            hosts.push(SocketAddr::new("::1".parse().unwrap(), local.port()));
+        }
+        (hosts, Vec::new())
+    } else {
+        (
+            candidate::host_addresses(local),
+            stun::external_addresses(socket, stun_servers).await,
+        )
+    };
+    hosts.sort_by_key(|address| address.is_ipv6() != prefer_ipv6);
+    reflexive.sort_by_key(|(address, _)| address.is_ipv6() != prefer_ipv6);
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for address in hosts {
+        candidates.push(Candidate::new(
+            CandidateKind::Host,
+            address,
+            candidates.len(),
+        ));
+    }
+    for &(address, _) in reflexive.iter() {
+        //Without a NAT the reflexive address is the host address
+        if !candidates.iter().any(|c| c.address == address) {
+            candidates.push(Candidate::new(
+                CandidateKind::ServerReflexive,
+                address,
+                candidates.len(),
+            ));
+        }
+    }
+    (candidates, reflexive)
+}
+
+/// Asks the gateway to forward our port, which makes hole punching unnecessary.
+/// The mapping is kept alive until the lease is released
+async fn map_port(local: SocketAddr) -> Option<Lease> {
+    let lan = candidate::host_addresses(local)
+        .into_iter()
+        .find_map(|address| match address {
+            SocketAddr::V4(address) => Some(address),
+            SocketAddr::V6(_) => None,
+        })?;
+    match PortMapper::new().map(lan).await {
+        Ok(mapping) => {
+            println!(
+                "The router forwards {} to us ({:?})",
+                mapping.external,
+                mapping.method()
+            );
+            Some(mapping.keep_alive())
+        }
+        Err(e) => {
+            println!("Port mapping is not available: {}", e);
+            None
+        }
+    }
+}
+
+/// Detects the type of our NAT and adds the addresses a symmetric NAT will probably assign next
+async fn detect_nat(
+    socket: &mut UdpSocket,
+    stun_servers: &[SocketAddr],
+    candidates: &mut Vec<Candidate>,
+) {
+    let hosts: Vec<SocketAddr> = candidates
+        .iter()
+        .filter(|candidate| candidate.kind == CandidateKind::Host)
+        .map(|candidate| candidate.address)
+        .collect();
+    //There is rarely a NAT in front of IPv6 hosts
+    let servers: Vec<SocketAddr> = stun_servers
+        .iter()
+        .filter(|server| server.is_ipv4())
+        .copied()
+        .collect();
+    match nat::detect(socket, &servers, &hosts).await {
+        Ok(behavior) => {
+            println!("NAT type: {}", behavior);
+            for address in behavior.predicted_addresses() {
+                if !candidates.iter().any(|c| c.address == address) {
+                    candidates.push(Candidate::new(
+                        CandidateKind::Predicted,
+                        address,
+                        candidates.len(),
+                    ));
+                }
+            }
+        }
+        Err(e) => println!("Could not detect the NAT type: {}", e),
+    }
+}
+
+/// Prints our candidates and code, and copies the code to the clipboard
+fn share_code(candidates: &[Candidate], session: Session, secret: [u8; SECRET_SIZE]) {
+    for candidate in candidates.iter() {
+        println!("Candidate: {:?} {}", candidate.kind, candidate.address);
+    }
+    let info = AddressInfo::with_session(candidates.to_vec(), session).with_secret(secret);
+    //There is no clipboard on headless machines
+    let copied = ClipboardProvider::new()
+        .and_then(|mut ctx: ClipboardContext| ctx.set_contents(info.to_string()))
+        .is_ok();
+    if copied {
+        println!("Your code is: {} (copied to clipboard)", info); //Print sync code
+    } else {
+        println!("Your code is: {}", info);
+    }
+    println!("Or in words: {}", info.to_words());
+}
+
+/// Password of peers which exchanged their codes directly: the secrets of both codes, the sender's first
+fn code_password(ours: [u8; SECRET_SIZE], theirs: [u8; SECRET_SIZE], sending: bool) -> Vec<u8> {
+    let (first, second) = if sending {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    [first, second].concat()
+}
+
+/// Resolves `name` to an address `local` can reach
+fn resolve_server(name: &str, local: SocketAddr) -> Option<SocketAddr> {
+    name.to_socket_addrs().ok().and_then(|mut servers| {
+        //IPv4 sockets can't reach IPv6 servers
+        servers.find(|server| local.is_ipv6() || server.is_ipv4())
+    })
+}
+
+/// Leaves our code on the rendezvous server under a new short code
+async fn open_mailbox(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    info: &AddressInfo,
+) -> Option<Code> {
+    match rendezvous::register(socket, server, info).await {
+        Ok(nameplate) => {
+            let words = Code::random_words();
+            let code = Code { nameplate, words };
+            println!("Or tell your partner the short code: {}", code);
+            Some(code)
+        }
+        Err(e) => {
+            println!("Could not open a mailbox on the rendezvous server: {}", e);
+            None
+        }
+    }
+}
+
+/// Allocates an address on the relay server and adds it to the candidates.
+/// Returns the address of the relay server, if the allocation worked
+async fn allocate_relay(
+    socket: &mut UdpSocket,
+    relay: &str,
+    candidates: &mut Vec<Candidate>,
+) -> Option<SocketAddr> {
+    let server = match resolve_server(relay, socket.local_addr().ok()?) {
+        Some(server) => server,
+        None => {
+            println!("Could not resolve the relay server");
+            return None;
+        }
+    };
+    match relay::allocate_address(socket, server).await {
+        Ok(address) => {
+            candidates.push(Candidate::new(
+                CandidateKind::Relayed,
+                address,
+                candidates.len(),
+            ));
+            Some(server)
+        }
+        Err(e) => {
+            println!("Relay allocation failed: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Finds the receiver called `to` on the LAN, or lets the user pick one, and asks it
+/// to connect to our code `info`. Returns the receiver with its code
+async fn find_receiver(
+    socket: &mut UdpSocket,
+    to: Option<&str>,
+    name: &str,
+    info: &AddressInfo,
+) -> Option<discovery::Peer> {
+    let mut browser = match discovery::Browser::bind(discovery::DISCOVERY_GROUP) {
+        Ok(browser) => browser,
+        Err(e) => {
+            println!("Could not search the local network: {}", e);
+            return None;
+        }
+    };
+    let peer = match to {
+        Some(to) => {
+            println!("Looking for {} on the local network...", to);
+            match tokio::time::timeout(DISCOVERY_TIMEOUT, browser.find(to)).await {
+                Ok(Ok(peer)) => peer,
+                _ => {
+                    println!("Could not find {} on the local network", to);
+                    return None;
+                }
+            }
+        }
+        None => {
+            let peers = browser.browse(BROWSE_TIME).await;
+            if peers.is_empty() {
+                println!("No receivers found on the local network");
+                return None;
+            }
+            for (i, peer) in peers.iter().enumerate() {
+                println!("{}: {}", i + 1, peer.name);
+            }
+            let mut line_stream = BufReader::new(tokio::io::stdin()).lines();
+            loop {
+                print!("Send to: ");
+                std::io::stdout().flush().unwrap();
+                let line = line_stream.next_line().await.ok()??;
+                match line.trim().parse::<usize>() {
+                    Ok(i) if (1..=peers.len()).contains(&i) => break peers[i - 1].clone(),
+                    _ => println!("Invalid choice"),
+                }
+            }
+        }
+    };
+    println!("Waiting for {} to accept the connection...", peer.name);
+    if let Err(e) = discovery::request_connection(socket, &peer, name, info).await {
+        println!("Could not reach {}: {}", peer.name, e);
+        return None;
+    }
+    Some(peer)
+}
+
+/// Asks the user a yes/no `question`, anything but yes is a no
+async fn confirm<R: AsyncBufRead + Unpin>(line_stream: &mut Lines<R>, question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().unwrap();
+    match line_stream.next_line().await {
+        Ok(Some(line)) => matches!(line.trim().to_lowercase().as_str(), "y" | "yes"),
+        _ => false,
+    }
+}
+
+/// Announces our host candidates under `name`, senders on the LAN can't use the others
+fn announce_hosts(
+    name: &str,
+    candidates: &[Candidate],
+    session: Session,
+) -> Result<discovery::Announcer, Error> {
+    let hosts: Vec<Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.kind == CandidateKind::Host)
+        .copied()
+        .collect();
+    discovery::announce(
+        name,
+        AddressInfo::with_session(hosts, session),
+        discovery::DISCOVERY_GROUP,
+    )
+}
+
+/// Removes the port mapping from the router
+async fn release(lease: Option<Lease>) {
+    if let Some(lease) = lease {
+        if let Err(e) = lease.release().await {
+            println!("Could not remove the port mapping: {}", e);
+        }
+    }
+}
 
 #[tokio::main]
 pub async fn main() {
@@ -27,7 +353,12 @@ pub async fn main() {
     let matches = App::new("Peer-to-peer file sender")
         .version("0.0.1")
         .about("Easy file sending")
-        .arg(Arg::with_name("FILE").index(1))
+        .arg(
+            Arg::with_name("FILE")
+                .index(1)
+                .multiple(true)
+                .help("Files or directories to send, directories are sent with everything in them"),
+        )
         .arg(
             Arg::with_name("local")
                 .short("l")
@@ -35,90 +366,491 @@ pub async fn main() {
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("ipv6")
+                .short("6")
+                .long("ipv6")
+                .help("Prefers IPv6 over IPv4 when both are available")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("congestion")
+                .short("c")
+                .long("congestion")
+                .help("Congestion control algorithm used while sending")
+                .possible_values(&["aimd", "vegas", "none"])
+                .default_value("aimd"),
+        )
+        .arg(
+            Arg::with_name("relay")
+                .short("r")
+                .long("relay")
+                .help("Relay server used when no direct connection is possible")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rendezvous")
+                .short("R")
+                .long("rendezvous")
+                .help("Rendezvous server exchanging the addresses for a short code, which only one side has to type")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no-port-mapping")
+                .long("no-port-mapping")
+                .help("Does not ask the router to forward a port")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("stun")
+                .short("s")
+                .long("stun")
+                .help("STUN server used to find our address and NAT type, can be given multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("to")
+                .short("t")
+                .long("to")
+                .help("Sends to the receiver with this name on the local network instead of exchanging codes")
+                .takes_value(true)
+                .requires("FILE")
+                .conflicts_with("lan"),
+        )
+        .arg(
+            Arg::with_name("lan")
+                .long("lan")
+                .help("Lists the receivers on the local network to pick one instead of exchanging codes")
+                .takes_value(false)
+                .requires("FILE"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .help("Name announced on the local network while waiting for a file, defaults to the host name")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .short("p")
+                .long("peer")
+                .help("Nickname the partner's identity is remembered and checked under")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("config-dir")
+                .long("config-dir")
+                .help("Directory of our identity and the known peers")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("announce")
+                .long("announce")
+                .help("Announces us on the local network, so senders there can connect without a code. Such connections are not authenticated, each of them has to be confirmed")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("Directory received files are saved in")
+                .takes_value(true)
+                .default_value("downloads"),
+        )
+        .arg(
+            Arg::with_name("on-collision")
+                .long("on-collision")
+                .help("What happens to received files which already exist")
+                .possible_values(&Collision::NAMES)
+                .default_value("rename"),
+        )
         .get_matches();
-    let path = matches.value_of("FILE");
-    let path = path.map(|path| Path::new(path));
-    let path = {
-        if let Some(path) = path {
-            if !path.exists() || path.is_dir() {
-                None
-            } else {
-                Some(path)
-            }
-        } else {
-            path
+    let paths: Option<Vec<&Path>> = matches
+        .values_of("FILE")
+        .map(|values| values.map(Path::new).collect());
+    if let Some(missing) = paths.iter().flatten().find(|path| !path.exists()) {
+        println!("{} does not exist", missing.display());
+        std::process::exit(1);
+    }
+
+    let config_dir = matches
+        .value_of("config-dir")
+        .map_or_else(identity::config_dir, PathBuf::from);
+    let identity = match Identity::load_or_create(&config_dir) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!(
+                "Could not load our identity from {}: {}",
+                config_dir.display(),
+                e
+            );
+            std::process::exit(1);
         }
     };
+    let mut known_peers = match KnownPeers::load(&config_dir) {
+        Ok(known_peers) => known_peers,
+        Err(e) => {
+            println!("Could not load the known peers: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Your fingerprint is {}",
+        identity::fingerprint(&identity.public_key())
+    );
 
-    let (socket, local_address) =
-        stunclient::just_give_me_the_udp_socket_and_its_external_address();
-    //The following line is only used for hacky, local debugging
-
-    let local_address = if matches.is_present("local") {
-        SocketAddr::new(
-            IpAddr::V4("127.0.0.1".parse().unwrap()),
-            socket.local_addr().unwrap().port(),
-        )
-    } else {
-        local_address
+    //Use a dual-stack socket, unless IPv6 is not available at all
+    let socket = networking::bind_dual_stack("[::]:0".parse().unwrap())
+        .or_else(|_| networking::bind_dual_stack("0.0.0.0:0".parse().unwrap()))
+        .unwrap();
+    let mut socket = UdpSocket::from_std(socket).unwrap();
+    let prefer_ipv6 = matches.is_present("ipv6");
+    let local_only = matches.is_present("local");
+    //Receivers on the LAN are reached on their host addresses, the internet is not needed
+    let lan = (matches.is_present("to") || matches.is_present("lan")) && paths.is_some();
+    let name = matches
+        .value_of("name")
+        .map_or_else(discovery::default_name, String::from);
+    let stun_servers = match matches.values_of("stun") {
+        _ if lan => Vec::new(),
+        Some(names) => names.collect(),
+        None => STUN_SERVERS.to_vec(),
     };
-    //Convert address to IPv4 address
-    let local_addressv4 = {
-        if let SocketAddr::V4(address) = local_address {
-            address
+    let stun_servers = resolve_stun_servers(&stun_servers, socket.local_addr().unwrap());
+    let local_addr = socket.local_addr().unwrap();
+    let port_mapping = async {
+        if local_only || lan || matches.is_present("no-port-mapping") {
+            None
         } else {
-            panic!("Invalid IP type");
+            map_port(local_addr).await
         }
     };
-
-    {
-        if let Some(path) = path {
-            println!(
-                "You are about to transmit: {}",
-                path.file_name().unwrap().to_str().unwrap()
-            );
+    let ((mut candidates, mut bindings), lease) = tokio::join!(
+        gather_candidates(&mut socket, &stun_servers, prefer_ipv6, local_only),
+        port_mapping
+    );
+    if let Some(lease) = &lease {
+        if !candidates.iter().any(|c| c.address == lease.external) {
+            candidates.push(Candidate::new(
+                CandidateKind::PortMapped,
+                lease.external,
+                candidates.len(),
+            ));
         }
-        let info = AddressInfo::new(local_addressv4);
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        ctx.set_contents(info.to_string()).unwrap();
-        println!("Your code is: {} (copied to clipboard)", info); //Print sync code
+    }
+    let relay_server = match matches.value_of("relay") {
+        Some(_) if lan => None,
+        Some(relay) => allocate_relay(&mut socket, relay, &mut candidates).await,
+        None => None,
+    };
+    //Every new destination may take up a port of a symmetric NAT, so this comes last
+    if !local_only && !lan {
+        detect_nat(&mut socket, &stun_servers, &mut candidates).await;
+    }
+    if candidates.is_empty() {
+        println!("Could not determine any address");
+        std::process::exit(1);
     }
 
-    //Setup an interval for the ping messages
-    let mut ping_interval = tokio::time::interval(Duration::from_secs(PING_INTERVAL));
-    //Setup a stream of lines from stdin
-    let mut line_stream = BufReader::new(tokio::io::stdin()).lines();
-    //Constantly ping, until a new line comes
-    print!("Partner's code: ");
-    std::io::stdout().flush().unwrap();
-    let remote: AddressInfo = loop {
-        tokio::select! {
-            line = line_stream.next_line() => {
-                let line = line.unwrap().unwrap();
-                if let Ok(info) = line.parse::<AddressInfo>(){
-                    break info;
-                }else{
-                    println!("Invalid code");
+    if let Some(paths) = &paths {
+        let names: Vec<String> = paths
+            .iter()
+            .map(|path| {
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into()
+            })
+            .collect();
+        println!("You are about to transmit: {}", names.join(", "));
+    }
+    //Codes of earlier runs are refused, the partner has to prove it got this one
+    let mut session = Session::new();
+    //Proves that the partner got our code, and not just someone who saw the connection
+    let mut secret = pake::new_secret();
+    //No password means the user accepted an unauthenticated connection on the LAN
+    let (remote, password): (AddressInfo, Option<Vec<u8>>) = if lan {
+        let info = AddressInfo::with_session(candidates.clone(), session);
+        match find_receiver(&mut socket, matches.value_of("to"), &name, &info).await {
+            Some(peer) => {
+                println!("Anyone on the local network could pose as the receiver, the connection is not authenticated");
+                (peer.info, None)
+            }
+            None => std::process::exit(1),
+        }
+    } else {
+        share_code(&candidates, session, secret);
+        //Senders on the LAN can pick us instead of entering our code, until we are connected
+        let mut announcer = if paths.is_none() && matches.is_present("announce") {
+            match announce_hosts(&name, &candidates, session) {
+                Ok(announcer) => {
+                    println!("Senders on the local network can find you as {}", name);
+                    Some(announcer)
+                }
+                Err(e) => {
+                    println!("Could not announce us on the local network: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let rendezvous_server = matches.value_of("rendezvous").and_then(|name| {
+            let server = resolve_server(name, socket.local_addr().unwrap());
+            if server.is_none() {
+                println!("Could not resolve the rendezvous server");
+            }
+            server
+        });
+        //The sender opens a mailbox, the receiver types its code
+        let mut mailbox = match (rendezvous_server, &paths) {
+            (Some(server), Some(_)) => {
+                let info = AddressInfo::with_session(candidates.clone(), session);
+                open_mailbox(&mut socket, server, &info).await
+            }
+            _ => None,
+        };
+        let socket_addr = socket.local_addr().unwrap();
+        //Setup an interval for the ping messages
+        let mut ping_interval = tokio::time::interval(Duration::from_secs(PING_INTERVAL));
+        //Setup a stream of lines from stdin
+        let mut line_stream = BufReader::new(tokio::io::stdin()).lines();
+        //Binding requests which were not answered yet, with the server they were sent to
+        let mut pending: HashMap<stun::TransactionId, SocketAddr> = HashMap::new();
+        //Senders on the LAN the user did not want to connect to, their retransmissions are ignored
+        let mut refused = HashSet::new();
+        let mut buf = vec![0; networking::RECEIVE_BUFFER_SIZE];
+        //Constantly refresh the NAT bindings, until a new line comes
+        print!("Partner's code: ");
+        std::io::stdout().flush().unwrap();
+        loop {
+            tokio::select! {
+                line = line_stream.next_line() => {
+                    let line = line.unwrap().unwrap();
+                    let parsed = line.parse::<AddressInfo>();
+                    match parsed.as_ref().map(|info| info.secret) {
+                        Ok(Some(theirs)) => {
+                            let password = code_password(secret, theirs, paths.is_some());
+                            break (parsed.unwrap(), Some(password));
+                        }
+                        //Only codes for the rendezvous server and the LAN come without a secret
+                        Ok(None) => {
+                            println!("Invalid code: the code has no secret");
+                            print!("Partner's code: ");
+                            std::io::stdout().flush().unwrap();
+                            continue;
+                        }
+                        Err(_) => {}
+                    }
+                    let claimed = match (rendezvous_server, line.parse::<Code>()) {
+                        (Some(server), Ok(code)) => {
+                            let info = AddressInfo::with_session(candidates.clone(), session);
+                            let claimed = rendezvous::claim(&mut socket, server, &code, &info).await;
+                            Some(claimed.map(|remote| (remote, Some(code.password()))))
+                        }
+                        _ => None,
+                    };
+                    match (claimed, parsed) {
+                        (Some(Ok(remote)), _) => break remote,
+                        (Some(Err(e)), _) => println!("Could not get the partner's addresses: {}", e),
+                        //Word codes know which word is wrong
+                        (None, Err(e)) if line.trim().contains(char::is_whitespace) => println!("Invalid code: {}", e),
+                        (None, _) => println!("Invalid code"),
+                    }
                     print!("Partner's code: ");
                     std::io::stdout().flush().unwrap();
                 }
+                _ = ping_interval.tick() => {
+                    if session.is_expired() {
+                        session = Session::new();
+                        secret = pake::new_secret();
+                        println!();
+                        println!("Your code expired, here is a new one");
+                        share_code(&candidates, session, secret);
+                        if announcer.is_some() {
+                            announcer = announce_hosts(&name, &candidates, session).ok();
+                        }
+                        print!("Partner's code: ");
+                        std::io::stdout().flush().unwrap();
+                    }
+                    pending.clear();
+                    for &(_, server) in bindings.iter() {
+                        let transaction = stun::new_transaction();
+                        pending.insert(transaction, server);
+                        let request = stun::binding_request(transaction);
+                        socket.send_to(&request, &networking::map_to_family(server, socket_addr)).await.ok();
+                    }
+                    //Keeps the allocation from expiring
+                    if let Some(server) = relay_server {
+                        let refresh = Messages::RelayAllocate(RelayAllocateMessage {}).to_datagram();
+                        socket.send_to(&refresh, &networking::map_to_family(server, socket_addr)).await.ok();
+                    }
+                    //Keeps the mailbox open, with our current candidates
+                    if let (Some(server), Some(_)) = (rendezvous_server, &mailbox) {
+                        let refresh = RendezvousRegisterMessage {
+                            info: AddressInfo::with_session(candidates.clone(), session),
+                        };
+                        let refresh = Messages::RendezvousRegister(refresh).to_datagram();
+                        socket.send_to(&refresh, &networking::map_to_family(server, socket_addr)).await.ok();
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (size, from) = match received {
+                        Ok((size, from)) => (size, networking::unmap(from)),
+                        Err(_) => continue,
+                    };
+                    //Everything else is a stray answer to the relay refresh, or an early connectivity check
+                    let response = match demux::demultiplex(&buf[..size]) {
+                        Packet::Stun(datagram) => stun::parse_binding_response(&datagram),
+                        //Requests from outside the local network could only come through our public address
+                        Packet::Message(Messages::ConnectRequest(msg)) if announcer.is_some() && discovery::is_local(from.ip()) && !refused.contains(&from) => {
+                            println!();
+                            println!("{} at {} found you on the local network", printable(&msg.name), from);
+                            println!("Anyone on the local network could pose as the sender, the connection is not authenticated");
+                            if confirm(&mut line_stream, "Accept the connection?").await {
+                                break (msg.info, None);
+                            }
+                            refused.insert(from);
+                            print!("Partner's code: ");
+                            std::io::stdout().flush().unwrap();
+                            None
+                        }
+                        Packet::Message(Messages::RendezvousPeer(msg)) if Some(from) == rendezvous_server && mailbox.is_some() => {
+                            println!();
+                            println!("Your partner entered the short code");
+                            break (msg.info, Some(mailbox.as_ref().unwrap().password()));
+                        }
+                        Packet::Message(Messages::RendezvousRegistered(msg)) if Some(from) == rendezvous_server => {
+                            //The server lost our mailbox, the refresh opened a new one
+                            if let Some(code) = mailbox.as_mut().filter(|code| code.nameplate != msg.nameplate) {
+                                code.nameplate = msg.nameplate;
+                                println!();
+                                println!("The rendezvous server lost your mailbox, your short code is now: {}", code);
+                                print!("Partner's code: ");
+                                std::io::stdout().flush().unwrap();
+                            }
+                            None
+                        }
+                        _ => None,
+                    };
+                    let response = match response {
+                        Some(response) => response,
+                        None => continue,
+                    };
+                    if pending.get(&response.transaction) != Some(&from) {
+                        continue;
+                    }
+                    pending.remove(&response.transaction);
+                    let mapped = networking::unmap(response.mapped);
+                    let binding = bindings.iter_mut().find(|(_, server)| *server == from);
+                    if let Some((address, _)) = binding {
+                        if *address != mapped {
+                            //The NAT dropped our mapping and made a new one, the old code is useless
+                            println!();
+                            println!("Your external address changed from {} to {}", address, mapped);
+                            for candidate in candidates.iter_mut() {
+                                if candidate.kind == CandidateKind::ServerReflexive && candidate.address == *address {
+                                    candidate.address = mapped;
+                                }
+                            }
+                            *address = mapped;
+                            share_code(&candidates, session, secret);
+                            print!("Partner's code: ");
+                            std::io::stdout().flush().unwrap();
+                        }
+                    }
+                }
             }
-            _ = ping_interval.tick() => {
-                socket.send_to("PING".as_bytes(), "stun.l.google.com:19302").unwrap();
+        }
+    };
+
+    //The peer with the file is in control of choosing the path
+    let selected = networking::ConnectivityCheck::new(
+        &mut socket,
+        &candidates,
+        &remote.candidates,
+        paths.is_some(),
+        relay_server,
+    )
+    .unwrap()
+    .run()
+    .await;
+    let selected = match selected {
+        Ok(selected) => selected,
+        Err(e) => {
+            println!("Could not connect to the partner: {}", e);
+            release(lease).await;
+            std::process::exit(1);
+        }
+    };
+    //Keep the socket, the peer reached us through its NAT mapping
+    let mut network_handler =
+        networking::NetworkHandler::with_socket(socket, selected.remote).unwrap();
+    match selected.relay {
+        Some(server) => {
+            println!(
+                "Connected to {} through the relay {}",
+                selected.remote, server
+            );
+            network_handler.set_relay(server);
+        }
+        None => println!("Connected through {}", selected.remote),
+    }
+    //The peer with the file starts the handshake, like it chooses the path
+    network_handler.set_encryption(paths.is_some()).unwrap();
+    if let Err(e) = network_handler.begin().await {
+        println!("Could not encrypt the connection: {}", e);
+        release(lease).await;
+        std::process::exit(1);
+    }
+    println!("Connection encrypted");
+    let mut state = network_handler.watch_state();
+    tokio::spawn(async move {
+        while let Some(state) = state.recv().await {
+            match state {
+                ConnectionState::Idle => println!("Partner is not responding..."),
+                ConnectionState::Failed => println!("Lost the connection to the partner"),
+                _ => {}
             }
         }
+    });
+    //Names on the local network are chosen by the peer, they can't be trusted
+    let nickname = matches.value_of("peer");
+    let introduction = Introduction {
+        ours: &session,
+        theirs: &remote.session,
+        password: password.as_deref(),
+        identity: &identity,
+        known_peers: &mut known_peers,
+        nickname,
     };
-    println!("{}", remote.address);
-    let socket_addr = socket.local_addr().unwrap();
-    drop(socket);
-    let network_handler =
-        networking::NetworkHandler::new(socket_addr, SocketAddr::V4(remote.address));
-    network_handler.begin().await.unwrap();
-    if let Some(path) = path {
+    let result = if let Some(paths) = &paths {
         //Transmitting
-        transmitter::begin(network_handler, path).await;
+        let controller = congestion::from_name(matches.value_of("congestion").unwrap()).unwrap();
+        transmitter::begin(network_handler, paths, controller, introduction).await
     } else {
         //Receiving
-        receiver::begin(network_handler).await;
+        let destination = Destination::new(
+            PathBuf::from(matches.value_of("output").unwrap()),
+            Collision::from_name(matches.value_of("on-collision").unwrap()).unwrap(),
+        );
+        receiver::begin(network_handler, introduction, &destination).await
+    };
+    release(lease).await;
+    if let Err(e) = result {
+        println!("Transfer failed: {}", e);
+        std::process::exit(1);
     }
 }
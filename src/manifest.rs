@@ -0,0 +1,130 @@
+use crate::error::Error;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Longest path of an entry in bytes, an entry has to fit into a single message
+pub const MAX_PATH_SIZE: usize = 512;
+/// Encoded entries per manifest message, so they fit into the smallest path MTU
+pub const MANIFEST_MESSAGE_SIZE: usize = 900;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A file or directory of a transfer. Paths are relative and separated by `/`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of a file, zero for directories
+    pub size: u64,
+}
+
+impl ManifestEntry {
+    /// Number of bytes the entry takes up in a manifest message
+    pub fn encoded_size(&self) -> usize {
+        1 + 8 + 2 + self.path.len()
+    }
+}
+
+/// Everything sent in one session, announced to the receiver before the first file
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    /// Where the entries are on this machine, only known to the sender
+    sources: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Lists the files and directories at `paths`, directories are walked recursively.
+    /// Each of them is named after its last component
+    pub fn from_paths(paths: &[&Path]) -> Result<Self, Error> {
+        let mut manifest = Self {
+            entries: Vec::new(),
+            sources: Vec::new(),
+        };
+        for path in paths {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| Error::new(&format!("Can't send {}", path.display())))?;
+            if manifest.entries.iter().any(|entry| entry.path == name) {
+                return Err(Error::new(&format!("{} was given twice", name)));
+            }
+            manifest.add(path, name.to_string())?;
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest as the receiver knows it
+    pub fn from_entries(entries: Vec<ManifestEntry>) -> Self {
+        Self {
+            entries,
+            sources: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, source: &Path, path: String) -> Result<(), Error> {
+        if path.len() > MAX_PATH_SIZE {
+            return Err(Error::new(&format!("The path {} is too long", path)));
+        }
+        let metadata = fs::symlink_metadata(source)?;
+        let (kind, size) = if metadata.is_dir() {
+            (EntryKind::Directory, 0)
+        } else if metadata.is_file() {
+            (EntryKind::File, metadata.len())
+        } else {
+            println!("Skipping {}, it is not a regular file", source.display());
+            return Ok(());
+        };
+        self.entries.push(ManifestEntry {
+            path: path.clone(),
+            kind,
+            size,
+        });
+        self.sources.push(source.to_path_buf());
+        if kind == EntryKind::Directory {
+            let mut children = fs::read_dir(source)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            children.sort();
+            for child in children {
+                let name = child
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| {
+                        Error::new(&format!("{} is not valid UTF-8", child.display()))
+                    })?;
+                self.add(&child, format!("{}/{}", path, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The files with their location on this machine, only on the sender
+    pub fn files(&self) -> impl Iterator<Item = (&ManifestEntry, &Path)> {
+        self.entries
+            .iter()
+            .zip(self.sources.iter())
+            .filter(|(entry, _)| entry.kind == EntryKind::File)
+            .map(|(entry, source)| (entry, source.as_path()))
+    }
+
+    /// The files, on both sides
+    pub fn file_entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind == EntryKind::File)
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.file_entries().count()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{error::Error, integrity::FileHash};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+/// Files are hashed in blocks of this size, the leaves of the tree
+pub const BLOCK_SIZE: u64 = 256 * 1024;
+/// Block hashes per message, so they fit into the smallest path MTU
+pub const HASHES_PER_MESSAGE: usize = 24;
+/// Larger files are refused, the hashes of their blocks alone would take more than 128 MiB
+pub const MAX_FILE_SIZE: u64 = 1 << 40;
+/// Leaves and inner nodes are hashed with different prefixes, so one can't pass for the other
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub type Hash = [u8; 32];
+
+/// Number of blocks of a file, an empty file still has one
+pub fn block_count(filesize: u64) -> u32 {
+    filesize.div_ceil(BLOCK_SIZE).max(1) as u32
+}
+
+pub fn leaf_hash(block: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of the tree over `leaves`. A node without a sibling moves up unchanged
+pub fn root(leaves: &[Hash]) -> Hash {
+    let mut level = leaves.to_vec();
+    if level.is_empty() {
+        return leaf_hash(&[]);
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Hashes of the blocks a file is made of
+pub struct BlockHashes {
+    pub leaves: Vec<Hash>,
+}
+
+impl BlockHashes {
+    /// Reads the whole file once, returning the hashes of its blocks
+    /// and the SHA-256 of all of it
+    pub fn from_reader<T: Read>(reader: &mut T) -> Result<(Self, FileHash), Error> {
+        let mut leaves = Vec::new();
+        let mut file_hasher = Sha256::new();
+        let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+        loop {
+            block.clear();
+            reader.by_ref().take(BLOCK_SIZE).read_to_end(&mut block)?;
+            if block.is_empty() && !leaves.is_empty() {
+                break;
+            }
+            file_hasher.update(&block);
+            leaves.push(leaf_hash(&block));
+            if (block.len() as u64) < BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok((Self { leaves }, file_hasher.finalize().into()))
+    }
+
+    pub fn root(&self) -> Hash {
+        root(&self.leaves)
+    }
+
+    /// Whether `block` is the block `index` of the file
+    pub fn verify(&self, index: u32, block: &[u8]) -> bool {
+        self.leaves.get(index as usize) == Some(&leaf_hash(block))
+    }
+}
@@ -1,8 +1,35 @@
-use crate::{bitfield::Bitfield, error::Error};
+use crate::{
+    error::Error,
+    identity::{PublicKey, PUBLIC_KEY_SIZE, SIGNATURE_SIZE},
+    integrity::{crc32, FileHash, HASH_SIZE},
+    manifest::{EntryKind, ManifestEntry},
+    merkle::Hash,
+    obfuscator::{read_tagged_address, write_address, AddressInfo, NONCE_SIZE},
+    pake::{SHARE_SIZE, TAG_SIZE},
+};
 use bytes::BytesMut;
-use std::convert::TryInto;
+use std::{convert::TryInto, net::SocketAddr};
 use tokio_util::codec::Decoder;
 
+/// Every datagram starts with this ("P2P" and a version), so our messages
+/// can share a socket with STUN traffic
+pub const MAGIC: [u8; 4] = [0x50, 0x32, 0x50, 0x01];
+
+/// Version of the file transfer protocol spoken by this build. The file is sent
+/// through a selective repeat window and every block is verified against a Merkle tree.
+/// Peers with an older version are refused
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// `text` of the peer without control characters and direction overrides, so printing
+/// it can't move the cursor, change colors or reorder what the user sees
+pub fn printable(text: &str) -> String {
+    text.chars()
+        .filter(|&c| {
+            !c.is_control() && !matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+        })
+        .collect()
+}
+
 #[derive(Clone)]
 pub enum Messages {
     Reliable(ReliableMessage),
@@ -11,12 +38,38 @@ pub enum Messages {
 
     FileTransferRequest(FileTransferRequestMessage),
     FileTransferAccept(FileTransferAcceptMessage),
-    PartBegin(PartBeginMessage),
-    Chunk(ChunkMessage),
-    PartEnd(PartEndMessage),
-    TransferIncomplete(TransferIncompleteMessage),
     TransferSuccessful(TransferSuccessfulMessage),
     Goodbye(GoodbyeMessage),
+    Segment(SegmentMessage),
+    SelectiveAck(SelectiveAckMessage),
+    Pong(PongMessage),
+    MtuProbe(MtuProbeMessage),
+    MtuProbeAck(MtuProbeAckMessage),
+    BindingRequest(BindingRequestMessage),
+    BindingResponse(BindingResponseMessage),
+    RelayAllocate(RelayAllocateMessage),
+    RelayAllocated(RelayAllocatedMessage),
+    RelayData(RelayDataMessage),
+    Close(CloseMessage),
+    CloseAck(CloseAckMessage),
+    Announce(AnnounceMessage),
+    ConnectRequest(ConnectRequestMessage),
+    RendezvousRegister(RendezvousRegisterMessage),
+    RendezvousRegistered(RendezvousRegisteredMessage),
+    RendezvousClaim(RendezvousClaimMessage),
+    RendezvousPeer(RendezvousPeerMessage),
+    RendezvousUnknown(RendezvousUnknownMessage),
+    Hello(HelloMessage),
+    NoiseHandshake(NoiseHandshakeMessage),
+    PakeShare(PakeShareMessage),
+    PakeConfirm(PakeConfirmMessage),
+    Identity(IdentityMessage),
+    TransferFailed(TransferFailedMessage),
+    BlockHashes(BlockHashesMessage),
+    Refetch(RefetchMessage),
+    Manifest(ManifestMessage),
+    FileTransferDecline(FileTransferDeclineMessage),
+    RelayPermission(RelayPermissionMessage),
 }
 
 impl Decoder for Messages {
@@ -25,7 +78,15 @@ impl Decoder for Messages {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let message_id = u32::from_le_bytes(src[0..4].try_into()?);
+        Ok(Some(Messages::parse(src)?))
+    }
+}
+
+impl Messages {
+    /// Parses a message without the magic
+    fn parse(src: &[u8]) -> Result<Self, Error> {
+        let header = src.get(0..4).ok_or(Error::new("Invalid data"))?;
+        let message_id = u32::from_le_bytes(header.try_into()?);
         let rest = src[4..].to_vec();
         let message = match message_id {
             0 => Messages::Reliable(
@@ -44,31 +105,122 @@ impl Decoder for Messages {
             4 => Messages::FileTransferAccept(
                 *(FileTransferAcceptMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
             ),
-            5 => Messages::PartBegin(
-                *(PartBeginMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
-            ),
-            6 => Messages::Chunk(
-                *(ChunkMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
-            ),
-            7 => Messages::PartEnd(
-                *(PartEndMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
-            ),
-            8 => Messages::TransferIncomplete(
-                *(TransferIncompleteMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
-            ),
             9 => Messages::TransferSuccessful(
                 *(TransferSuccessfulMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
             ),
             11 => Messages::Goodbye(
                 *(GoodbyeMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
             ),
+            12 => Messages::Segment(
+                *(SegmentMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            13 => Messages::SelectiveAck(
+                *(SelectiveAckMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            14 => {
+                Messages::Pong(*(PongMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?))
+            }
+            15 => Messages::MtuProbe(
+                *(MtuProbeMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            16 => Messages::MtuProbeAck(
+                *(MtuProbeAckMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            17 => Messages::BindingRequest(
+                *(BindingRequestMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            18 => Messages::BindingResponse(
+                *(BindingResponseMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            19 => Messages::RelayAllocate(
+                *(RelayAllocateMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            20 => Messages::RelayAllocated(
+                *(RelayAllocatedMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            21 => Messages::RelayData(
+                *(RelayDataMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            22 => Messages::Close(
+                *(CloseMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            23 => Messages::CloseAck(
+                *(CloseAckMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            24 => Messages::Announce(
+                *(AnnounceMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            25 => Messages::ConnectRequest(
+                *(ConnectRequestMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            26 => Messages::RendezvousRegister(
+                *(RendezvousRegisterMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            27 => Messages::RendezvousRegistered(
+                *(RendezvousRegisteredMessage::from_bytes(rest)
+                    .ok_or(Error::new("Invalid data"))?),
+            ),
+            28 => Messages::RendezvousClaim(
+                *(RendezvousClaimMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            29 => Messages::RendezvousPeer(
+                *(RendezvousPeerMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            30 => Messages::RendezvousUnknown(
+                *(RendezvousUnknownMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            31 => Messages::Hello(
+                *(HelloMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            32 => Messages::NoiseHandshake(
+                *(NoiseHandshakeMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            33 => Messages::PakeShare(
+                *(PakeShareMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            34 => Messages::PakeConfirm(
+                *(PakeConfirmMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            35 => Messages::Identity(
+                *(IdentityMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            36 => Messages::TransferFailed(
+                *(TransferFailedMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            37 => Messages::BlockHashes(
+                *(BlockHashesMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            38 => Messages::Refetch(
+                *(RefetchMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            39 => Messages::Manifest(
+                *(ManifestMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
+            40 => Messages::FileTransferDecline(
+                *(FileTransferDeclineMessage::from_bytes(rest)
+                    .ok_or(Error::new("Invalid data"))?),
+            ),
+            41 => Messages::RelayPermission(
+                *(RelayPermissionMessage::from_bytes(rest).ok_or(Error::new("Invalid data"))?),
+            ),
             _ => return Err(Error::new("Invalid packet ID")),
         };
-        Ok(Some(message))
+        Ok(message)
+    }
+
+    /// The message as it is sent on the wire
+    pub fn to_datagram(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.append(&mut self.get_bytes());
+        buf
+    }
+
+    /// Parses a datagram written by `to_datagram`
+    pub fn from_datagram(datagram: &[u8]) -> Option<Messages> {
+        let bytes = datagram.strip_prefix(&MAGIC[..])?;
+        Messages::parse(bytes).ok()
     }
-}
 
-impl Messages {
     pub fn get_bytes(&self) -> Vec<u8> {
         match self {
             Messages::Reliable(a) => a.get_bytes(),
@@ -76,12 +228,38 @@ impl Messages {
             Messages::Ping(a) => a.get_bytes(),
             Messages::FileTransferRequest(a) => a.get_bytes(),
             Messages::FileTransferAccept(a) => a.get_bytes(),
-            Messages::PartBegin(a) => a.get_bytes(),
-            Messages::Chunk(a) => a.get_bytes(),
-            Messages::PartEnd(a) => a.get_bytes(),
-            Messages::TransferIncomplete(a) => a.get_bytes(),
             Messages::TransferSuccessful(a) => a.get_bytes(),
             Messages::Goodbye(a) => a.get_bytes(),
+            Messages::Segment(a) => a.get_bytes(),
+            Messages::SelectiveAck(a) => a.get_bytes(),
+            Messages::Pong(a) => a.get_bytes(),
+            Messages::MtuProbe(a) => a.get_bytes(),
+            Messages::MtuProbeAck(a) => a.get_bytes(),
+            Messages::BindingRequest(a) => a.get_bytes(),
+            Messages::BindingResponse(a) => a.get_bytes(),
+            Messages::RelayAllocate(a) => a.get_bytes(),
+            Messages::RelayAllocated(a) => a.get_bytes(),
+            Messages::RelayData(a) => a.get_bytes(),
+            Messages::Close(a) => a.get_bytes(),
+            Messages::CloseAck(a) => a.get_bytes(),
+            Messages::Announce(a) => a.get_bytes(),
+            Messages::ConnectRequest(a) => a.get_bytes(),
+            Messages::RendezvousRegister(a) => a.get_bytes(),
+            Messages::RendezvousRegistered(a) => a.get_bytes(),
+            Messages::RendezvousClaim(a) => a.get_bytes(),
+            Messages::RendezvousPeer(a) => a.get_bytes(),
+            Messages::RendezvousUnknown(a) => a.get_bytes(),
+            Messages::Hello(a) => a.get_bytes(),
+            Messages::NoiseHandshake(a) => a.get_bytes(),
+            Messages::PakeShare(a) => a.get_bytes(),
+            Messages::PakeConfirm(a) => a.get_bytes(),
+            Messages::Identity(a) => a.get_bytes(),
+            Messages::TransferFailed(a) => a.get_bytes(),
+            Messages::BlockHashes(a) => a.get_bytes(),
+            Messages::Refetch(a) => a.get_bytes(),
+            Messages::Manifest(a) => a.get_bytes(),
+            Messages::FileTransferDecline(a) => a.get_bytes(),
+            Messages::RelayPermission(a) => a.get_bytes(),
         }
     }
 }
@@ -115,10 +293,14 @@ impl Message for ReliableMessage {
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        let index = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
-        let mut buf = BytesMut::new();
-        buf.extend(bytes[4..].iter());
-        let message = Messages::Ping(PingMessage {}).decode(&mut buf).ok()??;
+        let index = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let inner = bytes.get(4..)?;
+        //Reliable messages are never nested, parsing them could recurse without a limit
+        let id = u32::from_le_bytes(inner.get(0..4)?.try_into().ok()?);
+        if id == Self::ID {
+            return None;
+        }
+        let message = Messages::parse(inner).ok()?;
         Some(Box::new(Self {
             packet_index: index,
             message: Box::new(message),
@@ -139,7 +321,7 @@ impl Message for ReliableAckMessage {
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        let index = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let index = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
         Some(Box::new(Self {
             packet_index: index,
         }))
@@ -147,15 +329,42 @@ impl Message for ReliableAckMessage {
 }
 
 #[derive(Clone)]
-pub struct PingMessage {}
+pub struct PingMessage {
+    pub sequence: u32,
+}
 impl Message for PingMessage {
     const ID: u32 = 2;
     fn get_data(&self) -> Vec<u8> {
-        Vec::new()
+        self.sequence.to_le_bytes().to_vec()
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        Some(Box::new(Self {}))
+        //Older peers send empty pings
+        let sequence = if bytes.len() >= 4 {
+            u32::from_le_bytes(bytes[0..4].try_into().ok()?)
+        } else {
+            0
+        };
+        Some(Box::new(Self { sequence }))
+    }
+}
+
+#[derive(Clone)]
+pub struct PongMessage {
+    pub sequence: u32,
+}
+impl Message for PongMessage {
+    const ID: u32 = 14;
+    fn get_data(&self) -> Vec<u8> {
+        self.sequence.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let sequence = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        Some(Box::new(Self { sequence }))
     }
 }
 
@@ -163,6 +372,14 @@ impl Message for PingMessage {
 pub struct FileTransferRequestMessage {
     pub filename: String,
     pub filesize: u64,
+    /// Highest protocol version supported by the sender
+    pub version: u32,
+    /// Size of a segment
+    pub chunk_size: u32,
+    /// Hash of the whole file, checked by the receiver before it reports success
+    pub hash: FileHash,
+    /// Root of the Merkle tree over the blocks of the file
+    pub root: Hash,
 }
 
 impl Message for FileTransferRequestMessage {
@@ -174,6 +391,10 @@ impl Message for FileTransferRequestMessage {
         buf.extend((filename_buffer.len() as u32).to_le_bytes().iter());
         buf.extend(filename_buffer);
         buf.extend(self.filesize.to_le_bytes().iter());
+        buf.extend(self.version.to_le_bytes().iter());
+        buf.extend(self.chunk_size.to_le_bytes().iter());
+        buf.extend(self.hash.iter());
+        buf.extend(self.root.iter());
         buf.to_vec()
     }
 
@@ -182,7 +403,7 @@ impl Message for FileTransferRequestMessage {
             return None;
         }
         let filename_size = u32::from_le_bytes(bytes[0..4].try_into().ok()?) as usize;
-        if bytes.len() < (4 + 8 + filename_size) as usize {
+        if bytes.len() < 4 + 8 + filename_size {
             return None;
         }
         let filename = String::from_utf8(bytes[4..4 + filename_size].to_vec()).ok()?;
@@ -191,68 +412,115 @@ impl Message for FileTransferRequestMessage {
                 .try_into()
                 .ok()?,
         );
-        Some(Box::new(Self { filename, filesize }))
+        let rest = &bytes[4 + filename_size + 8..];
+        let version = u32::from_le_bytes(rest.get(0..4)?.try_into().ok()?);
+        let chunk_size = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?);
+        let hash = rest.get(8..8 + HASH_SIZE)?.try_into().ok()?;
+        let root = rest
+            .get(8 + HASH_SIZE..8 + 2 * HASH_SIZE)?
+            .try_into()
+            .ok()?;
+        Some(Box::new(Self {
+            filename,
+            filesize,
+            version,
+            chunk_size,
+            hash,
+            root,
+        }))
     }
 }
 
 #[derive(Clone)]
-pub struct FileTransferAcceptMessage {}
+pub struct FileTransferAcceptMessage {
+    /// Protocol version chosen by the receiver
+    pub version: u32,
+    /// Bytes at the start of the file the receiver already has from an earlier session
+    pub resume_from: u64,
+}
 impl Message for FileTransferAcceptMessage {
     const ID: u32 = 4;
     fn get_data(&self) -> Vec<u8> {
-        Vec::new()
+        let mut data = self.version.to_le_bytes().to_vec();
+        data.extend(self.resume_from.to_le_bytes().iter());
+        data
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 12 {
+            return None;
+        }
+        let version = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let resume_from = u64::from_le_bytes(bytes[4..12].try_into().ok()?);
+        Some(Box::new(Self {
+            version,
+            resume_from,
+        }))
+    }
+}
+#[derive(Clone)]
+pub struct TransferSuccessfulMessage {}
+impl Message for TransferSuccessfulMessage {
+    const ID: u32 = 9;
+    fn get_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(_bytes: Vec<u8>) -> Option<Box<Self>> {
         Some(Box::new(Self {}))
     }
 }
 #[derive(Clone)]
-pub struct PartBeginMessage {
-    pub part_size: u32,
-    pub chunk_size: u32,
-    pub part_number: u32,
-    pub chunk_count: u32,
+pub struct GoodbyeMessage {
+    pub motd: String,
 }
-impl Message for PartBeginMessage {
-    const ID: u32 = 5;
+impl Message for GoodbyeMessage {
+    const ID: u32 = 11;
     fn get_data(&self) -> Vec<u8> {
         let mut buf = BytesMut::new();
-        buf.extend(self.part_size.to_le_bytes().iter());
-        buf.extend(self.chunk_size.to_le_bytes().iter());
-        buf.extend(self.part_number.to_le_bytes().iter());
-        buf.extend(self.chunk_count.to_le_bytes().iter());
+        let motd_buffer = self.motd.as_bytes();
+        buf.extend((motd_buffer.len() as u32).to_le_bytes().iter());
+        buf.extend(motd_buffer);
         buf.to_vec()
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        if bytes.len() < 4 * 4 {
+        if bytes.len() < 4 {
             return None;
         }
-        let part_size = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
-        let chunk_size = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
-        let part_number = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
-        let chunk_count = u32::from_le_bytes(bytes[12..16].try_into().ok()?);
-        Some(Box::new(Self {
-            part_size,
-            chunk_size,
-            part_number,
-            chunk_count,
-        }))
+        let motd_size = u32::from_le_bytes(bytes[0..4].try_into().ok()?) as usize;
+        let end = 4usize.checked_add(motd_size)?;
+        let motd = String::from_utf8(bytes.get(4..end)?.to_vec()).ok()?;
+        Some(Box::new(Self { motd }))
     }
 }
 #[derive(Clone)]
-pub struct ChunkMessage {
-    pub index: u32,
-    pub part_number: u32,
+pub struct SegmentMessage {
+    pub sequence: u32,
+    /// CRC-32 of the data
+    pub checksum: u32,
     pub data: Vec<u8>,
 }
-impl Message for ChunkMessage {
-    const ID: u32 = 6;
+impl SegmentMessage {
+    pub fn new(sequence: u32, data: Vec<u8>) -> Self {
+        Self {
+            sequence,
+            checksum: crc32(&data),
+            data,
+        }
+    }
+
+    /// Whether the data still matches its checksum
+    pub fn is_intact(&self) -> bool {
+        crc32(&self.data) == self.checksum
+    }
+}
+impl Message for SegmentMessage {
+    const ID: u32 = 12;
     fn get_data(&self) -> Vec<u8> {
         let mut buf = BytesMut::new();
-        buf.extend(self.index.to_le_bytes().iter());
-        buf.extend(self.part_number.to_le_bytes().iter());
+        buf.extend(self.sequence.to_le_bytes().iter());
+        buf.extend(self.checksum.to_le_bytes().iter());
         buf.extend(self.data.iter());
         buf.to_vec()
     }
@@ -261,78 +529,612 @@ impl Message for ChunkMessage {
         if bytes.len() < 8 {
             return None;
         }
-        let index = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
-        let part_number = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
-        let data: Vec<u8> = bytes[8..].try_into().unwrap();
+        let sequence = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let checksum = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+        let data = bytes[8..].to_vec();
         Some(Box::new(Self {
-            index,
+            sequence,
+            checksum,
             data,
-            part_number,
         }))
     }
 }
 #[derive(Clone)]
-pub struct PartEndMessage {}
-impl Message for PartEndMessage {
-    const ID: u32 = 7;
+pub struct SelectiveAckMessage {
+    /// Every segment below this sequence number has been received
+    pub cumulative: u32,
+    /// Additional received segments above `cumulative`, as `start..end` ranges
+    pub ranges: Vec<(u32, u32)>,
+}
+impl Message for SelectiveAckMessage {
+    const ID: u32 = 13;
     fn get_data(&self) -> Vec<u8> {
-        Vec::new()
+        let mut buf = BytesMut::new();
+        buf.extend(self.cumulative.to_le_bytes().iter());
+        buf.extend((self.ranges.len() as u32).to_le_bytes().iter());
+        for (start, end) in self.ranges.iter() {
+            buf.extend(start.to_le_bytes().iter());
+            buf.extend(end.to_le_bytes().iter());
+        }
+        buf.to_vec()
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let cumulative = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let range_count = u32::from_le_bytes(bytes[4..8].try_into().ok()?) as usize;
+        let end = range_count.checked_mul(8)?.checked_add(8)?;
+        let ranges = bytes
+            .get(8..end)?
+            .chunks(8)
+            .map(|range| {
+                Some((
+                    u32::from_le_bytes(range[0..4].try_into().ok()?),
+                    u32::from_le_bytes(range[4..8].try_into().ok()?),
+                ))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Box::new(Self { cumulative, ranges }))
+    }
+}
+/// Padded to be exactly `size` bytes long on the wire, including the datagram header
+#[derive(Clone)]
+pub struct MtuProbeMessage {
+    pub size: u32,
+}
+impl Message for MtuProbeMessage {
+    const ID: u32 = 15;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = self.size.to_le_bytes().to_vec();
+        buf.resize(
+            (self.size as usize).max(8 + MAGIC.len()) - 4 - MAGIC.len(),
+            0,
+        );
+        buf
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let size = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        //Truncated probes must not be acknowledged
+        if bytes.len() + 4 + MAGIC.len() != size as usize {
+            return None;
+        }
+        Some(Box::new(Self { size }))
+    }
+}
+#[derive(Clone)]
+pub struct MtuProbeAckMessage {
+    pub size: u32,
+}
+impl Message for MtuProbeAckMessage {
+    const ID: u32 = 16;
+    fn get_data(&self) -> Vec<u8> {
+        self.size.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let size = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        Some(Box::new(Self { size }))
+    }
+}
+/// Connectivity check, sent to every candidate of the peer
+#[derive(Clone)]
+pub struct BindingRequestMessage {
+    pub transaction: u64,
+    /// Random number used to resolve role conflicts
+    pub tiebreaker: u64,
+    pub controlling: bool,
+    /// The controlling peer selects this candidate pair
+    pub nominate: bool,
+}
+impl Message for BindingRequestMessage {
+    const ID: u32 = 17;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.extend(self.transaction.to_le_bytes().iter());
+        buf.extend(self.tiebreaker.to_le_bytes().iter());
+        buf.extend([self.controlling as u8, self.nominate as u8].iter());
+        buf.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 18 {
+            return None;
+        }
+        let transaction = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
+        let tiebreaker = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
+        Some(Box::new(Self {
+            transaction,
+            tiebreaker,
+            controlling: bytes[16] != 0,
+            nominate: bytes[17] != 0,
+        }))
+    }
+}
+#[derive(Clone)]
+pub struct BindingResponseMessage {
+    pub transaction: u64,
+}
+impl Message for BindingResponseMessage {
+    const ID: u32 = 18;
+    fn get_data(&self) -> Vec<u8> {
+        self.transaction.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let transaction = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
+        Some(Box::new(Self { transaction }))
+    }
+}
+/// Asks a relay server for an allocation, or refreshes the existing one
+#[derive(Clone)]
+pub struct RelayAllocateMessage {}
+impl Message for RelayAllocateMessage {
+    const ID: u32 = 19;
+    fn get_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(_bytes: Vec<u8>) -> Option<Box<Self>> {
         Some(Box::new(Self {}))
     }
 }
 #[derive(Clone)]
-pub struct TransferIncompleteMessage {
-    pub bitfield: Bitfield,
+pub struct RelayAllocatedMessage {
+    /// Address on the relay server which forwards to the client
+    pub address: SocketAddr,
 }
-impl Message for TransferIncompleteMessage {
-    const ID: u32 = 8;
+impl Message for RelayAllocatedMessage {
+    const ID: u32 = 20;
     fn get_data(&self) -> Vec<u8> {
-        self.bitfield.get_bytes().clone()
+        let mut buf = Vec::new();
+        write_address(&mut buf, self.address);
+        buf
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        let bitfield = Bitfield::from_bytes(bytes);
-        Some(Box::new(Self { bitfield }))
+        let (address, _) = read_tagged_address(&bytes)?;
+        Some(Box::new(Self { address }))
     }
 }
+/// Datagram tunneled between a client and its relay server
 #[derive(Clone)]
-pub struct TransferSuccessfulMessage {}
-impl Message for TransferSuccessfulMessage {
-    const ID: u32 = 9;
+pub struct RelayDataMessage {
+    /// Where the datagram is going to, or where it came from
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+impl Message for RelayDataMessage {
+    const ID: u32 = 21;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_address(&mut buf, self.peer);
+        buf.extend(self.data.iter());
+        buf
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let (peer, size) = read_tagged_address(&bytes)?;
+        let data = bytes.get(size..)?.to_vec();
+        Some(Box::new(Self { peer, data }))
+    }
+}
+/// The sender will not send anything anymore and wants to close the connection
+#[derive(Clone)]
+pub struct CloseMessage {}
+impl Message for CloseMessage {
+    const ID: u32 = 22;
+    fn get_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(_bytes: Vec<u8>) -> Option<Box<Self>> {
+        Some(Box::new(Self {}))
+    }
+}
+#[derive(Clone)]
+pub struct CloseAckMessage {}
+impl Message for CloseAckMessage {
+    const ID: u32 = 23;
     fn get_data(&self) -> Vec<u8> {
         Vec::new()
     }
 
+    fn from_bytes(_bytes: Vec<u8>) -> Option<Box<Self>> {
+        Some(Box::new(Self {}))
+    }
+}
+/// Multicast by receivers on the LAN, so senders can find them by name
+#[derive(Clone)]
+pub struct AnnounceMessage {
+    pub name: String,
+    pub info: AddressInfo,
+}
+impl Message for AnnounceMessage {
+    const ID: u32 = 24;
+    fn get_data(&self) -> Vec<u8> {
+        write_named_info(&self.name, &self.info)
+    }
+
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let (name, info) = read_named_info(&bytes)?;
+        Some(Box::new(Self { name, info }))
+    }
+}
+/// Sent by a sender to an announced receiver, instead of exchanging codes
+#[derive(Clone)]
+pub struct ConnectRequestMessage {
+    pub name: String,
+    pub info: AddressInfo,
+}
+impl Message for ConnectRequestMessage {
+    const ID: u32 = 25;
+    fn get_data(&self) -> Vec<u8> {
+        write_named_info(&self.name, &self.info)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let (name, info) = read_named_info(&bytes)?;
+        Some(Box::new(Self { name, info }))
+    }
+}
+/// Opens a mailbox on the rendezvous server, or refreshes it. The words of the code
+/// are never sent, the server hands the candidates to the first claim of the nameplate
+#[derive(Clone)]
+pub struct RendezvousRegisterMessage {
+    pub info: AddressInfo,
+}
+impl Message for RendezvousRegisterMessage {
+    const ID: u32 = 26;
+    fn get_data(&self) -> Vec<u8> {
+        self.info.to_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let info = AddressInfo::from_bytes(bytes).ok()?;
+        Some(Box::new(Self { info }))
+    }
+}
+#[derive(Clone)]
+pub struct RendezvousRegisteredMessage {
+    /// Number of the mailbox, the first part of the code
+    pub nameplate: u16,
+}
+impl Message for RendezvousRegisteredMessage {
+    const ID: u32 = 27;
+    fn get_data(&self) -> Vec<u8> {
+        self.nameplate.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let nameplate = u16::from_le_bytes(bytes.get(0..2)?.try_into().ok()?);
+        Some(Box::new(Self { nameplate }))
+    }
+}
+/// Asks for the candidates in a mailbox and leaves ours for its owner
+#[derive(Clone)]
+pub struct RendezvousClaimMessage {
+    pub nameplate: u16,
+    pub info: AddressInfo,
+}
+impl Message for RendezvousClaimMessage {
+    const ID: u32 = 28;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = self.nameplate.to_le_bytes().to_vec();
+        buf.append(&mut self.info.to_bytes());
+        buf
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let nameplate = u16::from_le_bytes(bytes.get(0..2)?.try_into().ok()?);
+        let info = AddressInfo::from_bytes(bytes.get(2..)?.to_vec()).ok()?;
+        Some(Box::new(Self { nameplate, info }))
+    }
+}
+/// Code of the other peer, sent to both sides of a claimed mailbox
+#[derive(Clone)]
+pub struct RendezvousPeerMessage {
+    pub info: AddressInfo,
+}
+impl Message for RendezvousPeerMessage {
+    const ID: u32 = 29;
+    fn get_data(&self) -> Vec<u8> {
+        self.info.to_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let info = AddressInfo::from_bytes(bytes).ok()?;
+        Some(Box::new(Self { info }))
+    }
+}
+/// The claimed mailbox does not exist, or someone else claimed it
+#[derive(Clone)]
+pub struct RendezvousUnknownMessage {}
+impl Message for RendezvousUnknownMessage {
+    const ID: u32 = 30;
+    fn get_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(_bytes: Vec<u8>) -> Option<Box<Self>> {
         Some(Box::new(Self {}))
     }
 }
+
+/// First message on a new connection, carries the nonce of the code we got from the peer
 #[derive(Clone)]
-pub struct GoodbyeMessage {
-    pub motd: String,
+pub struct HelloMessage {
+    pub nonce: [u8; NONCE_SIZE],
 }
-impl Message for GoodbyeMessage {
-    const ID: u32 = 11;
+impl Message for HelloMessage {
+    const ID: u32 = 31;
+    fn get_data(&self) -> Vec<u8> {
+        self.nonce.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let nonce = bytes.get(0..NONCE_SIZE)?.try_into().ok()?;
+        Some(Box::new(Self { nonce }))
+    }
+}
+
+/// Noise handshake message, the only message sent unencrypted on an encrypted connection
+#[derive(Clone)]
+pub struct NoiseHandshakeMessage {
+    pub payload: Vec<u8>,
+}
+impl Message for NoiseHandshakeMessage {
+    const ID: u32 = 32;
+    fn get_data(&self) -> Vec<u8> {
+        self.payload.clone()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        Some(Box::new(Self { payload: bytes }))
+    }
+}
+
+/// Our share of the password-authenticated key exchange
+#[derive(Clone)]
+pub struct PakeShareMessage {
+    pub share: [u8; SHARE_SIZE],
+}
+impl Message for PakeShareMessage {
+    const ID: u32 = 33;
+    fn get_data(&self) -> Vec<u8> {
+        self.share.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let share = bytes.get(0..SHARE_SIZE)?.try_into().ok()?;
+        Some(Box::new(Self { share }))
+    }
+}
+
+/// Proves that we derived the same key from the exchange
+#[derive(Clone)]
+pub struct PakeConfirmMessage {
+    pub confirmation: [u8; TAG_SIZE],
+}
+impl Message for PakeConfirmMessage {
+    const ID: u32 = 34;
+    fn get_data(&self) -> Vec<u8> {
+        self.confirmation.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let confirmation = bytes.get(0..TAG_SIZE)?.try_into().ok()?;
+        Some(Box::new(Self { confirmation }))
+    }
+}
+
+/// Name with its length, followed by a code
+fn write_named_info(name: &str, info: &AddressInfo) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend((name.len() as u32).to_le_bytes().iter());
+    buf.extend(name.as_bytes());
+    buf.append(&mut info.to_bytes());
+    buf
+}
+
+fn read_named_info(bytes: &[u8]) -> Option<(String, AddressInfo)> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let name_size = u32::from_le_bytes(bytes[0..4].try_into().ok()?) as usize;
+    let end = 4usize.checked_add(name_size)?;
+    let name = String::from_utf8(bytes.get(4..end)?.to_vec()).ok()?;
+    let info = AddressInfo::from_bytes(bytes[end..].to_vec()).ok()?;
+    Some((name, info))
+}
+
+/// Our long-term key, with a signature proving that we are on this connection
+#[derive(Clone)]
+pub struct IdentityMessage {
+    pub key: PublicKey,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+impl Message for IdentityMessage {
+    const ID: u32 = 35;
+    fn get_data(&self) -> Vec<u8> {
+        [&self.key[..], &self.signature[..]].concat()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let key = bytes.get(0..PUBLIC_KEY_SIZE)?.try_into().ok()?;
+        let signature = bytes
+            .get(PUBLIC_KEY_SIZE..PUBLIC_KEY_SIZE + SIGNATURE_SIZE)?
+            .try_into()
+            .ok()?;
+        Some(Box::new(Self { key, signature }))
+    }
+}
+
+/// The received file could not be verified, the transfer is given up
+#[derive(Clone)]
+pub struct TransferFailedMessage {
+    pub reason: String,
+}
+impl Message for TransferFailedMessage {
+    const ID: u32 = 36;
+    fn get_data(&self) -> Vec<u8> {
+        self.reason.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let reason = String::from_utf8(bytes).ok()?;
+        Some(Box::new(Self { reason }))
+    }
+}
+
+/// Hashes of the blocks `start..` of the file, the leaves of its Merkle tree
+#[derive(Clone)]
+pub struct BlockHashesMessage {
+    pub start: u32,
+    pub hashes: Vec<Hash>,
+}
+impl Message for BlockHashesMessage {
+    const ID: u32 = 37;
     fn get_data(&self) -> Vec<u8> {
         let mut buf = BytesMut::new();
-        let motd_buffer = self.motd.as_bytes();
-        buf.extend((motd_buffer.len() as u32).to_le_bytes().iter());
-        buf.extend(motd_buffer);
+        buf.extend(self.start.to_le_bytes().iter());
+        for hash in self.hashes.iter() {
+            buf.extend(hash.iter());
+        }
         buf.to_vec()
     }
 
     fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
-        if bytes.len() < 4 {
+        let start = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let rest = &bytes[4..];
+        if !rest.len().is_multiple_of(HASH_SIZE) {
             return None;
         }
-        let motd_size = u32::from_le_bytes(bytes[0..4].try_into().ok()?) as usize;
-        if bytes.len() < (4 + motd_size) as usize {
-            return None;
+        let hashes = rest
+            .chunks(HASH_SIZE)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        Some(Box::new(Self { start, hashes }))
+    }
+}
+
+/// Segments `start..end` belong to a block which failed its check, they have to be sent again
+#[derive(Clone)]
+pub struct RefetchMessage {
+    pub start: u32,
+    pub end: u32,
+}
+impl Message for RefetchMessage {
+    const ID: u32 = 38;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.extend(self.start.to_le_bytes().iter());
+        buf.extend(self.end.to_le_bytes().iter());
+        buf.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let start = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let end = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+        Some(Box::new(Self { start, end }))
+    }
+}
+
+/// Part of the list of files and directories the sender is about to send
+#[derive(Clone)]
+pub struct ManifestMessage {
+    /// Number of entries of the whole manifest
+    pub total: u32,
+    /// Index of the first entry of this message
+    pub start: u32,
+    pub entries: Vec<ManifestEntry>,
+}
+impl Message for ManifestMessage {
+    const ID: u32 = 39;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.extend(self.total.to_le_bytes().iter());
+        buf.extend(self.start.to_le_bytes().iter());
+        for entry in self.entries.iter() {
+            buf.extend(&[match entry.kind {
+                EntryKind::File => 0,
+                EntryKind::Directory => 1,
+            }]);
+            buf.extend(entry.size.to_le_bytes().iter());
+            buf.extend((entry.path.len() as u16).to_le_bytes().iter());
+            buf.extend(entry.path.as_bytes());
         }
-        let motd = String::from_utf8(bytes[4..4 + motd_size].to_vec()).ok()?;
-        Some(Box::new(Self { motd }))
+        buf.to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let total = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
+        let start = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+        let mut entries = Vec::new();
+        let mut rest = &bytes[8..];
+        while let Some(&kind) = rest.first() {
+            let kind = match kind {
+                0 => EntryKind::File,
+                1 => EntryKind::Directory,
+                _ => return None,
+            };
+            let size = u64::from_le_bytes(rest.get(1..9)?.try_into().ok()?);
+            let length = u16::from_le_bytes(rest.get(9..11)?.try_into().ok()?) as usize;
+            let path = String::from_utf8(rest.get(11..11 + length)?.to_vec()).ok()?;
+            entries.push(ManifestEntry { path, kind, size });
+            rest = rest.get(11 + length..)?;
+        }
+        Some(Box::new(Self {
+            total,
+            start,
+            entries,
+        }))
+    }
+}
+
+/// The receiver does not want the requested file, sent instead of accepting it
+#[derive(Clone)]
+pub struct FileTransferDeclineMessage {
+    pub reason: String,
+}
+impl Message for FileTransferDeclineMessage {
+    const ID: u32 = 40;
+    fn get_data(&self) -> Vec<u8> {
+        self.reason.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let reason = String::from_utf8(bytes).ok()?;
+        Some(Box::new(Self { reason }))
+    }
+}
+
+/// Lets `peer` reach the allocation of the client, and the client reach `peer` (like TURN permissions)
+#[derive(Clone)]
+pub struct RelayPermissionMessage {
+    pub peer: SocketAddr,
+}
+impl Message for RelayPermissionMessage {
+    const ID: u32 = 41;
+    fn get_data(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_address(&mut buf, self.peer);
+        buf
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Option<Box<Self>> {
+        let (peer, _) = read_tagged_address(&bytes)?;
+        Some(Box::new(Self { peer }))
     }
 }
@@ -0,0 +1,68 @@
+//Datagram packetization layer path MTU discovery, loosely following RFC 8899.
+//Probes of increasing size are sent over the established connection and the
+//largest acknowledged one becomes the maximum datagram size.
+
+/// Datagram size which is assumed to work on every path
+pub const BASE_PLPMTU: usize = 1200;
+/// Largest UDP payload on an Ethernet path
+pub const MAX_PLPMTU: usize = 1472;
+/// Number of unanswered probes after which a size is considered too large
+pub const MAX_PROBES: u32 = 3;
+/// The search stops when the bounds are closer than this
+const SEARCH_GRANULARITY: usize = 8;
+
+/// Binary search over probe sizes
+pub struct MtuSearch {
+    /// Largest confirmed size
+    low: usize,
+    /// Smallest size which is known to fail
+    high: usize,
+    probe_count: u32,
+    first: bool,
+}
+
+impl MtuSearch {
+    pub fn new(max_plpmtu: usize) -> Self {
+        Self {
+            low: BASE_PLPMTU,
+            high: max_plpmtu.max(BASE_PLPMTU) + 1,
+            probe_count: 0,
+            first: true,
+        }
+    }
+
+    /// Size of the next probe, or `None` once the search is finished
+    pub fn next_probe(&self) -> Option<usize> {
+        if self.high - self.low <= SEARCH_GRANULARITY {
+            None
+        } else if self.first {
+            //Most paths support the maximum, try it right away
+            Some(self.high - 1)
+        } else {
+            Some((self.low + self.high) / 2)
+        }
+    }
+
+    pub fn acknowledged(&mut self, size: usize) {
+        self.first = false;
+        self.probe_count = 0;
+        self.low = self.low.max(size);
+        if self.low >= self.high - 1 {
+            self.high = self.low + 1;
+        }
+    }
+
+    pub fn timed_out(&mut self, size: usize) {
+        self.probe_count += 1;
+        if self.probe_count >= MAX_PROBES {
+            self.first = false;
+            self.probe_count = 0;
+            self.high = self.high.min(size);
+        }
+    }
+
+    /// Largest datagram size confirmed so far
+    pub fn plpmtu(&self) -> usize {
+        self.low
+    }
+}
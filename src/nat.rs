@@ -0,0 +1,169 @@
+use crate::{
+    error::{Error, ErrorKind},
+    stun,
+};
+use std::{fmt::Display, net::SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Number of ports after the last mapping which are advertised behind symmetric NATs
+const PREDICTED_PORTS: i32 = 8;
+/// Larger steps between mapped ports are treated as random allocation
+const MAX_PORT_DELTA: i32 = 16;
+
+/// How the NAT picks ports for new mappings (RFC 4787 section 4.1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// The local port is kept
+    Preserving,
+    /// Every new mapping is this far from the previous one
+    Incrementing(i32),
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mapping {
+    /// Our address is public
+    Open,
+    /// Every destination sees the same address (cone NAT)
+    EndpointIndependent,
+    /// Every destination sees a different address (symmetric NAT)
+    EndpointDependent(PortAllocation),
+    /// Only one STUN server answered
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filtering {
+    /// Other ports of a host we sent to may reach us
+    AddressDependent,
+    /// Only the exact addresses we sent to may reach us
+    AddressAndPortDependent,
+    /// None of the STUN servers supports RFC 5780
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NatBehavior {
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+    /// Our addresses as seen by the STUN servers, in the order they were asked
+    mapped: Vec<SocketAddr>,
+    local_port: u16,
+}
+
+impl NatBehavior {
+    /// Classifies the addresses the STUN servers saw, in the order they were asked.
+    /// `hosts` are the addresses of our interfaces
+    pub fn from_mapped(
+        mapped: Vec<SocketAddr>,
+        hosts: &[SocketAddr],
+        filtering: Filtering,
+    ) -> Self {
+        let local_port = hosts.first().map_or(0, |host| host.port());
+        let ports: Vec<i32> = mapped.iter().map(|address| address.port() as i32).collect();
+        let mapping = if mapped.iter().all(|address| hosts.contains(address)) {
+            Mapping::Open
+        } else if mapped.len() < 2 {
+            Mapping::Unknown
+        } else if mapped.iter().all(|address| *address == mapped[0]) {
+            Mapping::EndpointIndependent
+        } else if ports.iter().all(|port| *port == local_port as i32) {
+            Mapping::EndpointDependent(PortAllocation::Preserving)
+        } else {
+            let delta = ports[1] - ports[0];
+            let constant = ports.windows(2).all(|pair| pair[1] - pair[0] == delta);
+            if constant && delta != 0 && delta.abs() <= MAX_PORT_DELTA {
+                Mapping::EndpointDependent(PortAllocation::Incrementing(delta))
+            } else {
+                Mapping::EndpointDependent(PortAllocation::Random)
+            }
+        };
+        Self {
+            mapping,
+            filtering,
+            mapped,
+            local_port,
+        }
+    }
+
+    /// Addresses the NAT will probably use for the next mappings. Only symmetric NATs with
+    /// predictable ports have them, the peer has to probe them during the connectivity checks
+    pub fn predicted_addresses(&self) -> Vec<SocketAddr> {
+        let last = match self.mapped.last() {
+            Some(last) => *last,
+            None => return Vec::new(),
+        };
+        let ports: Vec<i32> = match self.mapping {
+            Mapping::EndpointDependent(PortAllocation::Preserving) => vec![self.local_port as i32],
+            Mapping::EndpointDependent(PortAllocation::Incrementing(delta)) => {
+                let last_port = last.port() as i32;
+                (1..=PREDICTED_PORTS)
+                    .map(|i| last_port + delta * i)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        ports
+            .into_iter()
+            .filter(|port| (1..=65535).contains(port))
+            .map(|port| SocketAddr::new(last.ip(), port as u16))
+            .collect()
+    }
+}
+
+impl Display for NatBehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.mapping {
+            Mapping::Open => f.write_str("no NAT")?,
+            Mapping::EndpointIndependent => f.write_str("cone NAT")?,
+            Mapping::EndpointDependent(PortAllocation::Preserving) => {
+                f.write_str("symmetric NAT, preserving ports")?
+            }
+            Mapping::EndpointDependent(PortAllocation::Incrementing(delta)) => {
+                write!(f, "symmetric NAT, ports change by {}", delta)?
+            }
+            Mapping::EndpointDependent(PortAllocation::Random) => {
+                f.write_str("symmetric NAT, random ports")?
+            }
+            Mapping::Unknown => f.write_str("unknown NAT")?,
+        }
+        match self.filtering {
+            Filtering::AddressDependent => f.write_str(", address-dependent filtering"),
+            Filtering::AddressAndPortDependent => f.write_str(", port-dependent filtering"),
+            Filtering::Unknown => Ok(()),
+        }
+    }
+}
+
+/// Detects the behavior of the NAT in front of `socket` by asking every server in turn.
+/// `hosts` are the addresses of our interfaces
+pub async fn detect(
+    socket: &mut UdpSocket,
+    servers: &[SocketAddr],
+    hosts: &[SocketAddr],
+) -> Result<NatBehavior, Error> {
+    let mut mapped = Vec::new();
+    let mut alternate = None;
+    for &server in servers.iter() {
+        if let Ok(response) = stun::query(socket, server, false).await {
+            mapped.push(response.mapped);
+            if response.other_address.is_some() {
+                alternate.get_or_insert(server);
+            }
+        }
+    }
+    if mapped.is_empty() {
+        return Err(Error::timeout("No STUN server answered"));
+    }
+    let filtering = match alternate {
+        //The mapping to this server exists already, only the filter decides whether the answer arrives
+        Some(server) => match stun::query(socket, server, true).await {
+            Ok(_) => Filtering::AddressDependent,
+            Err(e) if e.kind() == ErrorKind::Timeout => Filtering::AddressAndPortDependent,
+            //The server refused the port change
+            Err(_) => Filtering::Unknown,
+        },
+        None => Filtering::Unknown,
+    };
+    Ok(NatBehavior::from_mapped(mapped, hosts, filtering))
+}
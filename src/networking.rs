@@ -1,17 +1,205 @@
-use crate::message::{Messages, PingMessage, ReliableAckMessage, ReliableMessage};
-use crate::{bitfield::Bitfield, error::Error};
-use bytes::BytesMut;
+use crate::message::{
+    BindingRequestMessage, BindingResponseMessage, CloseAckMessage, CloseMessage, HelloMessage,
+    IdentityMessage, Messages, MtuProbeAckMessage, MtuProbeMessage, NoiseHandshakeMessage,
+    PakeConfirmMessage, PakeShareMessage, PingMessage, PongMessage, ReliableAckMessage,
+    ReliableMessage,
+};
+use crate::{
+    bitfield::Bitfield,
+    candidate::{self, Candidate, CandidateKind},
+    demux::{self, Packet},
+    error::Error,
+    identity::{self, Identity, KnownPeers, PublicKey},
+    mtu::{MtuSearch, BASE_PLPMTU, MAX_PLPMTU},
+    obfuscator::Session,
+    pake::{self, Pake},
+    relay,
+    secure::{self, Decryptor, Encryptor, Handshake},
+};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    net::SocketAddr,
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::net::UdpSocket;
-use tokio::sync::broadcast;
-use tokio_util::codec::Decoder;
+use tokio::sync::broadcast::{self, Receiver, RecvError};
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+const DEFAULT_MAX_RETRIES: u32 = 8;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(30);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// Three pings in a row got lost
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_FAILURE_TIMEOUT: Duration = Duration::from_secs(30);
+const CLOSE_ATTEMPTS: u32 = 3;
+/// After the peer closed, its close requests are still answered for this long
+const CLOSE_LINGER: Duration = Duration::from_secs(2);
+/// Largest possible UDP payload
+pub const RECEIVE_BUFFER_SIZE: usize = 65536;
+/// Pacing of connectivity checks (Ta in RFC 8445)
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+const CHECK_RTO: Duration = Duration::from_millis(250);
+const MAX_CHECK_ATTEMPTS: u32 = 8;
+const CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+/// After the first working pair, the controlling peer waits this long for better ones
+const NOMINATION_DELAY: Duration = Duration::from_millis(500);
+/// Time the peer has to prove that it got our current code
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+const HANDSHAKE_RTO: Duration = Duration::from_millis(250);
+
+/// Round trip time estimator as described in RFC 6298
+#[derive(Default)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, sample: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+        }
+    }
+
+    /// Smoothed round trip time, if there was at least one sample
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Retransmission timeout
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).max(MIN_RTO).min(MAX_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Nothing was received from the peer yet
+    Connecting,
+    /// The peer was heard from recently
+    Established,
+    /// The peer was silent for the idle timeout, but may come back
+    Idle,
+    /// We are closing the connection
+    Closing,
+    Closed,
+    /// The peer was silent for the failure timeout
+    Failed,
+}
+
+impl ConnectionState {
+    /// Closed and failed connections never change their state again
+    pub fn is_terminal(self) -> bool {
+        matches!(self, ConnectionState::Closed | ConnectionState::Failed)
+    }
+}
+
+/// Connection state shared by the handler and its tasks
+#[derive(Clone)]
+struct SharedState {
+    sender: Arc<Mutex<watch::Sender<ConnectionState>>>,
+    receiver: watch::Receiver<ConnectionState>,
+}
+
+impl SharedState {
+    fn new() -> Self {
+        let (sender, receiver) = watch::channel(ConnectionState::Connecting);
+        Self {
+            sender: Arc::new(Mutex::new(sender)),
+            receiver,
+        }
+    }
+
+    fn get(&self) -> ConnectionState {
+        *self.receiver.borrow()
+    }
+
+    /// Moves to `to` if the connection is in one of the `from` states
+    fn transition(&self, from: &[ConnectionState], to: ConnectionState) -> bool {
+        let sender = self.sender.lock().unwrap();
+        if !from.contains(&self.get()) {
+            return false;
+        }
+        sender.broadcast(to).ok();
+        true
+    }
+}
+
+/// Waits until the connection is closed or failed
+async fn ended(state: &mut watch::Receiver<ConnectionState>) -> ConnectionState {
+    while let Some(state) = state.recv().await {
+        if state.is_terminal() {
+            return state;
+        }
+    }
+    ConnectionState::Closed
+}
+
+fn disconnected(state: ConnectionState) -> Error {
+    match state {
+        ConnectionState::Failed => Error::disconnected("Peer stopped responding"),
+        _ => Error::disconnected("Connection closed"),
+    }
+}
+
+/// Number of reliable messages which still wait for their acknowledgement
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    drained: Notify,
+}
+
+impl InFlight {
+    /// Waits until no reliable message is outstanding
+    async fn drained(&self) {
+        while self.count.load(Ordering::SeqCst) > 0 {
+            self.drained.notified().await;
+        }
+    }
+}
+
+/// Counts a reliable message as outstanding while it lives
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlightGuard {
+    fn new(in_flight: &Arc<InFlight>) -> Self {
+        in_flight.count.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(in_flight))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify();
+        }
+    }
+}
 
 pub struct NetworkHandler {
     local_addr: SocketAddr,
@@ -20,46 +208,121 @@ pub struct NetworkHandler {
     receiver_in: broadcast::Sender<Messages>,
     index: Arc<AtomicU32>,
     received_messages: Arc<Mutex<Bitfield>>,
+    rtt: Arc<Mutex<RttEstimator>>,
+    max_retries: u32,
+    max_datagram_size: Arc<AtomicUsize>,
+    relay: Option<SocketAddr>,
+    state: SharedState,
+    idle_timeout: Duration,
+    failure_timeout: Duration,
+    in_flight: Arc<InFlight>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    /// Socket handed over by `with_socket`, used by `begin` instead of binding a new one
+    socket: Mutex<Option<UdpSocket>>,
+    /// Encryption handshake `begin` performs before anything else
+    handshake: Mutex<Option<Handshake>>,
+    /// Bytes the encryption adds to every datagram
+    overhead: usize,
+    /// Hash of the encryption handshake, binds later exchanges to this connection
+    channel_binding: Mutex<Option<Vec<u8>>>,
+    key_updates: Mutex<Option<KeyUpdates>>,
+}
+
+/// What the peers prove to each other before the transfer
+pub struct Introduction<'a> {
+    /// Session of our code and of the partner's code
+    pub ours: &'a Session,
+    pub theirs: &'a Session,
+    /// Derived from the code. None if the user accepted a connection on the LAN,
+    /// which is not authenticated
+    pub password: Option<&'a [u8]>,
+    pub identity: &'a Identity,
+    pub known_peers: &'a mut KnownPeers,
+    /// Name the partner's key is remembered under
+    pub nickname: Option<&'a str>,
+}
+
+/// Secrets for the tasks which encrypt and decrypt the datagrams
+struct KeyUpdates {
+    incoming: mpsc::UnboundedSender<[u8; pake::KEY_SIZE]>,
+    outgoing: mpsc::UnboundedSender<[u8; pake::KEY_SIZE]>,
 }
 
 pub struct Sender {
     sender: tokio::sync::broadcast::Sender<Messages>,
     receiver: tokio::sync::broadcast::Receiver<Messages>,
     index: Arc<AtomicU32>,
+    rtt: Arc<Mutex<RttEstimator>>,
+    max_retries: u32,
+    max_datagram_size: Arc<AtomicUsize>,
+    state: watch::Receiver<ConnectionState>,
+    in_flight: Arc<InFlight>,
 }
 
 impl Sender {
+    /// Sends a message without waiting for an acknowledgement.
+    /// Fails if the message does not fit into a single datagram on the current path
     pub fn send(&self, message: Messages) -> Result<(), Error> {
+        let state = *self.state.borrow();
+        if state.is_terminal() {
+            return Err(disconnected(state));
+        }
+        if !matches!(message, Messages::MtuProbe(_))
+            && message.to_datagram().len() > self.max_datagram_size.load(Ordering::Relaxed)
+        {
+            return Err(Error::new("Message is larger than the path MTU"));
+        }
         self.sender
             .send(message)
-            .map_err(|a| Error::new("Packet send error: "))?;
+            .map_err(|_| Error::new("Packet send error"))?;
         Ok(())
     }
 
+    /// Sends a message until it is acknowledged. The retransmission timeout is doubled
+    /// after every attempt, and a timeout error is returned after `max_retries` retries
     pub async fn send_reliable(&mut self, message: Messages) -> Result<(), Error> {
+        if *self.state.borrow() == ConnectionState::Closing {
+            return Err(Error::disconnected("Connection is closing"));
+        }
+        let _guard = InFlightGuard::new(&self.in_flight);
         let index = self.index.fetch_add(1, Ordering::Relaxed);
-        let mut ping_interval = tokio::time::interval(Duration::from_secs(3));
-        loop {
-            tokio::select! {
-                msg = self.receiver.recv() => {
-                    if let Ok(msg) = msg{
-                        if let Messages::ReliableAck(msg) = msg{
-                            if msg.packet_index == index{
-                                break;
+        let mut rto = self.rtt.lock().unwrap().rto();
+        for attempt in 0..=self.max_retries {
+            let message = Box::new(message.clone());
+            self.send(Messages::Reliable(ReliableMessage {
+                packet_index: index,
+                message,
+            }))?;
+            let sent_at = Instant::now();
+            let receiver = &mut self.receiver;
+            let mut state = self.state.clone();
+            let acknowledged = tokio::time::timeout(rto, async {
+                loop {
+                    tokio::select! {
+                        msg = receiver.recv() => match msg {
+                            Ok(Messages::ReliableAck(msg)) if msg.packet_index == index => {
+                                return Ok(())
                             }
-                        }
+                            Err(RecvError::Closed) => return Err(Error::new("Connection closed")),
+                            _ => continue,
+                        },
+                        state = ended(&mut state) => return Err(disconnected(state)),
                     }
                 }
-                _ = ping_interval.tick() => {
-                    let message = Box::new(message.clone());
-                    self.send(Messages::Reliable(ReliableMessage{
-                        packet_index: index,
-                        message,
-                    }))?;
+            })
+            .await;
+            match acknowledged {
+                Ok(result) => {
+                    //Karn's algorithm: acks of retransmitted messages are ambiguous
+                    if attempt == 0 {
+                        self.rtt.lock().unwrap().update(sent_at.elapsed());
+                    }
+                    return result;
                 }
+                Err(_) => rto = (rto * 2).min(MAX_RTO),
             }
         }
-        Ok(())
+        Err(Error::timeout("Peer did not acknowledge the message"))
     }
 }
 
@@ -74,15 +337,131 @@ impl NetworkHandler {
             receiver_in,
             index: Arc::new(AtomicU32::new(0)),
             received_messages: Arc::new(Mutex::new(Bitfield::new())),
+            rtt: Arc::new(Mutex::new(RttEstimator::new())),
+            max_retries: DEFAULT_MAX_RETRIES,
+            max_datagram_size: Arc::new(AtomicUsize::new(BASE_PLPMTU)),
+            relay: None,
+            state: SharedState::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            failure_timeout: DEFAULT_FAILURE_TIMEOUT,
+            in_flight: Arc::new(InFlight::default()),
+            tasks: Mutex::new(Vec::new()),
+            socket: Mutex::new(None),
+            handshake: Mutex::new(None),
+            overhead: 0,
+            channel_binding: Mutex::new(None),
+            key_updates: Mutex::new(None),
         }
     }
 
+    /// Uses an existing socket, like the one the STUN servers and connectivity checks used.
+    /// Keeps the NAT mapping the peer already knows about
+    pub fn with_socket(socket: UdpSocket, remote_addr: SocketAddr) -> Result<Self, Error> {
+        let handler = Self::new(socket.local_addr()?, remote_addr);
+        *handler.socket.lock().unwrap() = Some(socket);
+        Ok(handler)
+    }
+
+    /// Silence after which an established connection becomes idle
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Silence after which the connection fails. Has to be longer than the idle timeout
+    pub fn set_failure_timeout(&mut self, timeout: Duration) {
+        self.failure_timeout = timeout;
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Notifies about every change of the connection state
+    pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.receiver.clone()
+    }
+
+    /// Tunnels all traffic through our allocation on the relay `server`
+    pub fn set_relay(&mut self, server: SocketAddr) {
+        self.relay = Some(server);
+    }
+
+    /// Encrypts and authenticates all traffic. `begin` performs the handshake,
+    /// the `initiator` sends its first message
+    pub fn set_encryption(&mut self, initiator: bool) -> Result<(), Error> {
+        *self.handshake.get_mut().unwrap() = Some(Handshake::new(initiator)?);
+        self.overhead = secure::OVERHEAD;
+        self.max_datagram_size
+            .store(BASE_PLPMTU - self.overhead, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Number of retransmissions before `Sender::send_reliable` gives up
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
     pub fn get_sender(&self) -> Sender {
         Sender {
             sender: self.sender.clone(),
             index: Arc::clone(&self.index),
             receiver: self.subscribe(),
+            rtt: Arc::clone(&self.rtt),
+            max_retries: self.max_retries,
+            max_datagram_size: Arc::clone(&self.max_datagram_size),
+            state: self.watch_state(),
+            in_flight: Arc::clone(&self.in_flight),
+        }
+    }
+
+    /// Largest datagram which can be sent to the peer
+    pub fn max_datagram_size(&self) -> usize {
+        self.max_datagram_size.load(Ordering::Relaxed)
+    }
+
+    /// Probes the path for the largest datagram size which reaches the peer,
+    /// and uses it as the limit for all further messages
+    pub async fn discover_mtu(&self) -> usize {
+        let mut receiver = self.subscribe();
+        let sender = self.get_sender();
+        let mut search = MtuSearch::new(MAX_PLPMTU);
+        while let Some(size) = search.next_probe() {
+            //Probes are padded to the size, the encryption has to fit in as well
+            let padded = size - self.overhead;
+            let probe = Messages::MtuProbe(MtuProbeMessage {
+                size: padded as u32,
+            });
+            if sender.send(probe).is_err() {
+                break;
+            }
+            let acknowledged = tokio::time::timeout(self.rto(), async {
+                loop {
+                    match receiver.recv().await {
+                        Ok(Messages::MtuProbeAck(msg)) if msg.size as usize == padded => break,
+                        Err(RecvError::Closed) => break,
+                        _ => continue,
+                    }
+                }
+            })
+            .await;
+            match acknowledged {
+                Ok(_) => search.acknowledged(size),
+                Err(_) => search.timed_out(size),
+            }
         }
+        let size = search.plpmtu() - self.overhead;
+        self.max_datagram_size.store(size, Ordering::Relaxed);
+        size
+    }
+
+    /// Smoothed round trip time to the peer, if it was measured already
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.lock().unwrap().smoothed()
+    }
+
+    /// Current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rtt.lock().unwrap().rto()
     }
 
     pub async fn receive(&self) -> Result<Messages, Error> {
@@ -96,88 +475,1029 @@ impl NetworkHandler {
         self.receiver_in.subscribe()
     }
 
+    /// Waits for the next message of `receiver`. Fails once the connection is closed or failed
+    pub async fn recv(&self, receiver: &mut Receiver<Messages>) -> Result<Messages, Error> {
+        let mut state = self.watch_state();
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => match msg {
+                    Ok(msg) => return Ok(msg),
+                    //Lost messages are recovered by the transfer protocols
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Err(Error::disconnected("Connection closed")),
+                },
+                state = ended(&mut state) => return Err(disconnected(state)),
+            }
+        }
+    }
+
     pub async fn begin(&self) -> Result<(), Error> {
-        let client = UdpSocket::bind(self.local_addr).await?;
-        client.connect(self.remote_addr).await?;
+        let mut client = match self.socket.lock().unwrap().take() {
+            Some(socket) => socket,
+            None => UdpSocket::from_std(bind_dual_stack(self.local_addr)?)?,
+        };
+        set_dont_fragment(&client)?;
+        let (remote_addr, relay) = (self.remote_addr, self.relay);
+        //Everything goes through the relay, if there is one
+        let target = relay.unwrap_or(remote_addr);
+        let destination = map_to_family(target, self.local_addr);
+        let handshake = self.handshake.lock().unwrap().take();
+        let (mut encryptor, mut decryptor, final_message) = match handshake {
+            Some(handshake) => {
+                let encryption =
+                    encryption_handshake(&mut client, handshake, destination, remote_addr, relay)
+                        .await?;
+                *self.channel_binding.lock().unwrap() = Some(encryption.binding);
+                (
+                    Some(encryption.encryptor),
+                    Some(encryption.decryptor),
+                    encryption.final_message,
+                )
+            }
+            None => (None, None, None),
+        };
         let (mut udp_receiver, mut udp_sender) = client.split();
-        let mut codec = Messages::Ping(PingMessage {});
+        //Datagrams which are sent as they are, bypassing the encryption
+        let (raw_in, mut raw_out) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (incoming, mut incoming_keys) = mpsc::unbounded_channel();
+        let (outgoing, mut outgoing_keys) = mpsc::unbounded_channel();
+        *self.key_updates.lock().unwrap() = Some(KeyUpdates { incoming, outgoing });
+        let mut tasks = self.tasks.lock().unwrap();
         //Send messages to peer
         let mut sender_out = self.sender.subscribe();
-        tokio::spawn(async move {
+        let mut state = self.watch_state();
+        tasks.push(tokio::spawn(async move {
+            let mut wrap = |msg: Messages| {
+                let mut datagram = msg.to_datagram();
+                if let Some(encryptor) = encryptor.as_mut() {
+                    while let Ok(secret) = outgoing_keys.try_recv() {
+                        encryptor.rekey_with(&secret);
+                    }
+                    datagram = encryptor.seal(&datagram).ok()?;
+                }
+                Some(wrap_datagram(datagram, remote_addr, relay))
+            };
             loop {
-                let msg = sender_out.recv().await;
-                if let Ok(msg) = msg {
-                    let bytes_vec = msg.get_bytes();
-                    let bytes = bytes_vec.as_slice();
-                    udp_sender.send(bytes).await.unwrap();
+                tokio::select! {
+                    msg = sender_out.recv() => match msg {
+                        //Oversized probes are rejected by the OS, those are simply lost
+                        Ok(msg) => {
+                            if let Some(datagram) = wrap(msg) {
+                                udp_sender.send_to(&datagram, &destination).await.ok();
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    },
+                    Some(datagram) = raw_out.recv() => {
+                        udp_sender.send_to(&datagram, &destination).await.ok();
+                    }
+                    _ = ended(&mut state) => break,
                 }
             }
-        });
+            //The last close acknowledgement may still be queued
+            while let Ok(msg) = sender_out.try_recv() {
+                if let Some(datagram) = wrap(msg) {
+                    udp_sender.send_to(&datagram, &destination).await.ok();
+                }
+            }
+        }));
         //Receive messages from peer
         let receiver_in = self.receiver_in.clone();
-        tokio::spawn(async move {
-            let mut buf = [0; 2048];
+        let mut state = self.watch_state();
+        tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
 
             loop {
-                if let Ok(size) = udp_receiver.recv(&mut buf).await {
-                    if size < 4 {
-                        continue;
+                let (size, from) = tokio::select! {
+                    received = udp_receiver.recv_from(&mut buf) => match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    },
+                    _ = ended(&mut state) => break,
+                };
+                //The socket may be shared, late STUN answers and strangers are ignored
+                if unmap(from) != target {
+                    continue;
+                }
+                let datagram = match unwrap_datagram(&buf[..size], remote_addr, relay) {
+                    Some(datagram) => datagram,
+                    None => continue,
+                };
+                let data = match decryptor.as_mut() {
+                    Some(decryptor) => {
+                        while let Ok(secret) = incoming_keys.try_recv() {
+                            decryptor.expect_secret(secret);
+                        }
+                        match decryptor.open(&datagram) {
+                            Some(datagram) => Messages::from_datagram(&datagram),
+                            None => {
+                                //The peer missed our last handshake message, anything else is refused
+                                let message = Messages::from_datagram(&datagram);
+                                if let (Some(Messages::NoiseHandshake(_)), Some(final_message)) =
+                                    (message, &final_message)
+                                {
+                                    raw_in.send(final_message.clone()).ok();
+                                }
+                                continue;
+                            }
+                        }
                     }
-                    let mut bytes = BytesMut::new();
-                    bytes.extend(buf.iter().take(size));
-                    let data = codec.decode(&mut bytes).ok().unwrap().unwrap(); //TODO: replace unwrap
-                    receiver_in
-                        .send(data)
-                        .map_err(|_| Error::new("Broadcast send error"))
-                        .unwrap();
+                    None => Messages::from_datagram(&datagram),
+                };
+                if let Some(data) = data {
+                    receiver_in.send(data).ok();
                 }
             }
-        });
+        }));
         //Handle reliable messages
         let mut receiver = self.subscribe();
         let received_messages = Arc::clone(&self.received_messages);
         let receiver_in = self.receiver_in.clone();
         let sender = self.get_sender();
-        tokio::spawn(async move {
+        let mut state = self.watch_state();
+        tasks.push(tokio::spawn(async move {
             loop {
-                if let Ok(msg) = receiver.recv().await {
-                    if let Messages::Reliable(msg) = msg {
-                        let mut received_messages = received_messages.lock().unwrap();
-                        if !received_messages.get(msg.packet_index as usize) {
-                            received_messages.set(msg.packet_index as usize, true);
-                            receiver_in
-                                .send(*msg.message)
-                                .map_err(|_| Error::new("Broadcast send error"))
-                                .unwrap();
-                        }
+                let msg = tokio::select! {
+                    msg = receiver.recv() => msg,
+                    _ = ended(&mut state) => break,
+                };
+                if let Ok(Messages::Reliable(msg)) = msg {
+                    let mut received_messages = received_messages.lock().unwrap();
+                    if !received_messages.get(msg.packet_index as usize) {
+                        received_messages.set(msg.packet_index as usize, true);
+                        receiver_in.send(*msg.message).ok();
+                    }
 
-                        sender
-                            .send(Messages::ReliableAck(ReliableAckMessage {
-                                packet_index: msg.packet_index,
-                            }))
-                            .unwrap();
+                    sender
+                        .send(Messages::ReliableAck(ReliableAckMessage {
+                            packet_index: msg.packet_index,
+                        }))
+                        .ok();
+                }
+            }
+        }));
+        //Answer pings, path MTU probes and close requests
+        let mut receiver = self.subscribe();
+        let sender = self.get_sender();
+        let shared_state = self.state.clone();
+        let mut state = self.watch_state();
+        tasks.push(tokio::spawn(async move {
+            use ConnectionState::*;
+            loop {
+                let msg = tokio::select! {
+                    msg = receiver.recv() => msg,
+                    _ = ended(&mut state) => break,
+                };
+                let answer = match msg {
+                    Ok(Messages::Ping(msg)) => Messages::Pong(PongMessage {
+                        sequence: msg.sequence,
+                    }),
+                    Ok(Messages::MtuProbe(msg)) => {
+                        Messages::MtuProbeAck(MtuProbeAckMessage { size: msg.size })
+                    }
+                    //Late connectivity checks, the peer might still wait for its nomination
+                    Ok(Messages::BindingRequest(msg)) => {
+                        Messages::BindingResponse(BindingResponseMessage {
+                            transaction: msg.transaction,
+                        })
+                    }
+                    Ok(Messages::Close(_)) => {
+                        //Stay around for a while, in case our acknowledgement gets lost
+                        if shared_state.transition(&[Connecting, Established, Idle], Closing) {
+                            let shared_state = shared_state.clone();
+                            tokio::spawn(async move {
+                                tokio::time::delay_for(CLOSE_LINGER).await;
+                                shared_state.transition(&[Closing], Closed);
+                            });
+                        }
+                        Messages::CloseAck(CloseAckMessage {})
+                    }
+                    _ => continue,
+                };
+                sender.send(answer).ok();
+            }
+        }));
+        //Track the liveness of the peer, any message counts
+        let mut receiver = self.subscribe();
+        let state = self.state.clone();
+        let mut changes = self.watch_state();
+        let (idle_timeout, failure_timeout) = (self.idle_timeout, self.failure_timeout);
+        tasks.push(tokio::spawn(async move {
+            use ConnectionState::*;
+            let mut last_received = Instant::now();
+            loop {
+                let established = state.get() == Established;
+                let timeout = if established {
+                    idle_timeout
+                } else {
+                    failure_timeout
+                };
+                let deadline = tokio::time::Instant::from_std(last_received + timeout);
+                let received = tokio::select! {
+                    received = tokio::time::timeout_at(deadline, receiver.recv()) => received,
+                    _ = ended(&mut changes) => break,
+                };
+                match received {
+                    Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => {
+                        last_received = Instant::now();
+                        state.transition(&[Connecting, Idle], Established);
+                    }
+                    Ok(Err(RecvError::Closed)) => break,
+                    Err(_) if established => {
+                        state.transition(&[Established], Idle);
+                    }
+                    Err(_) => {
+                        state.transition(&[Connecting, Idle], Failed);
                     }
                 }
             }
-        });
+        }));
+        //Send pings and measure the round trip time from the answers
+        let mut receiver = self.subscribe();
         let ping_sender = self.get_sender();
-        //Send pings
-        tokio::spawn(async move {
-            let mut ping_interval = tokio::time::interval(Duration::from_secs(5));
+        let rtt = Arc::clone(&self.rtt);
+        let mut state = self.watch_state();
+        tasks.push(tokio::spawn(async move {
+            let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+            let mut pending: VecDeque<(u32, Instant)> = VecDeque::new();
+            let mut sequence = 0u32;
             loop {
-                ping_interval.tick().await;
-                ping_sender.send(Messages::Ping(PingMessage {})).unwrap();
+                tokio::select! {
+                    _ = ping_interval.tick() => {
+                        sequence = sequence.wrapping_add(1);
+                        pending.push_back((sequence, Instant::now()));
+                        if pending.len() > 4 {
+                            pending.pop_front();
+                        }
+                        ping_sender.send(Messages::Ping(PingMessage { sequence })).ok();
+                    }
+                    msg = receiver.recv() => {
+                        if let Ok(Messages::Pong(msg)) = msg {
+                            if let Some(position) = pending.iter().position(|(s, _)| *s == msg.sequence) {
+                                let (_, sent_at) = pending.remove(position).unwrap();
+                                rtt.lock().unwrap().update(sent_at.elapsed());
+                            }
+                        }
+                    }
+                    _ = ended(&mut state) => break,
+                }
             }
-        });
+        }));
         Ok(())
     }
 
-    pub async fn wait_for_connection(&self) {
+    /// Closes the connection: waits until every reliable message is acknowledged,
+    /// performs the close handshake with the peer and stops all tasks, which releases the socket.
+    /// Fails if the peer did not answer the handshake, the connection is closed anyway
+    pub async fn close(&self) -> Result<(), Error> {
+        use ConnectionState::*;
         let mut receiver = self.subscribe();
-        while let Ok(message) = receiver.recv().await {
-            if let Messages::Ping(_) = message {
-                break;
+        let result = if self
+            .state
+            .transition(&[Connecting, Established, Idle], Closing)
+        {
+            self.in_flight.drained().await;
+            self.close_handshake(&mut receiver).await
+        } else {
+            Ok(())
+        };
+        self.state
+            .transition(&[Connecting, Established, Idle, Closing], Closed);
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task.await.ok();
+        }
+        result
+    }
+
+    async fn close_handshake(&self, receiver: &mut Receiver<Messages>) -> Result<(), Error> {
+        let sender = self.get_sender();
+        let mut rto = self.rto();
+        for _ in 0..CLOSE_ATTEMPTS {
+            sender.send(Messages::Close(CloseMessage {}))?;
+            let answered = tokio::time::timeout(rto, async {
+                loop {
+                    match receiver.recv().await {
+                        //Both peers close at the same time
+                        Ok(Messages::CloseAck(_)) | Ok(Messages::Close(_)) => break,
+                        Err(RecvError::Closed) => break,
+                        _ => continue,
+                    }
+                }
+            })
+            .await;
+            if answered.is_ok() {
+                return Ok(());
+            }
+            rto = (rto * 2).min(MAX_RTO);
+        }
+        Err(Error::timeout("Peer did not acknowledge the close"))
+    }
+
+    /// Proves to the peer that we know `password` with a CPace exchange bound to the
+    /// encrypted connection, which unmasks a man in the middle as well. The agreed key
+    /// is mixed into the keys of the connection. The `initiator` speaks first
+    pub async fn authenticate(
+        &self,
+        receiver: &mut Receiver<Messages>,
+        password: &[u8],
+        initiator: bool,
+    ) -> Result<(), Error> {
+        //Everyone knows the empty password, the exchange would prove nothing
+        if password.is_empty() {
+            return Err(Error::new("Refusing to authenticate without a password"));
+        }
+        let binding = self.channel_binding.lock().unwrap().clone();
+        let binding = binding.ok_or(Error::new("The connection is not encrypted"))?;
+        let pake = Pake::new(password, &binding, initiator);
+        let mut sender = self.get_sender();
+        let share = Messages::PakeShare(PakeShareMessage {
+            share: pake.share(),
+        });
+        let exchange = async {
+            if initiator {
+                sender.send_reliable(share.clone()).await?;
+            }
+            let theirs = loop {
+                if let Messages::PakeShare(msg) = self.recv(receiver).await? {
+                    break msg.share;
+                }
+            };
+            let keys = pake.finish(&theirs)?;
+            self.update_keys(|updates| updates.incoming.send(keys.key).ok());
+            let confirm = Messages::PakeConfirm(PakeConfirmMessage {
+                confirmation: keys.confirmation,
+            });
+            if !initiator {
+                sender.send_reliable(share).await?;
+                sender.send_reliable(confirm.clone()).await?;
+            }
+            let confirmation = loop {
+                if let Messages::PakeConfirm(msg) = self.recv(receiver).await? {
+                    break msg.confirmation;
+                }
+            };
+            //Sent even if the peer failed, so it knows as well
+            if initiator {
+                sender.send_reliable(confirm).await?;
+            }
+            if !keys.verify(&confirmation) {
+                return Err(Error::new(
+                    "The partner used a different code, the connection was aborted",
+                ));
+            }
+            self.update_keys(|updates| updates.outgoing.send(keys.key).ok());
+            Ok(())
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::timeout("The partner did not authenticate"))?
+    }
+
+    /// Sends our identity key, signed over the encrypted connection, and returns the verified key of the peer
+    pub async fn exchange_identity(
+        &self,
+        receiver: &mut Receiver<Messages>,
+        identity: &Identity,
+        first: bool,
+    ) -> Result<PublicKey, Error> {
+        let binding = self.channel_binding.lock().unwrap().clone();
+        let binding = binding.ok_or(Error::new("The connection is not encrypted"))?;
+        let mut sender = self.get_sender();
+        let ours = Messages::Identity(IdentityMessage {
+            key: identity.public_key(),
+            signature: identity.sign(&binding),
+        });
+        let exchange = async {
+            if first {
+                sender.send_reliable(ours.clone()).await?;
+            }
+            let theirs = loop {
+                if let Messages::Identity(msg) = self.recv(receiver).await? {
+                    break msg;
+                }
+            };
+            if !first {
+                sender.send_reliable(ours).await?;
+            }
+            if !identity::verify(&theirs.key, &binding, &theirs.signature) {
+                return Err(Error::new("The partner's identity could not be verified"));
+            }
+            Ok(theirs.key)
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::timeout("The partner did not send its identity"))?
+    }
+
+    /// Verifies the code and the identity of the peer. The peer with the file goes `first`
+    pub async fn introduce(
+        &self,
+        receiver: &mut Receiver<Messages>,
+        introduction: Introduction<'_>,
+        first: bool,
+    ) -> Result<(), Error> {
+        self.verify_session(receiver, introduction.ours, introduction.theirs, first)
+            .await?;
+        match introduction.password {
+            Some(password) => self.authenticate(receiver, password, first).await?,
+            None => println!("Skipping the authentication of the partner"),
+        }
+        let key = self
+            .exchange_identity(receiver, introduction.identity, first)
+            .await?;
+        introduction.known_peers.trust(introduction.nickname, &key)
+    }
+
+    fn update_keys(&self, update: impl FnOnce(&KeyUpdates) -> Option<()>) {
+        if let Some(updates) = self.key_updates.lock().unwrap().as_ref() {
+            update(updates);
+        }
+    }
+
+    pub async fn wait_for_connection(&self) -> Result<(), Error> {
+        let mut receiver = self.subscribe();
+        loop {
+            if let Messages::Ping(_) = self.recv(&mut receiver).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Proves to the peer that we got its code `theirs`, and checks that it got our code
+    /// `ours`, so old or foreign codes can't be used to connect. The `first` side speaks
+    /// first, the other one has to be subscribed with `receiver` before that
+    pub async fn verify_session(
+        &self,
+        receiver: &mut Receiver<Messages>,
+        ours: &Session,
+        theirs: &Session,
+        first: bool,
+    ) -> Result<(), Error> {
+        let mut sender = self.get_sender();
+        let hello = Messages::Hello(HelloMessage {
+            nonce: theirs.nonce,
+        });
+        let handshake = async {
+            if first {
+                sender.send_reliable(hello.clone()).await?;
+            }
+            let nonce = loop {
+                if let Messages::Hello(msg) = self.recv(receiver).await? {
+                    break msg.nonce;
+                }
+            };
+            if !first {
+                sender.send_reliable(hello).await?;
+            }
+            if nonce != ours.nonce {
+                return Err(Error::new("The partner does not have our current code"));
+            }
+            Ok(())
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| Error::timeout("The partner did not verify the code"))?
+    }
+}
+
+/// Datagram as it is sent to the peer, tunneled if we use a relay
+fn wrap_datagram(datagram: Vec<u8>, remote_addr: SocketAddr, relay: Option<SocketAddr>) -> Vec<u8> {
+    match relay {
+        Some(_) => relay::wrap(remote_addr, datagram),
+        None => datagram,
+    }
+}
+
+/// Datagram of the peer, taken out of the tunnel if we use a relay
+fn unwrap_datagram(
+    datagram: &[u8],
+    remote_addr: SocketAddr,
+    relay: Option<SocketAddr>,
+) -> Option<Vec<u8>> {
+    match (demux::demultiplex(datagram), relay) {
+        (Packet::Message(Messages::RelayData(msg)), Some(_)) if msg.peer == remote_addr => {
+            Some(msg.data)
+        }
+        (_, None) => Some(datagram.to_vec()),
+        _ => None,
+    }
+}
+
+/// Outcome of the encryption handshake
+struct Encryption {
+    encryptor: Encryptor,
+    decryptor: Decryptor,
+    /// Hash of the handshake
+    binding: Vec<u8>,
+    /// Last handshake message if it was ours, the peer repeats its previous message until it gets it
+    final_message: Option<Vec<u8>>,
+}
+
+/// Runs the Noise `handshake` with the peer
+async fn encryption_handshake(
+    socket: &mut UdpSocket,
+    mut handshake: Handshake,
+    destination: SocketAddr,
+    remote_addr: SocketAddr,
+    relay: Option<SocketAddr>,
+) -> Result<Encryption, Error> {
+    let target = relay.unwrap_or(remote_addr);
+    let started = Instant::now();
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    //Our last handshake message, repeated until the peer answers
+    let mut last: Option<Vec<u8>> = None;
+    let mut final_message = None;
+    while !handshake.is_finished() {
+        if handshake.is_my_turn() {
+            let message = Messages::NoiseHandshake(NoiseHandshakeMessage {
+                payload: handshake.write()?,
+            });
+            let datagram = wrap_datagram(message.to_datagram(), remote_addr, relay);
+            socket.send_to(&datagram, &destination).await?;
+            if handshake.is_finished() {
+                final_message = Some(datagram.clone());
+            }
+            last = Some(datagram);
+            continue;
+        }
+        if started.elapsed() > HANDSHAKE_TIMEOUT {
+            return Err(Error::timeout(
+                "Peer did not complete the encryption handshake",
+            ));
+        }
+        let received = match tokio::time::timeout(HANDSHAKE_RTO, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(_)) => continue,
+            Err(_) => {
+                if let Some(last) = &last {
+                    socket.send_to(last, &destination).await?;
+                }
+                continue;
             }
+        };
+        let (size, from) = received;
+        if unmap(from) != target {
+            continue;
+        }
+        let message = unwrap_datagram(&buf[..size], remote_addr, relay)
+            .and_then(|datagram| Messages::from_datagram(&datagram));
+        match message {
+            //A repeated message means the peer missed our answer
+            Some(Messages::NoiseHandshake(msg)) => {
+                if let (Err(_), Some(last)) = (handshake.read(&msg.payload), &last) {
+                    socket.send_to(last, &destination).await?;
+                }
+            }
+            //Late connectivity checks, the peer might still wait for its nomination
+            Some(Messages::BindingRequest(msg)) => {
+                let response = Messages::BindingResponse(BindingResponseMessage {
+                    transaction: msg.transaction,
+                });
+                let datagram = wrap_datagram(response.to_datagram(), remote_addr, relay);
+                socket.send_to(&datagram, &destination).await?;
+            }
+            _ => {}
         }
     }
+    let binding = handshake.hash();
+    let (encryptor, decryptor) = handshake.split()?;
+    Ok(Encryption {
+        encryptor,
+        decryptor,
+        binding,
+        final_message,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PairState {
+    Waiting,
+    InProgress {
+        transaction: u64,
+        sent_at: Instant,
+        attempts: u32,
+    },
+    Succeeded,
+    Failed,
+}
+
+/// Path chosen by the connectivity checks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelectedPath {
+    /// Address of the peer
+    pub remote: SocketAddr,
+    /// Relay server our traffic has to be tunneled through, if no direct path worked
+    pub relay: Option<SocketAddr>,
+}
+
+struct CandidatePair {
+    local_priority: u32,
+    remote: Candidate,
+    /// Checks of this pair are tunneled through our relay allocation
+    relayed: bool,
+    state: PairState,
+}
+
+impl CandidatePair {
+    fn priority(&self, controlling: bool) -> u64 {
+        if controlling {
+            candidate::pair_priority(self.local_priority, self.remote.priority)
+        } else {
+            candidate::pair_priority(self.remote.priority, self.local_priority)
+        }
+    }
+
+    /// Either side of the pair is a relay
+    fn uses_relay(&self) -> bool {
+        self.relayed || self.remote.kind == CandidateKind::Relayed
+    }
+}
+
+/// ICE-like connectivity checks: probes every candidate of the peer and
+/// agrees on the best working one with it
+pub struct ConnectivityCheck<'a> {
+    socket: &'a mut UdpSocket,
+    local_addr: SocketAddr,
+    pairs: Vec<CandidatePair>,
+    controlling: bool,
+    tiebreaker: u64,
+    relay: Option<SocketAddr>,
+}
+
+impl<'a> ConnectivityCheck<'a> {
+    /// `relay` is the server of our `Relayed` candidate, if we have one
+    pub fn new(
+        socket: &'a mut UdpSocket,
+        local: &[Candidate],
+        remote: &[Candidate],
+        controlling: bool,
+        relay: Option<SocketAddr>,
+    ) -> Result<Self, Error> {
+        let local_addr = socket.local_addr()?;
+        let mut check = Self {
+            socket,
+            local_addr,
+            pairs: Vec::new(),
+            controlling,
+            tiebreaker: rand::random(),
+            relay,
+        };
+        for remote in remote.iter() {
+            //Every local candidate shares our single socket, the best one of the same family represents them
+            let best_local = |relayed: bool| {
+                local
+                    .iter()
+                    .filter(|local| local.address.is_ipv4() == remote.address.is_ipv4())
+                    .filter(|local| (local.kind == CandidateKind::Relayed) == relayed)
+                    .map(|local| local.priority)
+                    .max()
+            };
+            if let Some(local_priority) = best_local(false) {
+                check.add_pair(local_priority, *remote, false);
+            }
+            if let (Some(local_priority), Some(_)) = (best_local(true), relay) {
+                check.add_pair(local_priority, *remote, true);
+            }
+        }
+        Ok(check)
+    }
+
+    fn add_pair(&mut self, local_priority: u32, remote: Candidate, relayed: bool) {
+        if !relayed && self.local_addr.is_ipv4() && remote.address.is_ipv6() {
+            return;
+        }
+        if let Some(pair) = self
+            .pairs
+            .iter_mut()
+            .find(|pair| pair.remote.address == remote.address && pair.relayed == relayed)
+        {
+            pair.remote.priority = pair.remote.priority.max(remote.priority);
+            return;
+        }
+        self.pairs.push(CandidatePair {
+            local_priority,
+            remote,
+            relayed,
+            state: PairState::Waiting,
+        });
+    }
+
+    fn sort(&mut self) {
+        let controlling = self.controlling;
+        self.pairs
+            .sort_by_key(|pair| std::cmp::Reverse(pair.priority(controlling)));
+    }
+
+    fn path(&self, remote: SocketAddr, relayed: bool) -> SelectedPath {
+        SelectedPath {
+            remote,
+            relay: if relayed { self.relay } else { None },
+        }
+    }
+
+    async fn send_to(
+        &mut self,
+        message: Messages,
+        address: SocketAddr,
+        relayed: bool,
+    ) -> Result<(), Error> {
+        let (bytes, address) = match (relayed, self.relay) {
+            (true, Some(server)) => (relay::wrap(address, message.to_datagram()), server),
+            _ => (message.to_datagram(), address),
+        };
+        let address = map_to_family(address, self.local_addr);
+        self.socket.send_to(&bytes, &address).await?;
+        Ok(())
+    }
+
+    async fn send_request(
+        &mut self,
+        transaction: u64,
+        address: SocketAddr,
+        relayed: bool,
+        nominate: bool,
+    ) -> Result<(), Error> {
+        let msg = BindingRequestMessage {
+            transaction,
+            tiebreaker: self.tiebreaker,
+            controlling: self.controlling,
+            nominate,
+        };
+        //The relay only lets through peers we registered
+        if let (true, Some(server)) = (relayed, self.relay) {
+            let server = map_to_family(server, self.local_addr);
+            self.socket
+                .send_to(&relay::permit(address), &server)
+                .await
+                .ok();
+        }
+        //Unreachable candidates may produce errors, those pairs will simply fail
+        self.send_to(Messages::BindingRequest(msg), address, relayed)
+            .await
+            .ok();
+        Ok(())
+    }
+
+    /// Runs the checks until a candidate pair is selected
+    pub async fn run(mut self) -> Result<SelectedPath, Error> {
+        self.sort();
+        let deadline = Instant::now() + CHECK_TIMEOUT;
+        let mut tick = tokio::time::interval(CHECK_INTERVAL);
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        let mut first_success: Option<Instant> = None;
+        //Transaction of the nomination, its target, whether it is relayed and when it was sent
+        let mut nomination: Option<(u64, SocketAddr, bool, Instant)> = None;
+
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {
+                    if Instant::now() > deadline {
+                        return Err(Error::timeout("No candidate pair works"));
+                    }
+                    self.step(&mut nomination, first_success).await?;
+                }
+                received = self.socket.recv_from(&mut buf) => {
+                    let (size, from) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    let from = unmap(from);
+                    //Datagrams from our relay server were sent to our relayed address
+                    let (message, from, relayed) = match Messages::from_datagram(&buf[..size]) {
+                        Some(Messages::RelayData(msg)) if Some(from) == self.relay => {
+                            match Messages::from_datagram(&msg.data) {
+                                Some(message) => (message, msg.peer, true),
+                                None => continue,
+                            }
+                        }
+                        Some(message) => (message, from, false),
+                        None => continue,
+                    };
+                    match message {
+                        Messages::BindingRequest(msg) => {
+                            if let Some(path) = self.on_request(&msg, from, relayed).await? {
+                                return Ok(path);
+                            }
+                        }
+                        Messages::BindingResponse(msg) => {
+                            if let Some((transaction, address, via_relay, _)) = nomination {
+                                if transaction == msg.transaction && address == from && via_relay == relayed {
+                                    return Ok(self.path(address, relayed));
+                                }
+                            }
+                            for pair in self.pairs.iter_mut() {
+                                if let PairState::InProgress { transaction, .. } = pair.state {
+                                    if transaction == msg.transaction
+                                        && pair.remote.address == from
+                                        && pair.relayed == relayed
+                                    {
+                                        pair.state = PairState::Succeeded;
+                                        first_success.get_or_insert_with(Instant::now);
+                                    }
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the next check, retransmits lost ones and nominates a pair when it is time
+    async fn step(
+        &mut self,
+        nomination: &mut Option<(u64, SocketAddr, bool, Instant)>,
+        first_success: Option<Instant>,
+    ) -> Result<(), Error> {
+        if let Some((transaction, address, relayed, sent_at)) = *nomination {
+            if sent_at.elapsed() > CHECK_RTO {
+                self.send_request(transaction, address, relayed, true)
+                    .await?;
+                *nomination = Some((transaction, address, relayed, Instant::now()));
+            }
+            return Ok(());
+        }
+
+        //Retransmit checks which were not answered in time
+        let mut retransmit = None;
+        for pair in self.pairs.iter_mut() {
+            if let PairState::InProgress {
+                transaction,
+                sent_at,
+                attempts,
+            } = pair.state
+            {
+                if sent_at.elapsed() > CHECK_RTO {
+                    if attempts >= MAX_CHECK_ATTEMPTS {
+                        pair.state = PairState::Failed;
+                    } else if retransmit.is_none() {
+                        pair.state = PairState::InProgress {
+                            transaction,
+                            sent_at: Instant::now(),
+                            attempts: attempts + 1,
+                        };
+                        retransmit = Some((transaction, pair.remote.address, pair.relayed));
+                    }
+                }
+            }
+        }
+        if let Some((transaction, address, relayed)) = retransmit {
+            self.send_request(transaction, address, relayed, false)
+                .await?;
+        } else if let Some(pair) = self
+            .pairs
+            .iter_mut()
+            .find(|pair| pair.state == PairState::Waiting)
+        {
+            let transaction = rand::random();
+            pair.state = PairState::InProgress {
+                transaction,
+                sent_at: Instant::now(),
+                attempts: 1,
+            };
+            let (address, relayed) = (pair.remote.address, pair.relayed);
+            self.send_request(transaction, address, relayed, false)
+                .await?;
+        }
+
+        if !self.controlling {
+            return Ok(());
+        }
+        //Nominate the best working pair, once no better pair can succeed or we waited long enough.
+        //Relays are a last resort, every direct pair has to fail before one is used
+        let best = self
+            .pairs
+            .iter()
+            .position(|pair| pair.state == PairState::Succeeded);
+        if let Some(best) = best {
+            let better_pending = self.pairs[..best]
+                .iter()
+                .any(|pair| pair.state != PairState::Failed);
+            let waited = first_success.is_some_and(|at| at.elapsed() > NOMINATION_DELAY);
+            if !better_pending || (waited && !self.pairs[best].uses_relay()) {
+                let transaction = rand::random();
+                let (address, relayed) =
+                    (self.pairs[best].remote.address, self.pairs[best].relayed);
+                self.send_request(transaction, address, relayed, true)
+                    .await?;
+                *nomination = Some((transaction, address, relayed, Instant::now()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a check of the peer. Returns the selected path once the peer nominated it
+    async fn on_request(
+        &mut self,
+        msg: &BindingRequestMessage,
+        from: SocketAddr,
+        relayed: bool,
+    ) -> Result<Option<SelectedPath>, Error> {
+        //Role conflict, the larger tiebreaker is controlling (RFC 8445 section 7.3.1.1)
+        if msg.controlling == self.controlling {
+            self.controlling = self.tiebreaker > msg.tiebreaker;
+            self.sort();
+        }
+        let response = BindingResponseMessage {
+            transaction: msg.transaction,
+        };
+        self.send_to(Messages::BindingResponse(response), from, relayed)
+            .await
+            .ok();
+        if !self
+            .pairs
+            .iter()
+            .any(|pair| pair.remote.address == from && pair.relayed == relayed)
+        {
+            //The peer is behind a NAT we did not know about, check it right away
+            let priority = candidate::priority(CandidateKind::PeerReflexive, 0);
+            let local_priority = self
+                .pairs
+                .iter()
+                .find(|pair| pair.relayed == relayed)
+                .map_or(0, |pair| pair.local_priority);
+            let remote = Candidate {
+                kind: CandidateKind::PeerReflexive,
+                address: from,
+                priority,
+            };
+            self.add_pair(local_priority, remote, relayed);
+            self.sort();
+        }
+        if msg.nominate && !self.controlling {
+            return Ok(Some(self.path(from, relayed)));
+        }
+        Ok(None)
+    }
+}
+
+/// Binds a UDP socket. Sockets bound to the unspecified IPv6 address accept IPv4 traffic as well
+pub fn bind_dual_stack(addr: SocketAddr) -> Result<std::net::UdpSocket, Error> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into_udp_socket())
+}
+
+/// Converts `addr` so it can be used with a socket bound to `local`.
+/// IPv4 addresses become IPv4-mapped IPv6 addresses on IPv6 sockets
+pub fn map_to_family(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr.ip(), local) {
+        (IpAddr::V4(ip), SocketAddr::V6(_)) => {
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Reverses `map_to_family`
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// Makes the OS refuse datagrams larger than the path MTU instead of fragmenting them,
+/// otherwise path MTU probes would always succeed
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+    let (level, option) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+    };
+    //IPV6_PMTUDISC_PROBE has the same value
+    let value: libc::c_int = libc::IP_PMTUDISC_PROBE;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &UdpSocket) -> Result<(), Error> {
+    Ok(())
 }
@@ -1,50 +1,388 @@
-use crate::error::Error;
+use crate::{
+    candidate::{Candidate, CandidateKind},
+    error::Error,
+    integrity::crc32,
+    pake::SECRET_SIZE,
+    words::{self, WORDS},
+};
 use std::{
     fmt::Display,
     io::Write,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+
+const TAG_IPV4: u8 = 4;
+const TAG_IPV6: u8 = 6;
+/// Codes of older versions carrying a list of candidates
+const TAG_CANDIDATES: u8 = 1;
+/// Codes with a format version, a creation time and a session nonce
+const TAG_SESSION: u8 = 2;
+/// Version of the codes after `TAG_SESSION`, raised whenever peers of
+/// different versions can't talk to each other. Version 2 encrypts the connection,
+/// version 3 authenticates it with a secret from the code, version 4 checksums the data,
+/// version 5 announces the files of a transfer in a manifest,
+/// version 6 lets the receiver skip files
+pub const CODE_VERSION: u8 = 6;
+pub const NONCE_SIZE: usize = 8;
+/// Codes are refused after this long, so stale codes from chat histories can't connect
+pub const CODE_LIFETIME: Duration = Duration::from_secs(3600);
+/// Tolerated difference between the clocks of the peers
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(600);
+/// Word codes end with a CRC-32 of the other words
+const CHECKSUM_SIZE: usize = 4;
+
+/// One run of the program. Both peers verify the nonce in their first message,
+/// which proves that the code is the current one of the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// Seconds since the UNIX epoch
+    pub created: u32,
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            created: now() as u32,
+            nonce: rand::random(),
+        }
+    }
+
+    /// Whether codes of this session are refused already
+    pub fn is_expired(&self) -> bool {
+        now() > self.created as u64 + CODE_LIFETIME.as_secs()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[derive(Clone)]
 pub struct AddressInfo {
-    pub address: SocketAddrV4,
+    pub session: Session,
+    /// Password for the authentication, only in codes which are given to the partner directly
+    pub secret: Option<[u8; SECRET_SIZE]>,
+    /// Candidates in order of preference
+    pub candidates: Vec<Candidate>,
 }
 
 impl AddressInfo {
-    pub fn new(socket: SocketAddrV4) -> Self {
-        Self { address: socket }
+    /// Candidates of a new session
+    pub fn new(candidates: Vec<Candidate>) -> Self {
+        Self::with_session(candidates, Session::new())
+    }
+
+    pub fn with_session(candidates: Vec<Candidate>, session: Session) -> Self {
+        Self {
+            session,
+            secret: None,
+            candidates,
+        }
+    }
+
+    /// Same code carrying `secret`
+    pub fn with_secret(mut self, secret: [u8; SECRET_SIZE]) -> Self {
+        self.secret = Some(secret);
+        self
     }
 
     pub fn new_from_address(addr: &str, port: u16) -> Self {
-        Self::new(SocketAddrV4::new(addr.parse().unwrap(), port))
+        let address = SocketAddr::new(addr.parse().unwrap(), port);
+        Self::new(vec![Candidate::new(
+            CandidateKind::ServerReflexive,
+            address,
+            0,
+        )])
+    }
+
+    pub(crate) fn from_bytes(b: Vec<u8>) -> Result<Self, Error> {
+        match (b.len(), b.first()) {
+            (_, Some(&TAG_SESSION)) => Self::session_from_bytes(&b[1..]),
+            //Bare addresses, tagged addresses and candidate lists without a session
+            (6, _) | (7, Some(&TAG_IPV4)) | (19, Some(&TAG_IPV6)) | (_, Some(&TAG_CANDIDATES)) => {
+                Err(Error::new(
+                    "The code is from an older version, which can't be verified",
+                ))
+            }
+            _ => Err(Error::new("Invalid data")),
+        }
     }
 
-    fn from_bytes(b: Vec<u8>) -> Result<Self, Error> {
-        if b.len() < 6 {
+    fn session_from_bytes(b: &[u8]) -> Result<Self, Error> {
+        if b.len() < 1 + 4 + NONCE_SIZE + 1 {
             return Err(Error::new("Invalid data"));
         }
-        let addr = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
-        let port = u16::from_le_bytes([b[4], b[5]]);
-        Ok(AddressInfo {
-            address: SocketAddrV4::new(addr, port),
+        match b[0] {
+            CODE_VERSION => {}
+            version if version > CODE_VERSION => {
+                return Err(Error::new("The code is from a newer version"))
+            }
+            _ => return Err(Error::new("The code is from an older version")),
+        }
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&b[5..5 + NONCE_SIZE]);
+        let session = Session {
+            created: u32::from_le_bytes([b[1], b[2], b[3], b[4]]),
+            nonce,
+        };
+        if session.is_expired() {
+            return Err(Error::new(
+                "The code expired, ask your partner for a new one",
+            ));
+        }
+        if session.created as u64 > now() + MAX_CLOCK_SKEW.as_secs() {
+            return Err(Error::new(
+                "The code was created in the future, check the clocks",
+            ));
+        }
+        let rest = &b[5 + NONCE_SIZE..];
+        let secret = match rest[0] as usize {
+            0 => None,
+            SECRET_SIZE if rest.len() > SECRET_SIZE => {
+                let mut secret = [0; SECRET_SIZE];
+                secret.copy_from_slice(&rest[1..1 + SECRET_SIZE]);
+                Some(secret)
+            }
+            _ => return Err(Error::new("Invalid data")),
+        };
+        let size = secret.map_or(0, |_| SECRET_SIZE);
+        let candidates = Self::candidates_from_bytes(&rest[1 + size..])?;
+        Ok(Self {
+            session,
+            secret,
+            candidates,
         })
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write(&self.address.ip().octets()).unwrap();
-        buf.write(&self.address.port().to_le_bytes()).unwrap();
+    fn candidates_from_bytes(b: &[u8]) -> Result<Vec<Candidate>, Error> {
+        let mut candidates = Vec::new();
+        let mut rest = b;
+        while !rest.is_empty() {
+            let kind = CandidateKind::from_byte(rest[0]).ok_or(Error::new("Invalid data"))?;
+            let (address, size) =
+                read_tagged_address(&rest[1..]).ok_or(Error::new("Invalid data"))?;
+            rest = &rest[1 + size..];
+            if kind != CandidateKind::Predicted {
+                candidates.push(Candidate::new(kind, address, candidates.len()));
+                continue;
+            }
+            //Predicted ports are sent as a range: number of ports and the step between them
+            if rest.len() < 3 {
+                return Err(Error::new("Invalid data"));
+            }
+            let (count, step) = (rest[0], i16::from_le_bytes([rest[1], rest[2]]));
+            rest = &rest[3..];
+            for i in 0..count as i32 {
+                let port = address.port() as i32 + step as i32 * i;
+                if !(1..=65535).contains(&port) {
+                    return Err(Error::new("Invalid data"));
+                }
+                let address = SocketAddr::new(address.ip(), port as u16);
+                candidates.push(Candidate::new(kind, address, candidates.len()));
+            }
+        }
+        if candidates.is_empty() {
+            return Err(Error::new("No candidates"));
+        }
+        Ok(candidates)
+    }
+
+    /// The code as words with a checksum, easier to read out and type than base58
+    pub fn to_words(&self) -> String {
+        let mut bytes = self.to_bytes();
+        bytes.extend(crc32(&bytes).to_be_bytes().iter());
+        let words: Vec<&str> = bytes.iter().map(|b| WORDS[*b as usize]).collect();
+        words.join(" ")
+    }
+
+    fn from_words(s: &str) -> Result<Self, Error> {
+        let typed: Vec<String> = s
+            .split(|c: char| c.is_whitespace() || c == '-' || c == ',')
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+        let mut bytes = Vec::new();
+        for (i, word) in typed.iter().enumerate() {
+            match words::index(word) {
+                Some(b) => bytes.push(b),
+                None => {
+                    let message = match words::closest(word) {
+                        Some(close) => format!(
+                            "Word {} \"{}\" is not in the word list, did you mean \"{}\"?",
+                            i + 1,
+                            word,
+                            close
+                        ),
+                        None => format!("Word {} \"{}\" is not in the word list", i + 1, word),
+                    };
+                    return Err(Error::new(&message));
+                }
+            }
+        }
+        if bytes.len() <= CHECKSUM_SIZE {
+            return Err(Error::new("The code is too short"));
+        }
+        if !checksum_matches(&bytes) {
+            return Err(Error::new(&diagnose(&typed, &bytes)));
+        }
+        Self::from_bytes(bytes[..bytes.len() - CHECKSUM_SIZE].to_vec())
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![TAG_SESSION, CODE_VERSION];
+        buf.write_all(&self.session.created.to_le_bytes()).unwrap();
+        buf.write_all(&self.session.nonce).unwrap();
+        match &self.secret {
+            Some(secret) => {
+                buf.write_all(&[SECRET_SIZE as u8]).unwrap();
+                buf.write_all(secret).unwrap();
+            }
+            None => buf.write_all(&[0]).unwrap(),
+        }
+        let mut rest = &self.candidates[..];
+        while let Some(candidate) = rest.first() {
+            buf.write_all(&[candidate.kind.to_byte()]).unwrap();
+            write_address(&mut buf, candidate.address);
+            if candidate.kind != CandidateKind::Predicted {
+                rest = &rest[1..];
+                continue;
+            }
+            let (count, step) = predicted_range(rest);
+            buf.write_all(&[count as u8]).unwrap();
+            buf.write_all(&step.to_le_bytes()).unwrap();
+            rest = &rest[count..];
+        }
         buf
     }
 }
 
+/// Length of the run of predicted candidates at the start of `candidates`, and the step between their ports
+fn predicted_range(candidates: &[Candidate]) -> (usize, i16) {
+    let first = candidates[0].address;
+    let step = match candidates.get(1) {
+        Some(next) if next.kind == CandidateKind::Predicted && next.address.ip() == first.ip() => {
+            next.address.port() as i32 - first.port() as i32
+        }
+        _ => return (1, 0),
+    };
+    let step = match step {
+        step if step != 0 && step.abs() <= i16::MAX as i32 => step as i16,
+        _ => return (1, 0),
+    };
+    let count = candidates
+        .iter()
+        .take(u8::MAX as usize)
+        .enumerate()
+        .take_while(|(i, candidate)| {
+            candidate.kind == CandidateKind::Predicted
+                && candidate.address.ip() == first.ip()
+                && candidate.address.port() as i32 == first.port() as i32 + step as i32 * *i as i32
+        })
+        .count();
+    (count, step)
+}
+
+fn checksum_matches(bytes: &[u8]) -> bool {
+    let (data, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    crc32(data).to_be_bytes() == checksum
+}
+
+/// Explains a checksum mismatch. A mistyped word usually is another word of the list,
+/// so every single replacement and swap is tried to find the one which fixes the checksum
+fn diagnose(typed: &[String], bytes: &[u8]) -> String {
+    let mut fixes = Vec::new();
+    for i in 0..bytes.len() {
+        let mut fixed = bytes.to_vec();
+        for b in 0..=255u8 {
+            fixed[i] = b;
+            if b != bytes[i] && checksum_matches(&fixed) {
+                fixes.push((i, b));
+            }
+        }
+    }
+    //The intended word is most likely the one which looks like the typed one
+    let fix = fixes
+        .into_iter()
+        .min_by_key(|&(i, b)| words::distance(&typed[i], WORDS[b as usize]));
+    if let Some((i, b)) = fix {
+        return format!(
+            "Word {} \"{}\" is probably mistyped, did you mean \"{}\"?",
+            i + 1,
+            typed[i],
+            WORDS[b as usize]
+        );
+    }
+    for i in 1..bytes.len() {
+        let mut swapped = bytes.to_vec();
+        swapped.swap(i - 1, i);
+        if checksum_matches(&swapped) {
+            return format!("Words {} and {} are probably swapped", i, i + 1);
+        }
+    }
+    "The code is mistyped, a word is probably missing or extra".into()
+}
+
+fn read_address(tag: u8, b: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match (tag, b.len()) {
+        (TAG_IPV4, 6) => (IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])), &b[4..6]),
+        (TAG_IPV6, 18) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&b[0..16]);
+            (IpAddr::V6(Ipv6Addr::from(octets)), &b[16..18])
+        }
+        _ => return None,
+    };
+    let port = u16::from_le_bytes([port[0], port[1]]);
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Reads an address written by `write_address`. Returns it with the number of bytes it took
+pub(crate) fn read_tagged_address(b: &[u8]) -> Option<(SocketAddr, usize)> {
+    let size = match *b.first()? {
+        TAG_IPV4 => 4 + 2,
+        TAG_IPV6 => 16 + 2,
+        _ => return None,
+    };
+    if b.len() < 1 + size {
+        return None;
+    }
+    Some((read_address(b[0], &b[1..1 + size])?, 1 + size))
+}
+
+pub(crate) fn write_address(buf: &mut Vec<u8>, address: SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            buf.write_all(&[TAG_IPV4]).unwrap();
+            buf.write_all(&ip.octets()).unwrap();
+        }
+        IpAddr::V6(ip) => {
+            buf.write_all(&[TAG_IPV6]).unwrap();
+            buf.write_all(&ip.octets()).unwrap();
+        }
+    }
+    buf.write_all(&address.port().to_le_bytes()).unwrap();
+}
+
 impl FromStr for AddressInfo {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let byte_data = bs58::decode(s).into_vec()?;
-        if byte_data.len() != 6 {
-            return Err(Error::new("Invalid size"));
+        //Base58 codes are a single word of mixed case and digits
+        if s.trim().contains(char::is_whitespace) || s.contains('-') {
+            return Self::from_words(s);
         }
+        let byte_data = bs58::decode(s.trim()).into_vec()?;
         AddressInfo::from_bytes(byte_data)
     }
 }
@@ -0,0 +1,114 @@
+use crate::error::Error;
+use curve25519_dalek::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::IsIdentity,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+
+/// Separates our hashes from those of other protocols
+const DOMAIN: &[u8] = b"p2p CPace ristretto255";
+pub const SHARE_SIZE: usize = 32;
+pub const KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 32;
+/// Size of the secret in codes which are given to the partner directly
+pub const SECRET_SIZE: usize = 4;
+
+/// New secret for the codes of a session
+pub fn new_secret() -> [u8; SECRET_SIZE] {
+    rand::random()
+}
+
+/// SHA-512 of `parts`, each prefixed with its length so they can't be shifted into each other
+fn hash(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update((part.len() as u32).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// One side of a CPace exchange. Both sides derive a generator from the password,
+/// only peers with the same password agree on a key, and every guess costs an exchange
+pub struct Pake {
+    scalar: Scalar,
+    share: [u8; SHARE_SIZE],
+    channel: Vec<u8>,
+    initiator: bool,
+}
+
+/// Outcome of an exchange
+pub struct PakeKeys {
+    pub key: [u8; KEY_SIZE],
+    /// Proves to the peer that we got the same key
+    pub confirmation: [u8; TAG_SIZE],
+    expected: [u8; TAG_SIZE],
+}
+
+impl PakeKeys {
+    /// Whether the confirmation of the peer proves that it knows the password
+    pub fn verify(&self, confirmation: &[u8; TAG_SIZE]) -> bool {
+        self.expected.ct_eq(confirmation).into()
+    }
+}
+
+impl Pake {
+    /// `channel` binds the exchange to the encrypted connection it runs on
+    pub fn new(password: &[u8], channel: &[u8], initiator: bool) -> Self {
+        let generator = RistrettoPoint::from_uniform_bytes(&hash(&[DOMAIN, password, channel]));
+        let mut random = [0; 64];
+        rand::thread_rng().fill_bytes(&mut random);
+        let scalar = Scalar::from_bytes_mod_order_wide(&random);
+        let share = (generator * scalar).compress().to_bytes();
+        Self {
+            scalar,
+            share,
+            channel: channel.to_vec(),
+            initiator,
+        }
+    }
+
+    /// Our share, sent to the peer
+    pub fn share(&self) -> [u8; SHARE_SIZE] {
+        self.share
+    }
+
+    /// Derives the key from the share of the peer
+    pub fn finish(self, theirs: &[u8; SHARE_SIZE]) -> Result<PakeKeys, Error> {
+        let point = CompressedRistretto(*theirs)
+            .decompress()
+            .ok_or(Error::new("Invalid PAKE share"))?;
+        let shared = point * self.scalar;
+        if shared.is_identity() {
+            return Err(Error::new("Invalid PAKE share"));
+        }
+        let (first, second) = if self.initiator {
+            (&self.share, theirs)
+        } else {
+            (theirs, &self.share)
+        };
+        let shared = shared.compress();
+        let secret = hash(&[DOMAIN, &self.channel, shared.as_bytes(), first, second]);
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&secret[..KEY_SIZE]);
+        let tag = |role: &[u8]| {
+            let mut tag = [0; TAG_SIZE];
+            tag.copy_from_slice(&hash(&[DOMAIN, role, &secret])[..TAG_SIZE]);
+            tag
+        };
+        let (initiator, responder) = (tag(b"initiator"), tag(b"responder"));
+        let (confirmation, expected) = if self.initiator {
+            (initiator, responder)
+        } else {
+            (responder, initiator)
+        };
+        Ok(PakeKeys {
+            key,
+            confirmation,
+            expected,
+        })
+    }
+}
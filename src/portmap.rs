@@ -0,0 +1,397 @@
+use crate::error::Error;
+use igd::{aio::Gateway, PortMappingProtocol, SearchOptions};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// NAT-PMP and PCP servers listen on the same port of the gateway
+const PCP_PORT: u16 = 5351;
+const PCP_VERSION: u8 = 2;
+const NATPMP_VERSION: u8 = 0;
+/// Both protocols use opcode 1 to map UDP ports
+const OPCODE_MAP: u8 = 1;
+const OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const RESPONSE_BIT: u8 = 0x80;
+const RESULT_SUCCESS: u8 = 0;
+/// Same code in NAT-PMP and PCP
+const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+const PROTOCOL_UDP: u8 = 17;
+/// Largest PCP message (RFC 6887 section 7)
+const MAX_PCP_SIZE: usize = 1100;
+/// Initial retransmission timeout, doubled after every attempt (RFC 6886 section 3.1)
+const REQUEST_RTO: Duration = Duration::from_millis(250);
+const MAX_REQUEST_ATTEMPTS: u32 = 3;
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+/// Gateways may grant very short lifetimes, or none at all
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+const DESCRIPTION: &str = "p2p file transfer";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+enum Control {
+    Pcp {
+        server: SocketAddr,
+        /// Identifies our mapping on the server
+        nonce: [u8; 12],
+    },
+    NatPmp {
+        server: SocketAddr,
+    },
+    Upnp(Gateway),
+}
+
+/// UDP port forwarded to us by the gateway
+pub struct PortMapping {
+    /// Address peers can reach us on
+    pub external: SocketAddr,
+    local: SocketAddrV4,
+    lifetime: Duration,
+    control: Control,
+}
+
+/// Finds the gateway and asks it to forward a port, with PCP, NAT-PMP or UPnP-IGD
+pub struct PortMapper {
+    /// NAT-PMP and PCP server, usually the default gateway
+    pcp_server: Option<SocketAddr>,
+    /// Where UPnP gateways are searched
+    ssdp_address: SocketAddr,
+    lifetime: Duration,
+}
+
+impl Default for PortMapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortMapper {
+    /// Uses the default gateway of the system
+    pub fn new() -> Self {
+        Self {
+            pcp_server: default_gateway().map(|ip| SocketAddr::new(IpAddr::V4(ip), PCP_PORT)),
+            ssdp_address: SSDP_ADDRESS.parse().unwrap(),
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Uses the given gateway, like a fake one in tests
+    pub fn with_gateway(pcp_server: SocketAddr, ssdp_address: SocketAddr) -> Self {
+        Self {
+            pcp_server: Some(pcp_server),
+            ssdp_address,
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Requested lifetime of mappings, they have to be renewed before it runs out
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    /// Asks the gateway to forward a UDP port to `local`, the port is kept if possible.
+    /// PCP is tried first, NAT-PMP if the gateway only speaks that, UPnP-IGD if neither answers
+    pub async fn map(&self, local: SocketAddrV4) -> Result<PortMapping, Error> {
+        let server = match self.pcp_server {
+            Some(server) => server,
+            None => return self.map_upnp(local).await,
+        };
+        let nonce = rand::random();
+        let lifetime = self.lifetime.as_secs() as u32;
+        let request = pcp_request(local, local.port(), lifetime, &nonce);
+        let answer = match exchange(server, &request).await {
+            Ok(answer) => answer,
+            Err(_) => return self.map_upnp(local).await,
+        };
+        let mut mapping = PortMapping {
+            external: SocketAddr::new(IpAddr::V4(*local.ip()), local.port()),
+            local,
+            lifetime: self.lifetime,
+            control: Control::Pcp { server, nonce },
+        };
+        if speaks_pcp(&answer) {
+            let (external, lifetime) = parse_pcp_response(&answer, &nonce)?;
+            mapping.external = external;
+            mapping.lifetime = Duration::from_secs(lifetime as u64);
+        } else {
+            mapping.control = Control::NatPmp { server };
+            mapping.renew().await?;
+        }
+        Ok(mapping)
+    }
+
+    async fn map_upnp(&self, local: SocketAddrV4) -> Result<PortMapping, Error> {
+        let options = SearchOptions {
+            bind_addr: SocketAddr::new(IpAddr::V4(*local.ip()), 0),
+            broadcast_address: self.ssdp_address,
+            timeout: Some(UPNP_SEARCH_TIMEOUT),
+        };
+        let gateway = igd::aio::search_gateway(options).await?;
+        let lease = self.lifetime.as_secs() as u32;
+        let protocol = PortMappingProtocol::UDP;
+        //Keeping the local port spares the peer a guess, if it sees only one of our addresses
+        let port = match gateway
+            .add_port(protocol, local.port(), local, lease, DESCRIPTION)
+            .await
+        {
+            Ok(_) => local.port(),
+            Err(_) => {
+                gateway
+                    .add_any_port(protocol, local, lease, DESCRIPTION)
+                    .await?
+            }
+        };
+        let ip = gateway.get_external_ip().await?;
+        Ok(PortMapping {
+            external: SocketAddr::new(IpAddr::V4(ip), port),
+            local,
+            lifetime: self.lifetime,
+            control: Control::Upnp(gateway),
+        })
+    }
+}
+
+impl PortMapping {
+    pub fn method(&self) -> Method {
+        match self.control {
+            Control::Pcp { .. } => Method::Pcp,
+            Control::NatPmp { .. } => Method::NatPmp,
+            Control::Upnp(_) => Method::Upnp,
+        }
+    }
+
+    /// Lifetime granted by the gateway
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Requests the mapping again, which restarts its lifetime
+    pub async fn renew(&mut self) -> Result<(), Error> {
+        let lifetime = self.lifetime.as_secs() as u32;
+        let (external, lifetime) = match &self.control {
+            Control::Pcp { server, nonce } => {
+                let request = pcp_request(self.local, self.external.port(), lifetime, nonce);
+                parse_pcp_response(&exchange(*server, &request).await?, nonce)?
+            }
+            Control::NatPmp { server } => {
+                let request = natpmp_request(self.local.port(), self.external.port(), lifetime);
+                let (port, lifetime) = parse_natpmp_response(&exchange(*server, &request).await?)?;
+                let request = [NATPMP_VERSION, OPCODE_EXTERNAL_ADDRESS];
+                let ip = parse_natpmp_address(&exchange(*server, &request).await?)?;
+                (SocketAddr::new(IpAddr::V4(ip), port), lifetime)
+            }
+            Control::Upnp(gateway) => {
+                let protocol = PortMappingProtocol::UDP;
+                let port = self.external.port();
+                gateway
+                    .add_port(protocol, port, self.local, lifetime, DESCRIPTION)
+                    .await?;
+                (self.external, lifetime)
+            }
+        };
+        self.external = external;
+        self.lifetime = Duration::from_secs(lifetime as u64);
+        Ok(())
+    }
+
+    /// Asks the gateway to stop forwarding the port
+    pub async fn remove(self) -> Result<(), Error> {
+        match &self.control {
+            Control::Pcp { server, nonce } => {
+                //A lifetime of zero deletes the mapping
+                let request = pcp_request(self.local, self.external.port(), 0, nonce);
+                parse_pcp_response(&exchange(*server, &request).await?, nonce)?;
+            }
+            Control::NatPmp { server } => {
+                let request = natpmp_request(self.local.port(), 0, 0);
+                parse_natpmp_response(&exchange(*server, &request).await?)?;
+            }
+            Control::Upnp(gateway) => {
+                let protocol = PortMappingProtocol::UDP;
+                gateway.remove_port(protocol, self.external.port()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renews the mapping in the background, until the lease is released
+    pub fn keep_alive(mut self) -> Lease {
+        let external = self.external;
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::delay_for((self.lifetime / 2).max(MIN_RENEW_INTERVAL)) => {
+                        self.renew().await.ok();
+                    }
+                    _ = &mut stopped => break,
+                }
+            }
+            self
+        });
+        Lease {
+            external,
+            stop,
+            task,
+        }
+    }
+}
+
+/// Port mapping which is renewed until it is released
+pub struct Lease {
+    pub external: SocketAddr,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<PortMapping>,
+}
+
+impl Lease {
+    /// Stops renewing the mapping and removes it from the gateway
+    pub async fn release(self) -> Result<(), Error> {
+        self.stop.send(()).ok();
+        let mapping = self
+            .task
+            .await
+            .map_err(|_| Error::new("Port mapping task failed"))?;
+        mapping.remove().await
+    }
+}
+
+/// Sends a NAT-PMP or PCP request until the server answers
+async fn exchange(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    let mut buf = vec![0; MAX_PCP_SIZE];
+    let mut rto = REQUEST_RTO;
+    for _ in 0..MAX_REQUEST_ATTEMPTS {
+        socket.send_to(request, &server).await?;
+        let answer = tokio::time::timeout(rto, async {
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await?;
+                if from == server {
+                    return Ok::<Vec<u8>, Error>(buf[..size].to_vec());
+                }
+            }
+        })
+        .await;
+        if let Ok(result) = answer {
+            return result;
+        }
+        rto *= 2;
+    }
+    Err(Error::timeout("Gateway did not answer"))
+}
+
+/// PCP MAP request (RFC 6887 section 11.1)
+fn pcp_request(
+    local: SocketAddrV4,
+    external_port: u16,
+    lifetime: u32,
+    nonce: &[u8; 12],
+) -> Vec<u8> {
+    let mut buf = vec![PCP_VERSION, OPCODE_MAP, 0, 0];
+    buf.extend(lifetime.to_be_bytes().iter());
+    buf.extend(local.ip().to_ipv6_mapped().octets().iter());
+    buf.extend(nonce.iter());
+    buf.extend([PROTOCOL_UDP, 0, 0, 0].iter());
+    buf.extend(local.port().to_be_bytes().iter());
+    buf.extend(external_port.to_be_bytes().iter());
+    //Any external address will do
+    buf.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets().iter());
+    buf
+}
+
+/// NAT-PMP servers answer PCP requests with their own version
+fn speaks_pcp(b: &[u8]) -> bool {
+    b.len() >= 4 && b[0] == PCP_VERSION && b[3] != RESULT_UNSUPPORTED_VERSION
+}
+
+/// Returns the mapped address and the granted lifetime
+fn parse_pcp_response(b: &[u8], nonce: &[u8; 12]) -> Result<(SocketAddr, u32), Error> {
+    if b.len() < 60 || b[1] != OPCODE_MAP | RESPONSE_BIT || &b[24..36] != nonce {
+        return Err(Error::new("Invalid PCP response"));
+    }
+    if b[3] != RESULT_SUCCESS {
+        return Err(Error::new(&format!(
+            "Gateway refused the mapping: {}",
+            b[3]
+        )));
+    }
+    let lifetime = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+    let port = u16::from_be_bytes([b[42], b[43]]);
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&b[44..60]);
+    let ip = Ipv6Addr::from(octets);
+    let ip = match ip.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    };
+    Ok((SocketAddr::new(ip, port), lifetime))
+}
+
+/// NAT-PMP mapping request (RFC 6886 section 3.3)
+fn natpmp_request(local_port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+    let mut buf = vec![NATPMP_VERSION, OPCODE_MAP, 0, 0];
+    buf.extend(local_port.to_be_bytes().iter());
+    buf.extend(external_port.to_be_bytes().iter());
+    buf.extend(lifetime.to_be_bytes().iter());
+    buf
+}
+
+fn natpmp_result(b: &[u8], opcode: u8, size: usize) -> Result<(), Error> {
+    if b.len() < 4 || b[0] != NATPMP_VERSION || b[1] != opcode | RESPONSE_BIT {
+        return Err(Error::new("Invalid NAT-PMP response"));
+    }
+    let result = u16::from_be_bytes([b[2], b[3]]);
+    if result != 0 {
+        return Err(Error::new(&format!(
+            "Gateway refused the mapping: {}",
+            result
+        )));
+    }
+    if b.len() < size {
+        return Err(Error::new("Invalid NAT-PMP response"));
+    }
+    Ok(())
+}
+
+/// Returns the mapped port and the granted lifetime
+fn parse_natpmp_response(b: &[u8]) -> Result<(u16, u32), Error> {
+    natpmp_result(b, OPCODE_MAP, 16)?;
+    let port = u16::from_be_bytes([b[10], b[11]]);
+    let lifetime = u32::from_be_bytes([b[12], b[13], b[14], b[15]]);
+    Ok((port, lifetime))
+}
+
+fn parse_natpmp_address(b: &[u8]) -> Result<Ipv4Addr, Error> {
+    natpmp_result(b, OPCODE_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(b[8], b[9], b[10], b[11]))
+}
+
+/// Gateway of the default route
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        //Addresses are written in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
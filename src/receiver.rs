@@ -1,126 +1,559 @@
 use std::{
-    fs::{self, File},
-    io::Write,
-    iter::successors,
-    path::{self, Path},
+    collections::{BTreeMap, HashSet},
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
 };
 
-use bytes::BytesMut;
-use futures::{FutureExt, Stream};
-use tokio::{pin, sync::broadcast::Receiver};
+use tokio::{sync::broadcast::Receiver, time::Duration};
 
 use crate::{
     bitfield::Bitfield,
-    message::FileTransferAcceptMessage,
-    message::{Messages, PartBeginMessage, TransferIncompleteMessage, TransferSuccessfulMessage},
-    networking::NetworkHandler,
+    destination::{self, Destination},
+    error::Error,
+    integrity::{self, FileHash},
+    manifest::{EntryKind, Manifest, ManifestEntry},
+    merkle::{self, BlockHashes, Hash, BLOCK_SIZE, MAX_FILE_SIZE},
+    message::{
+        printable, Messages, RefetchMessage, TransferFailedMessage, TransferSuccessfulMessage,
+    },
+    message::{
+        FileTransferAcceptMessage, FileTransferDeclineMessage, FileTransferRequestMessage,
+        ManifestMessage, SelectiveAckMessage, PROTOCOL_VERSION,
+    },
+    networking::{Introduction, NetworkHandler},
+    resume::TransferState,
 };
 
-pub async fn begin(handler: NetworkHandler) {
-    let dir = Path::new("downloads/");
-    fs::create_dir_all(dir).unwrap();
-    println!("Ready for transmission");
+/// An acknowledgement is sent after this many newly received segments
+const ACK_INTERVAL: u32 = 4;
+/// Pending acknowledgements are sent when no segment arrives for this long
+const ACK_DELAY: Duration = Duration::from_millis(10);
+/// Maximum number of ranges in a single selective acknowledgement
+const MAX_SACK_RANGES: usize = 32;
+
+pub async fn begin(
+    handler: NetworkHandler,
+    introduction: Introduction<'_>,
+    destination: &Destination,
+) -> Result<(), Error> {
+    fs::create_dir_all(destination.dir())?;
     let mut receiver = handler.subscribe();
-    let mut sender = handler.get_sender();
+    handler
+        .introduce(&mut receiver, introduction, false)
+        .await?;
+    println!("Ready for transmission");
 
-    while let Ok(msg) = receiver.recv().await {
-        if let Messages::FileTransferRequest(msg) = msg {
-            println!("Downloading {} byte file: {}", msg.filesize, msg.filename);
-            let mut file = fs::File::create(dir.join(msg.filename)).unwrap();
-            {
-                let msg = FileTransferAcceptMessage {};
-                sender
-                    .send_reliable(Messages::FileTransferAccept(msg))
-                    .await
-                    .unwrap();
+    let mut manifest = None;
+    //A file can be sent again, it only counts once
+    let mut received = HashSet::new();
+    let mut failed = HashSet::new();
+    let mut skipped = HashSet::new();
+    loop {
+        match handler.recv(&mut receiver).await? {
+            Messages::Manifest(msg) => {
+                let entries = receive_manifest(&handler, &mut receiver, msg).await?;
+                for entry in entries.iter() {
+                    if entry.kind == EntryKind::Directory {
+                        //Files in a refused directory are refused when they are requested
+                        match destination.path(&entry.path) {
+                            Ok(path) => fs::create_dir_all(path)?,
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                }
+                let entries = Manifest::from_entries(entries);
+                println!(
+                    "Receiving {} files, {} bytes",
+                    entries.file_count(),
+                    entries.total_size()
+                );
+                manifest = Some(entries);
+            }
+            Messages::FileTransferRequest(msg) => {
+                let entry = manifest.as_ref().and_then(|manifest| {
+                    manifest
+                        .entries
+                        .iter()
+                        .find(|entry| entry.kind == EntryKind::File && entry.path == msg.filename)
+                });
+                let entry = entry.ok_or_else(|| {
+                    Error::new(&format!(
+                        "{} is not in the manifest",
+                        printable(&msg.filename)
+                    ))
+                })?;
+                if entry.size != msg.filesize {
+                    return Err(Error::new(&format!(
+                        "The size of {} does not match the manifest",
+                        printable(&msg.filename)
+                    )));
+                }
+                let name = msg.filename.clone();
+                match receive_file(&handler, &mut receiver, destination, msg).await? {
+                    Outcome::Verified(path) => {
+                        println!("Received {}, saved as {}", printable(&name), path.display());
+                        received.insert(name);
+                    }
+                    Outcome::Skipped => {
+                        println!("Skipped {}", printable(&name));
+                        skipped.insert(name);
+                    }
+                    Outcome::Failed => {
+                        println!("Failed to receive {}", printable(&name));
+                        failed.insert(name);
+                    }
+                }
+            }
+            Messages::Goodbye(msg) => {
+                println!("MOTD: {}", printable(&msg.motd));
+                break;
+            }
+            _ => continue,
+        }
+    }
+    //The files are complete, a lost close handshake doesn't matter anymore
+    handler.close().await.ok();
+    let expected = manifest
+        .as_ref()
+        .map_or(received.len(), Manifest::file_count);
+    println!("Received {} of {} files", received.len(), expected);
+    if !skipped.is_empty() {
+        println!("Skipped {} files which already existed", skipped.len());
+    }
+    let missing = expected.saturating_sub(received.len() + skipped.len());
+    if missing > 0 {
+        if let Some(manifest) = &manifest {
+            let handled = received.iter().chain(&skipped).chain(&failed).cloned();
+            for path in not_sent(manifest, &handled.collect()) {
+                println!("Not sent: {}", printable(path));
             }
-            let motd = download_loop(&handler, &mut file, &mut receiver)
-                .await
-                .unwrap();
-            println!("MOTD: {}", motd);
-            break;
         }
+        return Err(Error::new(&format!("{} files were not received", missing)));
     }
+    Ok(())
+}
+
+/// Files of `manifest` the sender never offered, all others were `handled`
+pub fn not_sent<'a>(manifest: &'a Manifest, handled: &HashSet<String>) -> Vec<&'a str> {
+    manifest
+        .file_entries()
+        .filter(|entry| !handled.contains(&entry.path))
+        .map(|entry| entry.path.as_str())
+        .collect()
+}
+
+/// How the transfer of a single file ended
+pub enum Outcome {
+    /// The file matched its hash and was saved at the path
+    Verified(PathBuf),
+    /// The file did not match its hash or was refused, the sender was told
+    Failed,
+    /// The file already existed, the sender was told
+    Skipped,
 }
 
-pub async fn download_loop(
+/// Collects the entries of the manifest, `first` is the message which arrived first
+pub async fn receive_manifest(
     handler: &NetworkHandler,
-    file: &mut File,
     receiver: &mut Receiver<Messages>,
-) -> Option<String> {
+    first: ManifestMessage,
+) -> Result<Vec<ManifestEntry>, Error> {
+    let total = first.total as usize;
+    let mut entries = BTreeMap::new();
+    let mut msg = first;
     loop {
-        let msg = receiver.recv().await.unwrap();
-        match msg {
-            Messages::PartBegin(msg) => {
-                let mut part_data = vec![0u8; msg.part_size as usize];
-                part_loop(handler, file, &mut part_data, &msg, receiver)
-                    .await
-                    .unwrap();
+        if msg.total as usize != total || msg.start as usize + msg.entries.len() > total {
+            return Err(Error::new("Invalid manifest"));
+        }
+        for (i, entry) in msg.entries.into_iter().enumerate() {
+            entries.insert(msg.start as usize + i, entry);
+        }
+        if entries.len() == total {
+            return Ok(entries.into_values().collect());
+        }
+        msg = loop {
+            if let Messages::Manifest(msg) = handler.recv(receiver).await? {
+                break msg;
             }
-            Messages::Goodbye(msg) => {
-                return Some(msg.motd);
+        };
+    }
+}
+
+/// Receives the requested file into the download directory. It is written to a `.part`
+/// file first, which only takes the place of the file after it was verified
+pub async fn receive_file(
+    handler: &NetworkHandler,
+    receiver: &mut Receiver<Messages>,
+    destination: &Destination,
+    msg: FileTransferRequestMessage,
+) -> Result<Outcome, Error> {
+    let mut sender = handler.get_sender();
+    println!(
+        "Downloading {} byte file: {}",
+        msg.filesize,
+        printable(&msg.filename)
+    );
+    if msg.filesize > MAX_FILE_SIZE {
+        println!("The file is larger than {} bytes", MAX_FILE_SIZE);
+        decline(handler, "The file is too large").await?;
+        return Ok(Outcome::Failed);
+    }
+    if msg.version < PROTOCOL_VERSION {
+        let reason = format!("Protocol version {} is required", PROTOCOL_VERSION);
+        println!("The sender only supports protocol version {}", msg.version);
+        decline(handler, &reason).await?;
+        return Ok(Outcome::Failed);
+    }
+    let path = match destination.choose(&msg.filename).await {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            decline(handler, "The file already exists").await?;
+            return Ok(Outcome::Skipped);
+        }
+        Err(e) => {
+            println!("{}", e);
+            decline(handler, &e.to_string()).await?;
+            return Ok(Outcome::Failed);
+        }
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part = destination::part_path(&path);
+    //The blocks an earlier session left on disk are checked against the Merkle tree
+    let mut state = TransferState::load(&part, msg.hash, msg.filesize)
+        .unwrap_or_else(|| TransferState::new(&part, msg.hash, msg.filesize));
+    let resume_from = state.resume_offset();
+    if resume_from > 0 {
+        println!("Resuming at byte {} of {}", resume_from, msg.filesize);
+    }
+    //Read back to check the hash of the file
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(resume_from == 0)
+        .open(&part)?;
+    {
+        let msg = FileTransferAcceptMessage {
+            version: PROTOCOL_VERSION,
+            resume_from,
+        };
+        sender
+            .send_reliable(Messages::FileTransferAccept(msg))
+            .await?;
+    }
+    let blocks = receive_block_hashes(handler, receiver, &msg).await?;
+    let result = window_loop(handler, &mut file, &msg, &blocks, &mut state, receiver).await;
+    match &result {
+        //A file which does not match its hash is received from scratch next time
+        Ok(_) => state.remove()?,
+        //The next session with this file goes on from here
+        Err(_) => state
+            .save()
+            .unwrap_or_else(|e| println!("Could not save the progress: {}", e)),
+    }
+    drop(file);
+    if !result? {
+        fs::remove_file(&part)?;
+        return Ok(Outcome::Failed);
+    }
+    fs::rename(&part, &path)?;
+    Ok(Outcome::Verified(path))
+}
+
+/// Tells the sender that the requested file is not wanted
+async fn decline(handler: &NetworkHandler, reason: &str) -> Result<(), Error> {
+    let msg = FileTransferDeclineMessage {
+        reason: reason.to_string(),
+    };
+    handler
+        .get_sender()
+        .send_reliable(Messages::FileTransferDecline(msg))
+        .await
+}
+
+/// Receives the leaves of the Merkle tree of the requested file, and checks them against its root
+pub async fn receive_block_hashes(
+    handler: &NetworkHandler,
+    receiver: &mut Receiver<Messages>,
+    request: &FileTransferRequestMessage,
+) -> Result<BlockHashes, Error> {
+    if request.filesize > MAX_FILE_SIZE {
+        return Err(Error::new("The file is too large"));
+    }
+    let count = usize::try_from(merkle::block_count(request.filesize))?;
+    let mut leaves: Vec<Option<Hash>> = vec![None; count];
+    let mut missing = count;
+    while missing > 0 {
+        if let Messages::BlockHashes(msg) = handler.recv(receiver).await? {
+            let start = msg.start as usize;
+            if start + msg.hashes.len() > count {
+                return Err(Error::new("Invalid block hashes"));
+            }
+            for (leaf, hash) in leaves[start..].iter_mut().zip(msg.hashes) {
+                if leaf.replace(hash).is_none() {
+                    missing -= 1;
+                }
             }
-            _ => continue,
         }
     }
-    Some("ERROR".to_string())
+    let blocks = BlockHashes {
+        leaves: leaves.into_iter().flatten().collect(),
+    };
+    if blocks.root() != request.root {
+        return Err(Error::new(
+            "The block hashes do not match the announced root",
+        ));
+    }
+    Ok(blocks)
+}
+
+/// Number of segments of the requested file
+fn segment_count(request: &FileTransferRequestMessage) -> Result<u32, Error> {
+    if request.chunk_size == 0 {
+        return Err(Error::new("Invalid chunk size"));
+    }
+    let count = request.filesize.div_ceil(request.chunk_size as u64);
+    u32::try_from(count).map_err(|_| Error::new("The file has too many segments"))
+}
+
+/// Segments making up the block `index`
+fn block_segments(index: u32, chunk_size: u64, segment_count: u32) -> (u32, u32) {
+    let start = index as u64 * BLOCK_SIZE / chunk_size;
+    let end = ((index as u64 + 1) * BLOCK_SIZE).div_ceil(chunk_size);
+    let clamp = |n: u64| u32::try_from(n).map_or(segment_count, |n| n.min(segment_count));
+    (clamp(start), clamp(end))
+}
+
+/// Whether the block `index` on disk matches its hash
+fn verify_block(
+    file: &mut File,
+    blocks: &BlockHashes,
+    index: u32,
+    filesize: u64,
+) -> Result<bool, Error> {
+    let block_start = index as u64 * BLOCK_SIZE;
+    let block_size = (filesize - block_start).min(BLOCK_SIZE);
+    let mut block = Vec::with_capacity(block_size as usize);
+    file.seek(SeekFrom::Start(block_start))?;
+    Read::by_ref(file)
+        .take(block_size)
+        .read_to_end(&mut block)?;
+    Ok(blocks.verify(index, &block))
+}
+
+/// Checks the blocks which were completed by the segment `sequence`. Returns the
+/// segments of a block which does not match its hash, they have to be received again
+fn check_blocks(
+    file: &mut File,
+    blocks: &BlockHashes,
+    state: &mut TransferState,
+    received: &Bitfield,
+    sequence: u32,
+    request: &FileTransferRequestMessage,
+) -> Result<Option<(u32, u32)>, Error> {
+    let chunk_size = request.chunk_size as u64;
+    let segment_count = segment_count(request)?;
+    let offset = sequence as u64 * chunk_size;
+    let end = (offset + chunk_size).min(request.filesize);
+    let first = u32::try_from(offset / BLOCK_SIZE)?;
+    let last = u32::try_from((end - 1) / BLOCK_SIZE)?;
+    for index in first..=last {
+        let (start, end) = block_segments(index, chunk_size, segment_count);
+        if state.blocks.get(index as usize) || !(start..end).all(|s| received.get(s as usize)) {
+            continue;
+        }
+        if !verify_block(file, blocks, index, request.filesize)? {
+            println!("Block {} is corrupted, receiving it again", index);
+            return Ok(Some((start, end)));
+        }
+        state.complete(index)?;
+    }
+    Ok(None)
+}
+
+/// Checks the blocks an earlier session left on disk, up to `resume_from`. Returns the
+/// segments of the blocks which do not match their hash, they have to be received again
+fn check_resumed(
+    file: &mut File,
+    blocks: &BlockHashes,
+    state: &mut TransferState,
+    resume_from: u64,
+    request: &FileTransferRequestMessage,
+) -> Result<Vec<(u32, u32)>, Error> {
+    let chunk_size = request.chunk_size as u64;
+    let segment_count = segment_count(request)?;
+    let mut corrupted = Vec::new();
+    for index in 0..u32::try_from(resume_from.div_ceil(BLOCK_SIZE))? {
+        if !verify_block(file, blocks, index, request.filesize)? {
+            println!("Block {} changed on disk, receiving it again", index);
+            state.blocks.set(index as usize, false);
+            corrupted.push(block_segments(index, chunk_size, segment_count));
+        }
+    }
+    Ok(corrupted)
 }
 
-pub async fn part_loop(
+/// Receives the whole file through a selective repeat window. Every block is checked
+/// against `blocks` as soon as it is complete and recorded in the transfer state.
+/// The transfer goes on where the state stopped. Returns whether the file matched its hash
+pub async fn window_loop(
     handler: &NetworkHandler,
     file: &mut File,
-    part_data: &mut Vec<u8>,
-    part_info: &PartBeginMessage,
+    request: &FileTransferRequestMessage,
+    blocks: &BlockHashes,
+    state: &mut TransferState,
     receiver: &mut Receiver<Messages>,
-) -> Option<()> {
+) -> Result<bool, Error> {
     let mut sender = handler.get_sender();
+    let chunk_size = request.chunk_size as u64;
+    let segment_count = segment_count(request)?;
+    let mut received = Bitfield::new();
+    let mut unacknowledged = 0u32;
+    let mut corrupted = 0u32;
+    //The sender starts with the segment holding the first missing byte
+    let resume_from = state.resume_offset();
+    let first = u32::try_from(resume_from / chunk_size)?.min(segment_count);
+    let mut cumulative = first;
+    for sequence in 0..first {
+        received.set(sequence as usize, true);
+    }
+    for (start, end) in check_resumed(file, blocks, state, resume_from, request)? {
+        for sequence in start..end {
+            received.set(sequence as usize, false);
+        }
+        cumulative = cumulative.min(start);
+        let msg = RefetchMessage { start, end };
+        sender.send_reliable(Messages::Refetch(msg)).await?;
+    }
+    let mut highest = first;
 
-    let mut received_files = Bitfield::new();
-
-    while let Ok(msg) = receiver.recv().await {
+    loop {
+        if cumulative == segment_count {
+            if corrupted > 0 {
+                println!("{} corrupted segments were sent again", corrupted);
+            }
+            return report_hash(handler, file, request.hash).await;
+        }
+        let msg = match tokio::time::timeout(ACK_DELAY, handler.recv(receiver)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                if unacknowledged > 0 {
+                    unacknowledged = 0;
+                    let msg = selective_ack(&received, cumulative, highest);
+                    sender.send(Messages::SelectiveAck(msg))?;
+                }
+                continue;
+            }
+        };
         match msg {
-            Messages::Chunk(msg) => {
-                if msg.part_number != part_info.part_number {
+            Messages::Segment(msg) => {
+                if msg.sequence >= segment_count {
                     continue;
                 }
-                let range = (msg.index * part_info.chunk_size) as usize
-                    ..(msg.index * part_info.chunk_size) as usize + msg.data.len();
-                part_data.splice(range, msg.data);
-                received_files.set(msg.index as usize, true);
+                //The segment is treated as lost, the gap makes the sender repeat it
+                if !msg.is_intact() {
+                    corrupted += 1;
+                    continue;
+                }
+                //Only the last segment may be shorter, none may reach past the end of the file
+                let offset = msg.sequence as u64 * chunk_size;
+                if msg.data.len() as u64 != chunk_size.min(request.filesize - offset) {
+                    return Err(Error::new("Invalid segment size"));
+                }
+                let in_order = msg.sequence == cumulative;
+                if !received.get(msg.sequence as usize) {
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&msg.data)?;
+                    received.set(msg.sequence as usize, true);
+                    highest = highest.max(msg.sequence + 1);
+                    let refetch =
+                        check_blocks(file, blocks, state, &received, msg.sequence, request)?;
+                    if let Some((start, end)) = refetch {
+                        for sequence in start..end {
+                            received.set(sequence as usize, false);
+                        }
+                        cumulative = cumulative.min(start);
+                        let msg = RefetchMessage { start, end };
+                        sender.send_reliable(Messages::Refetch(msg)).await?;
+                    }
+                    while received.get(cumulative as usize) {
+                        cumulative += 1;
+                    }
+                }
+                unacknowledged += 1;
+                //Gaps and duplicates are reported right away, so the sender can react quickly
+                if !in_order || unacknowledged >= ACK_INTERVAL || cumulative == segment_count {
+                    unacknowledged = 0;
+                    let msg = selective_ack(&received, cumulative, highest);
+                    sender.send(Messages::SelectiveAck(msg))?;
+                }
+            }
+            Messages::Goodbye(_) => {
+                let missing = segment_count - cumulative;
+                return Err(Error::new(&format!("{} segments are missing", missing)));
             }
-            Messages::PartEnd(_) => {
-                let successful = received_files
-                    .iter()
-                    .take(part_info.chunk_count as usize)
-                    .all(|a| a);
-                if successful {
-                    file.write_all(part_data).unwrap();
-                    let msg = TransferSuccessfulMessage {};
-                    sender
-                        .send_reliable(Messages::TransferSuccessful(msg))
-                        .await
-                        .unwrap();
+            _ => continue,
+        }
+    }
+}
+
+/// Checks the file against the hash announced by the sender
+fn check_hash(file: &mut File, expected: FileHash) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(0))?;
+    let hash = integrity::hash_reader(file)?;
+    if hash != expected {
+        return Err(Error::new(
+            "The received file is corrupted, its hash does not match",
+        ));
+    }
+    println!("Verified the file, SHA-256: {}", integrity::to_hex(&hash));
+    Ok(())
+}
+
+/// Checks the hash of the complete file and tells the sender whether the transfer succeeded
+async fn report_hash(
+    handler: &NetworkHandler,
+    file: &mut File,
+    expected: FileHash,
+) -> Result<bool, Error> {
+    let mut sender = handler.get_sender();
+    if let Err(e) = check_hash(file, expected) {
+        println!("{}", e);
+        let msg = TransferFailedMessage {
+            reason: e.to_string(),
+        };
+        sender.send_reliable(Messages::TransferFailed(msg)).await?;
+        return Ok(false);
+    }
+    let msg = TransferSuccessfulMessage {};
+    sender
+        .send_reliable(Messages::TransferSuccessful(msg))
+        .await?;
+    Ok(true)
+}
+
+fn selective_ack(received: &Bitfield, cumulative: u32, highest: u32) -> SelectiveAckMessage {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for sequence in cumulative..highest {
+        match (received.get(sequence as usize), start) {
+            (true, None) => start = Some(sequence),
+            (false, Some(begin)) => {
+                ranges.push((begin, sequence));
+                start = None;
+                if ranges.len() == MAX_SACK_RANGES {
                     break;
-                } else {
-                    println!(
-                        "{} Missing chunks... Fixing.",
-                        received_files
-                            .iter()
-                            .take(part_info.chunk_count as usize)
-                            .filter(|a| !a)
-                            .count()
-                    );
-                    let msg = TransferIncompleteMessage {
-                        bitfield: received_files.clone(),
-                    };
-                    sender
-                        .send_reliable(Messages::TransferIncomplete(msg))
-                        .await
-                        .unwrap();
                 }
             }
-            _ => continue,
+            _ => {}
         }
     }
-    Some(())
+    if let (Some(begin), true) = (start, ranges.len() < MAX_SACK_RANGES) {
+        ranges.push((begin, highest));
+    }
+    SelectiveAckMessage { cumulative, ranges }
 }
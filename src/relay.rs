@@ -0,0 +1,277 @@
+use crate::{
+    error::Error,
+    message::{
+        Messages, RelayAllocateMessage, RelayAllocatedMessage, RelayDataMessage,
+        RelayPermissionMessage,
+    },
+    networking::{bind_dual_stack, map_to_family, unmap, RECEIVE_BUFFER_SIZE},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::{udp::SendHalf, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+
+/// Allocations without any traffic from their client are released after this long
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+/// Allocations which never relayed anything are released sooner, their clients
+/// refresh them while they wait for the partner
+const UNUSED_LIFETIME: Duration = Duration::from_secs(60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+/// Every allocation holds a socket, so there are only this many at once
+pub const MAX_ALLOCATIONS: usize = 1000;
+/// Allocations of the clients behind a single address
+pub const MAX_CLIENT_ALLOCATIONS: usize = 4;
+/// Peers a client can let through its allocation
+pub const MAX_PERMISSIONS: usize = 16;
+/// Bytes a client can relay per second, so the relay can't be used to flood others
+pub const RELAY_RATE: u64 = 4 * 1024 * 1024;
+/// Bytes a client can relay at once, before the rate applies
+pub const RELAY_BURST: u64 = 256 * 1024;
+const ALLOCATE_RTO: Duration = Duration::from_millis(500);
+const MAX_ALLOCATE_ATTEMPTS: u32 = 6;
+
+/// Address on the relay, bound to one client
+struct Allocation {
+    relayed: SocketAddr,
+    sender: SendHalf,
+    /// Only peers the client registered may reach it, and be reached by it
+    permissions: Arc<Mutex<HashSet<IpAddr>>>,
+    limit: RateLimit,
+    last_seen: Instant,
+    /// Whether the client sent anything through it
+    used: bool,
+    /// Dropping this stops forwarding to the client
+    _stop: oneshot::Sender<()>,
+}
+
+/// Token bucket over the bytes a client relays
+struct RateLimit {
+    tokens: u64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    fn new() -> Self {
+        Self {
+            tokens: RELAY_BURST,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes `size` bytes from the bucket. Returns false if the client is too fast
+    fn take(&mut self, size: usize) -> bool {
+        let refill = self.updated.elapsed().as_micros() as u64 * RELAY_RATE / 1_000_000;
+        //Refills below a byte would be lost, the time counts towards the next one
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(RELAY_BURST);
+            self.updated = Instant::now();
+        }
+        match self.tokens.checked_sub(size as u64) {
+            Some(tokens) => {
+                self.tokens = tokens;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// TURN-like relay server. Clients ask for an allocation, every datagram sent to the
+/// allocated address is tunneled to the client, and the client tunnels its answers back
+pub struct RelayServer {
+    socket: UdpSocket,
+    public_ip: IpAddr,
+}
+
+impl RelayServer {
+    /// Binds the server. `public_ip` is the address peers reach the relay on,
+    /// it defaults to the address of `listen`
+    pub async fn bind(listen: SocketAddr, public_ip: Option<IpAddr>) -> Result<Self, Error> {
+        let public_ip = match public_ip {
+            Some(ip) => ip,
+            None if listen.ip().is_unspecified() => {
+                return Err(Error::new("The public address of the relay is unknown"))
+            }
+            None => listen.ip(),
+        };
+        let socket = UdpSocket::from_std(bind_dual_stack(listen)?)?;
+        Ok(Self { socket, public_ip })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn run(self) -> Result<(), Error> {
+        let local_addr = self.socket.local_addr()?;
+        let public_ip = self.public_ip;
+        let (mut socket_in, mut socket_out) = self.socket.split();
+        //Every allocation sends to its client through the main socket
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr)>();
+        tokio::spawn(async move {
+            while let Some((bytes, client)) = outgoing_rx.recv().await {
+                socket_out.send_to(&bytes, &client).await.ok();
+            }
+        });
+
+        let mut allocations: HashMap<SocketAddr, Allocation> = HashMap::new();
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            tokio::select! {
+                _ = cleanup.tick() => {
+                    allocations.retain(|_, allocation| {
+                        let lifetime = match allocation.used {
+                            true => ALLOCATION_LIFETIME,
+                            false => UNUSED_LIFETIME,
+                        };
+                        allocation.last_seen.elapsed() < lifetime
+                    });
+                }
+                received = socket_in.recv_from(&mut buf) => {
+                    let (size, client) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    //Malformed requests are dropped, they must not stop the server
+                    match Messages::from_datagram(&buf[..size]) {
+                        Some(Messages::RelayAllocate(_)) => {
+                            let full = allocations.len() >= MAX_ALLOCATIONS
+                                || allocations.keys().filter(|other| other.ip() == client.ip()).count() >= MAX_CLIENT_ALLOCATIONS;
+                            let allocation = match allocations.entry(client) {
+                                Entry::Occupied(entry) => entry.into_mut(),
+                                Entry::Vacant(_) if full => continue,
+                                Entry::Vacant(entry) => {
+                                    match allocate(local_addr, public_ip, client, outgoing.clone()) {
+                                        Ok(allocation) => entry.insert(allocation),
+                                        Err(_) => continue,
+                                    }
+                                }
+                            };
+                            allocation.last_seen = Instant::now();
+                            let response = RelayAllocatedMessage {
+                                address: allocation.relayed,
+                            };
+                            let response = Messages::RelayAllocated(response).to_datagram();
+                            outgoing.send((response, client)).ok();
+                        }
+                        Some(Messages::RelayPermission(msg)) => {
+                            if let Some(allocation) = allocations.get_mut(&client) {
+                                allocation.last_seen = Instant::now();
+                                let mut permissions = allocation.permissions.lock().unwrap();
+                                if permissions.len() < MAX_PERMISSIONS {
+                                    permissions.insert(unmap(msg.peer).ip());
+                                }
+                            }
+                        }
+                        Some(Messages::RelayData(msg)) => {
+                            if let Some(allocation) = allocations.get_mut(&client) {
+                                allocation.last_seen = Instant::now();
+                                let permitted = allocation.permissions.lock().unwrap().contains(&unmap(msg.peer).ip());
+                                if !permitted || !allocation.limit.take(msg.data.len()) {
+                                    continue;
+                                }
+                                allocation.used = true;
+                                let peer = map_to_family(msg.peer, local_addr);
+                                allocation.sender.send_to(&msg.data, &peer).await.ok();
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Binds a new relayed address for `client` and forwards everything permitted to it
+fn allocate(
+    local_addr: SocketAddr,
+    public_ip: IpAddr,
+    client: SocketAddr,
+    outgoing: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+) -> Result<Allocation, Error> {
+    let socket = UdpSocket::from_std(bind_dual_stack(SocketAddr::new(local_addr.ip(), 0))?)?;
+    let relayed = SocketAddr::new(public_ip, socket.local_addr()?.port());
+    let (mut receiver, sender) = socket.split();
+    let permissions = Arc::new(Mutex::new(HashSet::new()));
+    let (stop, mut stopped) = oneshot::channel::<()>();
+    let allowed = Arc::clone(&permissions);
+    tokio::spawn(async move {
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            tokio::select! {
+                _ = &mut stopped => break,
+                received = receiver.recv_from(&mut buf) => {
+                    let (size, peer) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    let peer = unmap(peer);
+                    if !allowed.lock().unwrap().contains(&peer.ip()) {
+                        continue;
+                    }
+                    if outgoing.send((wrap(peer, buf[..size].to_vec()), client)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    Ok(Allocation {
+        relayed,
+        sender,
+        permissions,
+        limit: RateLimit::new(),
+        last_seen: Instant::now(),
+        used: false,
+        _stop: stop,
+    })
+}
+
+/// Requests an allocation on the relay `server`. Returns the relayed address,
+/// which peers can send to. Sending the request again refreshes the allocation
+pub async fn allocate_address(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+) -> Result<SocketAddr, Error> {
+    let local_addr = socket.local_addr()?;
+    let request = Messages::RelayAllocate(RelayAllocateMessage {}).to_datagram();
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    for _ in 0..MAX_ALLOCATE_ATTEMPTS {
+        socket
+            .send_to(&request, &map_to_family(server, local_addr))
+            .await?;
+        let answer = tokio::time::timeout(ALLOCATE_RTO, async {
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await?;
+                if unmap(from) != server {
+                    continue;
+                }
+                if let Some(Messages::RelayAllocated(msg)) = Messages::from_datagram(&buf[..size]) {
+                    return Ok::<SocketAddr, Error>(msg.address);
+                }
+            }
+        })
+        .await;
+        if let Ok(result) = answer {
+            return result;
+        }
+    }
+    Err(Error::timeout("Relay server did not answer"))
+}
+
+/// Wraps a datagram for `peer`, so it can be sent through the relay
+pub fn wrap(peer: SocketAddr, data: Vec<u8>) -> Vec<u8> {
+    Messages::RelayData(RelayDataMessage { peer, data }).to_datagram()
+}
+
+/// Asks the relay `server` to let `peer` through our allocation. Requests may be lost,
+/// so it is sent along with every check to the peer
+pub fn permit(peer: SocketAddr) -> Vec<u8> {
+    Messages::RelayPermission(RelayPermissionMessage { peer }).to_datagram()
+}
@@ -0,0 +1,328 @@
+use crate::{
+    error::Error,
+    message::{
+        Messages, RendezvousClaimMessage, RendezvousPeerMessage, RendezvousRegisterMessage,
+        RendezvousRegisteredMessage, RendezvousUnknownMessage,
+    },
+    networking::{bind_dual_stack, map_to_family, unmap, RECEIVE_BUFFER_SIZE},
+    obfuscator::AddressInfo,
+    words::{self, WORDS},
+};
+use rand::Rng;
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+/// Mailboxes without a refresh from their owner are closed after this long
+const MAILBOX_LIFETIME: Duration = Duration::from_secs(600);
+/// Claimed mailboxes are only kept to answer retransmissions
+const CLAIMED_LIFETIME: Duration = Duration::from_secs(30);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+/// Every mailbox takes up a nameplate, so there are only this many at once
+pub const MAX_MAILBOXES: usize = 10000;
+/// Mailboxes of the clients behind a single address
+pub const MAX_CLIENT_MAILBOXES: usize = 4;
+const REQUEST_RTO: Duration = Duration::from_millis(500);
+const MAX_REQUEST_ATTEMPTS: u32 = 6;
+/// Number of secret words in a code
+const CODE_WORDS: usize = 2;
+
+/// Short code like `7-crossbow-tulip`: the number of a mailbox on the rendezvous
+/// server, and the words which only the two peers know. They are the password of
+/// the connection, the server never sees them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Code {
+    pub nameplate: u16,
+    pub words: String,
+}
+
+impl Code {
+    /// Random words for a new mailbox
+    pub fn random_words() -> String {
+        let mut rng = rand::thread_rng();
+        let words: Vec<&str> = (0..CODE_WORDS)
+            .map(|_| WORDS[rng.gen::<u8>() as usize])
+            .collect();
+        words.join("-")
+    }
+
+    /// Password of the connection. The server and anyone who saw the nameplate
+    /// can't derive it, they never got the words
+    pub fn password(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.nameplate, self.words)
+    }
+}
+
+impl FromStr for Code {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (nameplate, words) = s.split_once('-').ok_or(Error::new("Invalid code"))?;
+        let nameplate = nameplate.parse().map_err(|_| Error::new("Invalid code"))?;
+        let valid = words.split('-').all(|word| words::index(word).is_some());
+        if !valid || words.split('-').count() != CODE_WORDS {
+            return Err(Error::new("Invalid code"));
+        }
+        Ok(Self {
+            nameplate,
+            words: words.into(),
+        })
+    }
+}
+
+struct Mailbox {
+    owner: SocketAddr,
+    info: AddressInfo,
+    /// Whoever claimed the mailbox, with its code
+    claim: Option<(SocketAddr, AddressInfo)>,
+    last_seen: Instant,
+}
+
+/// Open mailboxes by nameplate, and the nameplates which can be handed out again
+struct Mailboxes {
+    open: HashMap<u16, Mailbox>,
+    /// Nameplates of closed mailboxes
+    free: BTreeSet<u16>,
+    /// Lowest nameplate which was never handed out
+    next: u32,
+}
+
+impl Mailboxes {
+    fn new() -> Self {
+        Self {
+            open: HashMap::new(),
+            free: BTreeSet::new(),
+            next: 1,
+        }
+    }
+
+    /// Lowest nameplate which is not in use, low numbers keep the codes short
+    fn take_nameplate(&mut self) -> Option<u16> {
+        if let Some(nameplate) = self.free.pop_first() {
+            return Some(nameplate);
+        }
+        let nameplate = u16::try_from(self.next).ok()?;
+        self.next += 1;
+        Some(nameplate)
+    }
+
+    /// Closes the mailboxes which were not refreshed in time
+    fn cleanup(&mut self) {
+        let free = &mut self.free;
+        self.open.retain(|nameplate, mailbox| {
+            let lifetime = match mailbox.claim {
+                Some(_) => CLAIMED_LIFETIME,
+                None => MAILBOX_LIFETIME,
+            };
+            let open = mailbox.last_seen.elapsed() < lifetime;
+            if !open {
+                free.insert(*nameplate);
+            }
+            open
+        });
+    }
+}
+
+/// Mailbox server like the one of magic-wormhole. Senders leave their code under a
+/// nameplate, the receiver gets it with the nameplate and leaves its own in exchange
+pub struct RendezvousServer {
+    socket: UdpSocket,
+}
+
+impl RendezvousServer {
+    pub async fn bind(listen: SocketAddr) -> Result<Self, Error> {
+        let socket = UdpSocket::from_std(bind_dual_stack(listen)?)?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn run(mut self) -> Result<(), Error> {
+        let mut mailboxes = Mailboxes::new();
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            tokio::select! {
+                _ = cleanup.tick() => mailboxes.cleanup(),
+                received = self.socket.recv_from(&mut buf) => {
+                    let (size, client) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    let answers = match Messages::from_datagram(&buf[..size]) {
+                        Some(Messages::RendezvousRegister(msg)) => open_mailbox(&mut mailboxes, client, msg),
+                        Some(Messages::RendezvousClaim(msg)) => claim_mailbox(&mut mailboxes, client, msg),
+                        //Malformed requests are dropped, they must not stop the server
+                        _ => continue,
+                    };
+                    for (answer, to) in answers {
+                        self.socket.send_to(&answer.to_datagram(), &to).await.ok();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Opens or refreshes the mailbox of `client`
+fn open_mailbox(
+    mailboxes: &mut Mailboxes,
+    client: SocketAddr,
+    msg: RendezvousRegisterMessage,
+) -> Vec<(Messages, SocketAddr)> {
+    let existing = mailboxes
+        .open
+        .iter()
+        .find(|(_, mailbox)| mailbox.owner == client)
+        .map(|(nameplate, _)| *nameplate);
+    let full = mailboxes.open.len() >= MAX_MAILBOXES
+        || mailboxes
+            .open
+            .values()
+            .filter(|mailbox| mailbox.owner.ip() == client.ip())
+            .count()
+            >= MAX_CLIENT_MAILBOXES;
+    let nameplate = match existing {
+        Some(nameplate) => nameplate,
+        None if full => return Vec::new(),
+        None => match mailboxes.take_nameplate() {
+            Some(nameplate) => nameplate,
+            None => return Vec::new(),
+        },
+    };
+    let mailbox = mailboxes.open.entry(nameplate).or_insert(Mailbox {
+        owner: client,
+        info: AddressInfo::new(Vec::new()),
+        claim: None,
+        last_seen: Instant::now(),
+    });
+    mailbox.info = msg.info;
+    mailbox.last_seen = Instant::now();
+    let mut answers = vec![(
+        Messages::RendezvousRegistered(RendezvousRegisteredMessage { nameplate }),
+        client,
+    )];
+    //The owner missed the code of the claim
+    if let Some((_, info)) = &mailbox.claim {
+        let peer = RendezvousPeerMessage { info: info.clone() };
+        answers.push((Messages::RendezvousPeer(peer), client));
+    }
+    answers
+}
+
+/// Hands the code in the mailbox to `client` and its code to the owner
+fn claim_mailbox(
+    mailboxes: &mut Mailboxes,
+    client: SocketAddr,
+    msg: RendezvousClaimMessage,
+) -> Vec<(Messages, SocketAddr)> {
+    let unknown = vec![(
+        Messages::RendezvousUnknown(RendezvousUnknownMessage {}),
+        client,
+    )];
+    let mailbox = match mailboxes.open.get_mut(&msg.nameplate) {
+        Some(mailbox) => mailbox,
+        None => return unknown,
+    };
+    //Only one peer gets the code, a wrong guess of the words fails on the connection
+    if let Some((claimant, _)) = &mailbox.claim {
+        if *claimant != client {
+            return unknown;
+        }
+    }
+    mailbox.last_seen = Instant::now();
+    let to_owner = RendezvousPeerMessage {
+        info: msg.info.clone(),
+    };
+    let to_client = RendezvousPeerMessage {
+        info: mailbox.info.clone(),
+    };
+    mailbox.claim = Some((client, msg.info));
+    vec![
+        (Messages::RendezvousPeer(to_client), client),
+        (Messages::RendezvousPeer(to_owner), mailbox.owner),
+    ]
+}
+
+/// Opens a mailbox for our code `info` on the rendezvous `server` and returns its
+/// nameplate. Registering again refreshes the mailbox
+pub async fn register(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    info: &AddressInfo,
+) -> Result<u16, Error> {
+    let request = Messages::RendezvousRegister(RendezvousRegisterMessage { info: info.clone() });
+    exchange(socket, server, request, |answer| match answer {
+        Messages::RendezvousRegistered(msg) => Some(Ok(msg.nameplate)),
+        _ => None,
+    })
+    .await
+}
+
+/// Claims the mailbox of `code`, leaving our code `info` for its owner.
+/// Returns the code of the owner
+pub async fn claim(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    code: &Code,
+    info: &AddressInfo,
+) -> Result<AddressInfo, Error> {
+    let request = Messages::RendezvousClaim(RendezvousClaimMessage {
+        nameplate: code.nameplate,
+        info: info.clone(),
+    });
+    exchange(socket, server, request, |answer| match answer {
+        Messages::RendezvousPeer(msg) => Some(Ok(msg.info)),
+        Messages::RendezvousUnknown(_) => Some(Err(Error::new("Unknown code"))),
+        _ => None,
+    })
+    .await
+}
+
+/// Sends `request` until `parse` accepts an answer of the server
+async fn exchange<T>(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    request: Messages,
+    parse: impl Fn(Messages) -> Option<Result<T, Error>>,
+) -> Result<T, Error> {
+    let local_addr = socket.local_addr()?;
+    let request = request.to_datagram();
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    for _ in 0..MAX_REQUEST_ATTEMPTS {
+        socket
+            .send_to(&request, &map_to_family(server, local_addr))
+            .await?;
+        let answer = tokio::time::timeout(REQUEST_RTO, async {
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await?;
+                if unmap(from) != server {
+                    continue;
+                }
+                if let Some(result) = Messages::from_datagram(&buf[..size]).and_then(&parse) {
+                    return result;
+                }
+            }
+        })
+        .await;
+        if let Ok(result) = answer {
+            return result;
+        }
+    }
+    Err(Error::timeout("Rendezvous server did not answer"))
+}
@@ -0,0 +1,125 @@
+use crate::{
+    bitfield::Bitfield,
+    error::Error,
+    integrity::{FileHash, HASH_SIZE},
+    merkle::{self, BLOCK_SIZE},
+};
+use std::{
+    convert::TryInto,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Identifies the state files of this program
+const MAGIC: &[u8; 4] = b"p2ps";
+/// Layout of the state file, files of other versions are ignored
+const STATE_VERSION: u8 = 1;
+const EXTENSION: &str = "p2p-state";
+/// The state is written at most this often while blocks are verified
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Progress of a download, kept in a file next to it so a later session
+/// can continue where this one stopped
+pub struct TransferState {
+    path: PathBuf,
+    hash: FileHash,
+    filesize: u64,
+    /// Blocks which are on disk and matched their hash
+    pub blocks: Bitfield,
+    saved_at: Option<Instant>,
+}
+
+impl TransferState {
+    /// State of a download into `file` which starts from zero
+    pub fn new(file: &Path, hash: FileHash, filesize: u64) -> Self {
+        Self {
+            path: state_path(file),
+            hash,
+            filesize,
+            blocks: Bitfield::new(),
+            saved_at: None,
+        }
+    }
+
+    /// State of an earlier download of the same file into `file`, if there is one. Only the
+    /// completed blocks at the start are kept, the rest is received again
+    pub fn load(file: &Path, hash: FileHash, filesize: u64) -> Option<Self> {
+        if !file.is_file() {
+            return None;
+        }
+        let path = state_path(file);
+        let bytes = fs::read(&path).ok()?;
+        let header = MAGIC.len() + 1 + 8 + HASH_SIZE;
+        if bytes.len() < header || &bytes[..4] != MAGIC || bytes[4] != STATE_VERSION {
+            return None;
+        }
+        let size = u64::from_le_bytes(bytes[5..13].try_into().ok()?);
+        if size != filesize || bytes[13..header] != hash {
+            return None;
+        }
+        let stored = Bitfield::from_bytes(bytes[header..].to_vec());
+        let mut blocks = Bitfield::new();
+        for index in 0..merkle::block_count(filesize) as usize {
+            if !stored.get(index) {
+                break;
+            }
+            blocks.set(index, true);
+        }
+        Some(Self {
+            path,
+            hash,
+            filesize,
+            blocks,
+            saved_at: None,
+        })
+    }
+
+    /// Number of bytes at the start of the file which don't have to be received again
+    pub fn resume_offset(&self) -> u64 {
+        let count = (0..merkle::block_count(self.filesize) as usize)
+            .take_while(|&index| self.blocks.get(index))
+            .count();
+        (count as u64 * BLOCK_SIZE).min(self.filesize)
+    }
+
+    /// The block `index` matched its hash
+    pub fn complete(&mut self, index: u32) -> Result<(), Error> {
+        self.blocks.set(index as usize, true);
+        match self.saved_at {
+            Some(saved_at) if saved_at.elapsed() < SAVE_INTERVAL => Ok(()),
+            _ => self.save(),
+        }
+    }
+
+    /// Writes the state to disk. A new file replaces the old one, so it is never half written
+    pub fn save(&mut self) -> Result<(), Error> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(STATE_VERSION);
+        bytes.extend(self.filesize.to_le_bytes().iter());
+        bytes.extend(self.hash.iter());
+        bytes.extend(self.blocks.get_bytes());
+        let temporary = self.path.with_extension(format!("{}.tmp", EXTENSION));
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, &self.path)?;
+        self.saved_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// The download is complete, the state is not needed anymore
+    pub fn remove(self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Path of the state file of a download into `file`
+pub fn state_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(EXTENSION);
+    file.with_file_name(name)
+}
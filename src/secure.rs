@@ -0,0 +1,310 @@
+use crate::{bitfield::Bitfield, error::Error};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState};
+use std::{
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+
+/// Both peers bring an ephemeral and a static key, the static keys are sent encrypted
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Handshakes of other protocols with the same pattern fail
+const PROLOGUE: &[u8] = b"p2p file transfer";
+/// Encrypted datagrams start with this instead of `message::MAGIC`
+pub const SECURE_MAGIC: [u8; 4] = [0x50, 0x32, 0x50, 0x02];
+/// Magic, key epoch and counter, authenticated along with the payload
+const HEADER_SIZE: usize = SECURE_MAGIC.len() + 4 + 8;
+const TAG_SIZE: usize = 16;
+/// Bytes added to every datagram by the encryption
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+const KEY_SIZE: usize = 32;
+const MAX_HANDSHAKE_MESSAGE: usize = 65535;
+/// The keys are replaced after this many datagrams or after this long, whatever comes first
+const REKEY_AFTER_MESSAGES: u64 = 1 << 20;
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+/// Reordered datagrams are accepted up to this many counters behind the newest one
+const REPLAY_WINDOW: u64 = 4096;
+
+/// Noise handshake agreeing on the keys of the connection
+pub struct Handshake {
+    state: HandshakeState,
+}
+
+impl Handshake {
+    /// The initiator sends the first message
+    pub fn new(initiator: bool) -> Result<Self, Error> {
+        let builder = Builder::new(NOISE_PARAMS.parse()?);
+        let keypair = builder.generate_keypair()?;
+        let builder = builder
+            .local_private_key(&keypair.private)
+            .prologue(PROLOGUE);
+        let state = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+        Ok(Self { state })
+    }
+
+    /// Whether the next message is ours
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    /// Hash of the whole handshake, which is the same for both peers only without a man in the middle
+    pub fn hash(&self) -> Vec<u8> {
+        self.state.get_handshake_hash().to_vec()
+    }
+
+    /// Our next handshake message
+    pub fn write(&mut self) -> Result<Vec<u8>, Error> {
+        let mut message = vec![0; MAX_HANDSHAKE_MESSAGE];
+        let size = self.state.write_message(&[], &mut message)?;
+        message.truncate(size);
+        Ok(message)
+    }
+
+    /// Reads the next handshake message of the peer. Unexpected messages leave the state untouched
+    pub fn read(&mut self, message: &[u8]) -> Result<(), Error> {
+        let mut payload = vec![0; MAX_HANDSHAKE_MESSAGE];
+        self.state.read_message(message, &mut payload)?;
+        Ok(())
+    }
+
+    /// Keys for both directions of a finished handshake
+    pub fn split(mut self) -> Result<(Encryptor, Decryptor), Error> {
+        if !self.is_finished() {
+            return Err(Error::new("The handshake is not finished"));
+        }
+        //The transport of snow has no room for key epochs, only the keys are taken from it
+        let (initiator, responder) = self.state.dangerously_get_raw_split();
+        let (sending, receiving) = if self.state.is_initiator() {
+            (initiator, responder)
+        } else {
+            (responder, initiator)
+        };
+        Ok((Encryptor::new(sending), Decryptor::new(receiving)))
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+fn cipher(key: &[u8; KEY_SIZE]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// REKEY function of the Noise specification
+fn next_key(key: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    let payload = Payload {
+        msg: &[0; KEY_SIZE],
+        aad: &[],
+    };
+    let encrypted = cipher(key).encrypt(&nonce(u64::MAX), payload).unwrap();
+    encrypted[..KEY_SIZE].try_into().unwrap()
+}
+
+/// Next key with `secret` mixed in
+fn next_key_with(key: &[u8; KEY_SIZE], secret: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(next_key(key));
+    hasher.update(secret);
+    hasher.finalize().into()
+}
+
+/// Encrypts the datagrams we send
+pub struct Encryptor {
+    key: [u8; KEY_SIZE],
+    epoch: u32,
+    counter: u64,
+    rekeyed_at: Instant,
+}
+
+impl Encryptor {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            key,
+            epoch: 0,
+            counter: 0,
+            rekeyed_at: Instant::now(),
+        }
+    }
+
+    /// Encrypts `datagram`, moving to new keys once the current ones were used enough
+    pub fn seal(&mut self, datagram: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.counter >= REKEY_AFTER_MESSAGES || self.rekeyed_at.elapsed() >= REKEY_AFTER_TIME {
+            self.rekey();
+        }
+        let mut sealed = SECURE_MAGIC.to_vec();
+        sealed.extend(self.epoch.to_le_bytes().iter());
+        sealed.extend(self.counter.to_le_bytes().iter());
+        let payload = Payload {
+            msg: datagram,
+            aad: &sealed,
+        };
+        let mut encrypted = cipher(&self.key).encrypt(&nonce(self.counter), payload)?;
+        self.counter += 1;
+        sealed.append(&mut encrypted);
+        Ok(sealed)
+    }
+
+    /// Moves to the next keys, the peer follows as soon as it sees the new epoch
+    pub fn rekey(&mut self) {
+        let key = next_key(&self.key);
+        self.set_next_key(key);
+    }
+
+    /// Moves to the next keys with `secret` mixed in, once the peer expects it
+    pub fn rekey_with(&mut self, secret: &[u8; KEY_SIZE]) {
+        let key = next_key_with(&self.key, secret);
+        self.set_next_key(key);
+    }
+
+    fn set_next_key(&mut self, key: [u8; KEY_SIZE]) {
+        self.key = key;
+        self.epoch = self.epoch.wrapping_add(1);
+        self.counter = 0;
+        self.rekeyed_at = Instant::now();
+    }
+}
+
+/// Counters received recently, kept in a ring of `REPLAY_WINDOW` bits
+struct ReplayWindow {
+    /// One above the highest counter received
+    next: u64,
+    seen: Bitfield,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            seen: Bitfield::new(),
+        }
+    }
+
+    fn slot(counter: u64) -> usize {
+        (counter % REPLAY_WINDOW) as usize
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        self.next - counter <= REPLAY_WINDOW && !self.seen.get(Self::slot(counter))
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            //Slots of the counters skipped over belonged to counters which left the window
+            let start = self.next.max((counter + 1).saturating_sub(REPLAY_WINDOW));
+            for skipped in start..counter {
+                self.seen.set(Self::slot(skipped), false);
+            }
+            self.next = counter + 1;
+        }
+        self.seen.set(Self::slot(counter), true);
+    }
+}
+
+/// Keys of one epoch on the receiving side
+struct Epoch {
+    number: u32,
+    key: [u8; KEY_SIZE],
+    window: ReplayWindow,
+}
+
+impl Epoch {
+    fn new(number: u32, key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            number,
+            key,
+            window: ReplayWindow::new(),
+        }
+    }
+
+    fn open(&mut self, counter: u64, header: &[u8], encrypted: &[u8]) -> Option<Vec<u8>> {
+        if !self.window.is_fresh(counter) {
+            return None;
+        }
+        let payload = Payload {
+            msg: encrypted,
+            aad: header,
+        };
+        let datagram = cipher(&self.key).decrypt(&nonce(counter), payload).ok()?;
+        self.window.mark(counter);
+        Some(datagram)
+    }
+}
+
+/// Decrypts the datagrams of the peer
+pub struct Decryptor {
+    current: Epoch,
+    /// Datagrams sent just before a rekey may still arrive
+    previous: Option<Epoch>,
+    /// Secret the peer mixes into one of its next keys
+    secret: Option<[u8; KEY_SIZE]>,
+}
+
+impl Decryptor {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            current: Epoch::new(0, key),
+            previous: None,
+            secret: None,
+        }
+    }
+
+    /// Accepts keys with `secret` mixed in from the next epoch on. Rekeys
+    /// of the peer without the secret are still accepted until it arrives
+    pub fn expect_secret(&mut self, secret: [u8; KEY_SIZE]) {
+        self.secret = Some(secret);
+    }
+
+    /// Decrypts a datagram written by `Encryptor::seal`.
+    /// Forged, replayed and outdated datagrams are dropped
+    pub fn open(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let header = sealed.get(..HEADER_SIZE)?;
+        if !header.starts_with(&SECURE_MAGIC) {
+            return None;
+        }
+        let number = u32::from_le_bytes(header[4..8].try_into().ok()?);
+        let counter = u64::from_le_bytes(header[8..].try_into().ok()?);
+        let encrypted = &sealed[HEADER_SIZE..];
+        if number == self.current.number {
+            self.current.open(counter, header, encrypted)
+        } else if number == self.current.number.wrapping_sub(1) {
+            self.previous.as_mut()?.open(counter, header, encrypted)
+        } else if number == self.current.number.wrapping_add(1) {
+            let mixed = self
+                .secret
+                .map(|secret| next_key_with(&self.current.key, &secret));
+            let keys = mixed.into_iter().chain(Some(next_key(&self.current.key)));
+            //Only authentic datagrams move us to the next keys
+            for key in keys {
+                let mut next = Epoch::new(number, key);
+                if let Some(datagram) = next.open(counter, header, encrypted) {
+                    if Some(key) == mixed {
+                        self.secret = None;
+                    }
+                    self.previous = Some(std::mem::replace(&mut self.current, next));
+                    return Some(datagram);
+                }
+            }
+            None
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    error::Error,
+    networking::{bind_dual_stack, map_to_family, unmap, RECEIVE_BUFFER_SIZE},
+};
+use std::{
+    convert::TryInto,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+/// Fixed value in every STUN message (RFC 5389 section 6)
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_SIZE: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+/// RFC 5780 NAT behavior discovery
+const CHANGE_REQUEST: u16 = 0x0003;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const OTHER_ADDRESS: u16 = 0x802c;
+const CHANGE_PORT: u32 = 0x2;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+/// Attributes below this have to be understood by the receiver
+const COMPREHENSION_OPTIONAL: u16 = 0x8000;
+pub const UNKNOWN_ATTRIBUTE: u16 = 420;
+/// Initial retransmission timeout, doubled after every attempt (RFC 8489 section 6.2.1)
+const QUERY_RTO: Duration = Duration::from_millis(500);
+const MAX_QUERY_ATTEMPTS: u32 = 3;
+
+pub type TransactionId = [u8; 12];
+
+pub fn new_transaction() -> TransactionId {
+    rand::random()
+}
+
+pub struct BindingResponse {
+    pub transaction: TransactionId,
+    /// Our address, as seen by the server
+    pub mapped: SocketAddr,
+    /// Alternate address of servers which support RFC 5780
+    pub other_address: Option<SocketAddr>,
+}
+
+pub struct BindingRequest {
+    pub transaction: TransactionId,
+    /// The answer has to come from the alternate port
+    pub change_port: bool,
+    /// Attributes we have to understand, but don't
+    pub unknown_attributes: Vec<u16>,
+}
+
+/// Error response of a server (RFC 8489 section 14.8)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorCode {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.reason)
+    }
+}
+
+/// Whether the datagram looks like a STUN message
+pub fn is_stun(datagram: &[u8]) -> bool {
+    datagram.len() >= HEADER_SIZE
+        //The two most significant bits are always zero
+        && datagram[0] & 0xc0 == 0
+        && u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]) == MAGIC_COOKIE
+        && u16::from_be_bytes([datagram[2], datagram[3]]) as usize + HEADER_SIZE == datagram.len()
+}
+
+fn encode(kind: u16, transaction: TransactionId, attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (attribute, value) in attributes.iter() {
+        body.extend(attribute.to_be_bytes().iter());
+        body.extend((value.len() as u16).to_be_bytes().iter());
+        body.extend(value.iter());
+        //Attributes are padded to a multiple of four bytes
+        body.resize((body.len() + 3) & !3, 0);
+    }
+    let mut buf = Vec::with_capacity(HEADER_SIZE + body.len());
+    buf.extend(kind.to_be_bytes().iter());
+    buf.extend((body.len() as u16).to_be_bytes().iter());
+    buf.extend(MAGIC_COOKIE.to_be_bytes().iter());
+    buf.extend(transaction.iter());
+    buf.extend(body);
+    buf
+}
+
+/// Splits the attributes of a STUN message, `None` if they are malformed
+fn attributes(datagram: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut attributes = Vec::new();
+    let mut rest = &datagram[HEADER_SIZE..];
+    while rest.len() >= 4 {
+        let kind = u16::from_be_bytes([rest[0], rest[1]]);
+        let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        attributes.push((kind, rest.get(4..4 + length)?));
+        let padded = (4 + length + 3) & !3;
+        rest = rest.get(padded..).unwrap_or(&[]);
+    }
+    Some(attributes)
+}
+
+pub fn binding_request(transaction: TransactionId) -> Vec<u8> {
+    encode(BINDING_REQUEST, transaction, &[])
+}
+
+/// Binding request which asks the server to answer from its alternate port
+pub fn change_port_request(transaction: TransactionId) -> Vec<u8> {
+    let flags = CHANGE_PORT.to_be_bytes().to_vec();
+    encode(BINDING_REQUEST, transaction, &[(CHANGE_REQUEST, flags)])
+}
+
+/// Parses a binding request
+pub fn parse_binding_request(datagram: &[u8]) -> Option<BindingRequest> {
+    if !is_stun(datagram) || u16::from_be_bytes([datagram[0], datagram[1]]) != BINDING_REQUEST {
+        return None;
+    }
+    let mut request = BindingRequest {
+        transaction: datagram[8..HEADER_SIZE].try_into().ok()?,
+        change_port: false,
+        unknown_attributes: Vec::new(),
+    };
+    for (kind, value) in attributes(datagram)? {
+        match kind {
+            CHANGE_REQUEST if value.len() == 4 => {
+                request.change_port = value[3] as u32 & CHANGE_PORT != 0;
+            }
+            kind if kind < COMPREHENSION_OPTIONAL => request.unknown_attributes.push(kind),
+            _ => {}
+        }
+    }
+    Some(request)
+}
+
+pub fn binding_response(
+    transaction: TransactionId,
+    mapped: SocketAddr,
+    other_address: Option<SocketAddr>,
+) -> Vec<u8> {
+    let mut mapped = write_address(mapped);
+    xor(&mut mapped, &transaction);
+    let mut attributes = vec![(XOR_MAPPED_ADDRESS, mapped)];
+    if let Some(address) = other_address {
+        attributes.push((OTHER_ADDRESS, write_address(address)));
+    }
+    encode(BINDING_SUCCESS, transaction, &attributes)
+}
+
+pub fn error_response(transaction: TransactionId, error: &ErrorCode) -> Vec<u8> {
+    let mut value = vec![0, 0, (error.code / 100) as u8, (error.code % 100) as u8];
+    value.extend(error.reason.as_bytes());
+    encode(BINDING_ERROR, transaction, &[(ERROR_CODE, value)])
+}
+
+/// Parses a binding error response. Returns its transaction and the error
+pub fn parse_error_response(datagram: &[u8]) -> Option<(TransactionId, ErrorCode)> {
+    if !is_stun(datagram) || u16::from_be_bytes([datagram[0], datagram[1]]) != BINDING_ERROR {
+        return None;
+    }
+    let transaction: TransactionId = datagram[8..HEADER_SIZE].try_into().ok()?;
+    let (_, value) = attributes(datagram)?
+        .into_iter()
+        .find(|(kind, value)| *kind == ERROR_CODE && value.len() >= 4)?;
+    let error = ErrorCode {
+        code: (value[2] & 0x7) as u16 * 100 + value[3] as u16,
+        reason: String::from_utf8_lossy(&value[4..]).into_owned(),
+    };
+    Some((transaction, error))
+}
+
+/// Parses a binding success response
+pub fn parse_binding_response(datagram: &[u8]) -> Option<BindingResponse> {
+    if !is_stun(datagram) || u16::from_be_bytes([datagram[0], datagram[1]]) != BINDING_SUCCESS {
+        return None;
+    }
+    let transaction: TransactionId = datagram[8..HEADER_SIZE].try_into().ok()?;
+    let (mut mapped, mut xor_mapped, mut other_address) = (None, None, None);
+    for (kind, value) in attributes(datagram)? {
+        match kind {
+            MAPPED_ADDRESS => mapped = read_address(value),
+            XOR_MAPPED_ADDRESS => {
+                let mut value = value.to_vec();
+                xor(&mut value, &transaction);
+                xor_mapped = read_address(&value);
+            }
+            OTHER_ADDRESS => other_address = read_address(value),
+            _ => {}
+        }
+    }
+    Some(BindingResponse {
+        transaction,
+        //Preferred, some NATs rewrite addresses they find in packets
+        mapped: xor_mapped.or(mapped)?,
+        other_address,
+    })
+}
+
+fn read_address(value: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let ip = match (value[1], value.len()) {
+        (FAMILY_IPV4, 8) => IpAddr::V4(Ipv4Addr::new(value[4], value[5], value[6], value[7])),
+        (FAMILY_IPV6, 20) => {
+            let octets: [u8; 16] = value[4..20].try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn write_address(address: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0];
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            value.extend(address.port().to_be_bytes().iter());
+            value.extend(ip.octets().iter());
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            value.extend(address.port().to_be_bytes().iter());
+            value.extend(ip.octets().iter());
+        }
+    }
+    value
+}
+
+/// Converts between MAPPED-ADDRESS and XOR-MAPPED-ADDRESS values. The port is XORed
+/// with the cookie, the address with the cookie and the transaction
+fn xor(value: &mut [u8], transaction: &TransactionId) {
+    let mut key = MAGIC_COOKIE.to_be_bytes().to_vec();
+    key.extend(transaction.iter());
+    for (i, byte) in value.iter_mut().enumerate().skip(2) {
+        let index = if i < 4 { i - 2 } else { i - 4 };
+        if let Some(k) = key.get(index) {
+            *byte ^= k;
+        }
+    }
+}
+
+/// Asks `server` for the address it sees `socket` as. With `change_port` the server is asked
+/// to answer from its alternate port, which only passes NATs without port-dependent filtering.
+/// Lost requests are retransmitted, a timeout error is returned if the server never answers
+pub async fn query(
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    change_port: bool,
+) -> Result<BindingResponse, Error> {
+    let local_addr = socket.local_addr()?;
+    let server = unmap(server);
+    let transaction = new_transaction();
+    let request = if change_port {
+        change_port_request(transaction)
+    } else {
+        binding_request(transaction)
+    };
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    let mut rto = QUERY_RTO;
+    for _ in 0..MAX_QUERY_ATTEMPTS {
+        socket
+            .send_to(&request, &map_to_family(server, local_addr))
+            .await?;
+        let answer = tokio::time::timeout(rto, async {
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await?;
+                let from = unmap(from);
+                //Answers to port changes come from the alternate port of the server
+                if from.ip() != server.ip() {
+                    continue;
+                }
+                let datagram = &buf[..size];
+                if let Some((answered, error)) = parse_error_response(datagram) {
+                    if answered == transaction {
+                        let message = format!("STUN server refused the request: {}", error);
+                        return Err(Error::new(&message));
+                    }
+                }
+                match parse_binding_response(datagram) {
+                    Some(response) if response.transaction == transaction => {
+                        if change_port && from == server {
+                            return Err(Error::new("STUN server ignored the port change"));
+                        }
+                        return Ok(response);
+                    }
+                    _ => continue,
+                }
+            }
+        })
+        .await;
+        if let Ok(result) = answer {
+            return result;
+        }
+        rto *= 2;
+    }
+    Err(Error::timeout("STUN server did not answer"))
+}
+
+/// Asks the servers in turn until one of them answers. Returns the answer and the server
+pub async fn query_any(
+    socket: &mut UdpSocket,
+    servers: &[SocketAddr],
+) -> Result<(BindingResponse, SocketAddr), Error> {
+    let mut last_error = Error::new("No STUN server");
+    for &server in servers.iter() {
+        match query(socket, server, false).await {
+            Ok(response) => return Ok((response, server)),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// External addresses of `socket`, one per address family. Returns the addresses
+/// and the servers which answered, those have to be asked again to keep the NAT mappings alive
+pub async fn external_addresses(
+    socket: &mut UdpSocket,
+    servers: &[SocketAddr],
+) -> Vec<(SocketAddr, SocketAddr)> {
+    let local = match socket.local_addr() {
+        Ok(local) => local,
+        Err(_) => return Vec::new(),
+    };
+    let mut addresses = Vec::new();
+    for &ipv6 in [false, true].iter() {
+        //IPv4 sockets can't reach IPv6 servers
+        let family: Vec<SocketAddr> = servers
+            .iter()
+            .map(|server| unmap(*server))
+            .filter(|server| server.is_ipv6() == ipv6 && (local.is_ipv6() || !ipv6))
+            .collect();
+        if family.is_empty() {
+            continue;
+        }
+        if let Ok((response, server)) = query_any(socket, &family).await {
+            addresses.push((unmap(response.mapped), server));
+        }
+    }
+    addresses
+}
+
+/// Minimal STUN server, answers binding requests. With an alternate port
+/// it also answers RFC 5780 port change requests, which NAT detection needs
+pub struct StunServer {
+    socket: UdpSocket,
+    alternate: Option<UdpSocket>,
+}
+
+impl StunServer {
+    pub async fn bind(listen: SocketAddr, alternate_port: Option<u16>) -> Result<Self, Error> {
+        let socket = UdpSocket::from_std(bind_dual_stack(listen)?)?;
+        let alternate = match alternate_port {
+            Some(port) => {
+                let address = SocketAddr::new(listen.ip(), port);
+                Some(UdpSocket::from_std(bind_dual_stack(address)?)?)
+            }
+            None => None,
+        };
+        Ok(Self { socket, alternate })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn run(mut self) -> Result<(), Error> {
+        let other_address = match &self.alternate {
+            Some(alternate) => Some(alternate.local_addr()?),
+            None => None,
+        };
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        loop {
+            let (size, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            let request = match parse_binding_request(&buf[..size]) {
+                Some(request) => request,
+                None => continue,
+            };
+            let transaction = request.transaction;
+            let refuse = |reason: &str| ErrorCode {
+                code: UNKNOWN_ATTRIBUTE,
+                reason: reason.to_string(),
+            };
+            let (response, socket) = match (request.change_port, self.alternate.as_mut()) {
+                _ if !request.unknown_attributes.is_empty() => {
+                    let error = refuse("Unknown attribute");
+                    (error_response(transaction, &error), &mut self.socket)
+                }
+                (true, Some(alternate)) => {
+                    let response = binding_response(transaction, unmap(client), other_address);
+                    (response, alternate)
+                }
+                //RFC 5780 section 6.1: servers without an alternate address refuse port changes
+                (true, None) => {
+                    let error = refuse("No alternate port");
+                    (error_response(transaction, &error), &mut self.socket)
+                }
+                (false, _) => {
+                    let response = binding_response(transaction, unmap(client), other_address);
+                    (response, &mut self.socket)
+                }
+            };
+            socket.send_to(&response, &client).await.ok();
+        }
+    }
+}
@@ -1,17 +1,312 @@
 #[cfg(test)]
 mod tests {
-    use crate::{bitfield::Bitfield, obfuscator::AddressInfo};
-    use std::net::SocketAddrV4;
+    use crate::{
+        bitfield::Bitfield,
+        candidate::{self, Candidate, CandidateKind},
+        congestion,
+        demux::{self, Packet},
+        destination::{self, Collision, Destination},
+        discovery,
+        error::ErrorKind,
+        identity::{self, Identity, KnownPeers, Trust},
+        integrity::{self, HASH_SIZE},
+        manifest::{EntryKind, Manifest, ManifestEntry},
+        merkle::{self, BlockHashes, BLOCK_SIZE},
+        message::{
+            self, BlockHashesMessage, FileTransferAcceptMessage, FileTransferRequestMessage,
+            GoodbyeMessage, ManifestMessage, Message, Messages, PingMessage, ReliableMessage,
+            RendezvousClaimMessage, RendezvousRegisterMessage, SegmentMessage, SelectiveAckMessage,
+            MAGIC,
+        },
+        mtu::{MtuSearch, BASE_PLPMTU, MAX_PLPMTU, MAX_PROBES},
+        nat::{self, Filtering, Mapping, NatBehavior, PortAllocation},
+        networking::{ConnectionState, ConnectivityCheck, NetworkHandler, RttEstimator},
+        obfuscator::{AddressInfo, Session, CODE_LIFETIME, CODE_VERSION},
+        pake::{self, Pake},
+        portmap::{Method, PortMapper},
+        receiver,
+        relay::{self, RelayServer},
+        rendezvous::{self, Code, RendezvousServer},
+        resume::{self, TransferState},
+        secure::{self, Handshake},
+        stun::{self, StunServer},
+        transmitter, words,
+    };
+    use std::{
+        collections::{HashSet, VecDeque},
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
 
     #[test]
     fn bytes_to_string() {
         let addr = SocketAddrV4::new("192.168.0.111".parse().unwrap(), 6543);
         println!("Address: {}", addr);
-        let info = AddressInfo::new(addr);
+        let candidates = vec![
+            Candidate::new(CandidateKind::Host, SocketAddr::V4(addr), 0),
+            Candidate::new(
+                CandidateKind::ServerReflexive,
+                "[2001:db8::1:2]:6543".parse().unwrap(),
+                1,
+            ),
+        ];
+        let info = AddressInfo::new(candidates.clone());
         let code = info.to_string();
         println!("Code: {}", code);
         let info: AddressInfo = code.parse().unwrap();
-        assert_eq!(info.address, addr);
+        assert_eq!(info.candidates, candidates);
+
+        //Codes of older versions have no session which could be verified
+        let legacy = bs58::encode([192, 168, 0, 111, 0x8f, 0x19]).into_string();
+        let error = legacy.parse::<AddressInfo>().err().unwrap();
+        assert!(error.to_string().contains("older version"));
+    }
+
+    #[tokio::test]
+    async fn code_sessions() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let candidates = vec![Candidate::new(
+            CandidateKind::Host,
+            "192.168.0.111:6543".parse().unwrap(),
+            0,
+        )];
+        let session = Session::new();
+        let code = AddressInfo::with_session(candidates.clone(), session).to_string();
+        let info: AddressInfo = code.parse().unwrap();
+        assert_eq!(info.session, session);
+        assert_eq!(info.secret, None);
+        assert_ne!(Session::new().nonce, session.nonce);
+        let secret = pake::new_secret();
+        let code = AddressInfo::with_session(candidates.clone(), session).with_secret(secret);
+        let info: AddressInfo = code.to_words().parse().unwrap();
+        assert_eq!(info.secret, Some(secret));
+        assert_eq!(info.candidates[0].address, candidates[0].address);
+
+        let refused = |session: Session| {
+            let code = AddressInfo::with_session(candidates.clone(), session).to_string();
+            code.parse::<AddressInfo>().err().unwrap().to_string()
+        };
+        let expired = Session {
+            created: now - CODE_LIFETIME.as_secs() as u32 - 60,
+            ..session
+        };
+        assert!(expired.is_expired());
+        assert!(refused(expired).contains("expired"));
+        let future = Session {
+            created: now + 3600,
+            ..session
+        };
+        assert!(refused(future).contains("future"));
+        let mut bytes = AddressInfo::with_session(candidates.clone(), session).to_bytes();
+        bytes[1] = CODE_VERSION + 1;
+        let error = AddressInfo::from_bytes(bytes).err().unwrap();
+        assert!(error.to_string().contains("newer version"));
+
+        //Both sides have to prove that they got the current code of the other one
+        let a = NetworkHandler::new(
+            "127.0.0.1:40135".parse().unwrap(),
+            "127.0.0.1:40136".parse().unwrap(),
+        );
+        let b = NetworkHandler::new(
+            "127.0.0.1:40136".parse().unwrap(),
+            "127.0.0.1:40135".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let (ours, theirs) = (Session::new(), Session::new());
+        let (mut receiver_a, mut receiver_b) = (a.subscribe(), b.subscribe());
+        let (verified_a, verified_b) = tokio::join!(
+            a.verify_session(&mut receiver_a, &ours, &theirs, true),
+            b.verify_session(&mut receiver_b, &theirs, &ours, false)
+        );
+        verified_a.unwrap();
+        verified_b.unwrap();
+        //A code of an earlier run is refused
+        let stale = Session::new();
+        let (_, verified_b) = tokio::join!(
+            a.verify_session(&mut receiver_a, &ours, &stale, true),
+            b.verify_session(&mut receiver_b, &theirs, &ours, false)
+        );
+        assert!(verified_b.unwrap_err().to_string().contains("current code"));
+    }
+
+    #[test]
+    fn candidate_priority() {
+        let host = Candidate::new(CandidateKind::Host, "10.0.0.2:5000".parse().unwrap(), 0);
+        let reflexive = Candidate::new(
+            CandidateKind::ServerReflexive,
+            "1.2.3.4:5000".parse().unwrap(),
+            1,
+        );
+        assert_eq!(host.priority, (126 << 24) + (65535 << 8) + 255);
+        assert!(host.priority > reflexive.priority);
+        //Pair priority only depends on which side is controlling
+        assert_eq!(
+            candidate::pair_priority(host.priority, reflexive.priority),
+            (1 << 32) * reflexive.priority as u64 + 2 * host.priority as u64 + 1
+        );
+        assert_eq!(
+            candidate::pair_priority(reflexive.priority, host.priority),
+            (1 << 32) * reflexive.priority as u64 + 2 * host.priority as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn connectivity_check() {
+        let mut controlling = tokio::net::UdpSocket::bind("127.0.0.1:40105")
+            .await
+            .unwrap();
+        let mut controlled = tokio::net::UdpSocket::bind("127.0.0.1:40106")
+            .await
+            .unwrap();
+        let candidates = |port: u16| {
+            vec![
+                //Unreachable candidates must not prevent the working one from being chosen
+                Candidate::new(CandidateKind::Host, "127.0.0.1:40109".parse().unwrap(), 0),
+                Candidate::new(
+                    CandidateKind::ServerReflexive,
+                    SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+                    1,
+                ),
+            ]
+        };
+        let (a, b) = (candidates(40105), candidates(40106));
+        let check_a = ConnectivityCheck::new(&mut controlling, &a, &b, true, None).unwrap();
+        let check_b = ConnectivityCheck::new(&mut controlled, &b, &a, true, None).unwrap();
+        //Both claim to be controlling, the tiebreaker has to resolve it
+        let (a, b) = futures::join!(check_a.run(), check_b.run());
+        assert_eq!(a.unwrap().remote, "127.0.0.1:40106".parse().unwrap());
+        assert_eq!(b.unwrap().remote, "127.0.0.1:40105".parse().unwrap());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn relay_fallback() {
+        let server = RelayServer::bind("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let mut direct = tokio::net::UdpSocket::bind("127.0.0.1:40107")
+            .await
+            .unwrap();
+        let mut relayed = tokio::net::UdpSocket::bind("127.0.0.1:40108")
+            .await
+            .unwrap();
+        let relayed_addr = relay::allocate_address(&mut relayed, server_addr)
+            .await
+            .unwrap();
+        //Neither side advertises an address the other can reach directly
+        let a = vec![Candidate::new(
+            CandidateKind::Host,
+            "127.0.0.1:40109".parse().unwrap(),
+            0,
+        )];
+        let b = vec![Candidate::new(CandidateKind::Relayed, relayed_addr, 0)];
+        let check_a = ConnectivityCheck::new(&mut direct, &a, &b, true, None).unwrap();
+        let check_b =
+            ConnectivityCheck::new(&mut relayed, &b, &a, false, Some(server_addr)).unwrap();
+        let (path_a, path_b) = futures::join!(check_a.run(), check_b.run());
+        let (path_a, path_b) = (path_a.unwrap(), path_b.unwrap());
+        assert_eq!(path_a.remote, relayed_addr);
+        assert_eq!(path_a.relay, None);
+        assert_eq!(path_b.remote, "127.0.0.1:40107".parse().unwrap());
+        assert_eq!(path_b.relay, Some(server_addr));
+        drop(direct);
+        drop(relayed);
+
+        //Reliable messages have to make it through the relay in both directions
+        let handler_a = NetworkHandler::new("127.0.0.1:40107".parse().unwrap(), path_a.remote);
+        let mut handler_b = NetworkHandler::new("127.0.0.1:40108".parse().unwrap(), path_b.remote);
+        handler_b.set_relay(server_addr);
+        handler_a.begin().await.unwrap();
+        handler_b.begin().await.unwrap();
+        let mut receiver = handler_b.subscribe();
+        let goodbye = Messages::Goodbye(GoodbyeMessage {
+            motd: "relayed".to_string(),
+        });
+        handler_a.get_sender().send_reliable(goodbye).await.unwrap();
+        loop {
+            if let Messages::Goodbye(msg) = receiver.recv().await.unwrap() {
+                assert_eq!(msg.motd, "relayed");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_limits() {
+        let server = RelayServer::bind("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        //Malformed requests don't stop the server
+        let mut client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for size in 0..8 {
+            let mut datagram = MAGIC.to_vec();
+            datagram.extend(21u32.to_le_bytes().iter());
+            datagram.extend(vec![0xff; size]);
+            client.send_to(&datagram, &server_addr).await.unwrap();
+        }
+        client
+            .send_to(&nested_datagram(), &server_addr)
+            .await
+            .unwrap();
+        let mut clients = vec![client];
+        for _ in 1..relay::MAX_CLIENT_ALLOCATIONS {
+            clients.push(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        for client in clients.iter_mut() {
+            relay::allocate_address(client, server_addr).await.unwrap();
+        }
+        //One address can't take up all of the relay
+        let mut greedy = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(relay::allocate_address(&mut greedy, server_addr)
+            .await
+            .is_err());
+        //Existing allocations can still be refreshed
+        relay::allocate_address(&mut clients[0], server_addr)
+            .await
+            .unwrap();
+
+        //Nothing reaches peers the client did not register
+        let mut peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let client = &mut clients[1];
+        let stray = relay::wrap(peer_addr, vec![1; 1000]);
+        client.send_to(&stray, &server_addr).await.unwrap();
+        let mut buf = vec![0; 1500];
+        let timeout = Duration::from_millis(200);
+        assert!(tokio::time::timeout(timeout, peer.recv_from(&mut buf))
+            .await
+            .is_err());
+        //Registered peers get the datagrams, until the client is too fast
+        client
+            .send_to(&relay::permit(peer_addr), &server_addr)
+            .await
+            .unwrap();
+        let count = 2 * relay::RELAY_BURST as usize / 1000;
+        for _ in 0..count {
+            client.send_to(&stray, &server_addr).await.unwrap();
+        }
+        let mut received = 0;
+        while tokio::time::timeout(timeout, peer.recv_from(&mut buf))
+            .await
+            .is_ok()
+        {
+            received += 1;
+        }
+        assert!(received > 0);
+        assert!(received < count * 3 / 4, "{} of {}", received, count);
     }
 
     #[test]
@@ -34,14 +329,1694 @@ mod tests {
         }
 
         let iter = bitfield.iter();
-        assert!(iter.take(2).all(|a| a == true));
+        assert!(iter.take(2).all(|a| a));
         let iter = bitfield.iter();
-        assert!(iter.skip(2).take(6).all(|a| a == false));
+        assert!(iter.skip(2).take(6).all(|a| !a));
         let iter = bitfield.iter();
-        assert!(iter.skip(2).take(100).all(|a| a == false));
+        assert!(iter.skip(2).take(100).all(|a| !a));
 
         bitfield.set(145, true);
-        assert_eq!(bitfield.get(145), true);
+        assert!(bitfield.get(145));
         assert_eq!(bitfield.get_bytes()[145 / 8], 0b00000010);
     }
+
+    /// Sends chunks through a simulated bottleneck link for `duration` milliseconds.
+    /// Returns the number of delivered and lost chunks
+    fn simulate_link(name: &str, duration: u64) -> (u64, u64) {
+        const RATE: usize = 2; //Chunks per millisecond
+        const BUFFER: usize = 64;
+        const BASE_RTT: u64 = 20;
+        const MAX_BURST: usize = 1000;
+        let mut controller = congestion::from_name(name).unwrap();
+        let mut queue: VecDeque<u64> = VecDeque::new();
+        let mut acks: VecDeque<(u64, u64)> = VecDeque::new();
+        let mut losses: VecDeque<u64> = VecDeque::new();
+        let (mut in_flight, mut delivered, mut lost) = (0u64, 0u64, 0u64);
+        let mut next_send = 0.0f64;
+        let mut recovery_until = 0;
+        for now in 0..duration {
+            while acks.front().is_some_and(|&(at, _)| at <= now) {
+                let (_, sent) = acks.pop_front().unwrap();
+                in_flight -= 1;
+                controller.on_ack(1, Duration::from_millis(now - sent));
+            }
+            while losses.front().is_some_and(|&at| at <= now) {
+                losses.pop_front();
+                in_flight -= 1;
+                if now >= recovery_until {
+                    controller.on_loss(1);
+                    recovery_until = now + BASE_RTT;
+                }
+            }
+            let mut burst = 0;
+            while in_flight < controller.window() as u64
+                && next_send < (now + 1) as f64
+                && burst < MAX_BURST
+            {
+                burst += 1;
+                in_flight += 1;
+                next_send =
+                    next_send.max(now as f64) + controller.pacing_interval().as_secs_f64() * 1000.0;
+                if queue.len() < BUFFER {
+                    queue.push_back(now);
+                } else {
+                    lost += 1;
+                    losses.push_back(now + BASE_RTT);
+                }
+            }
+            for sent in queue.drain(..RATE.min(queue.len())) {
+                delivered += 1;
+                acks.push_back((now + BASE_RTT, sent));
+            }
+        }
+        (delivered, lost)
+    }
+
+    #[test]
+    fn congestion_control() {
+        let capacity = 2 * 5000;
+        let (_, unlimited_lost) = simulate_link("none", 5000);
+        println!("none: {} lost", unlimited_lost);
+        for name in ["aimd", "vegas"].iter() {
+            let (delivered, lost) = simulate_link(name, 5000);
+            println!("{}: {} delivered, {} lost", name, delivered, lost);
+            assert!(delivered > capacity * 7 / 10);
+            assert!(lost * 20 < unlimited_lost);
+        }
+    }
+
+    #[test]
+    fn integrity() {
+        assert_eq!(integrity::crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            integrity::to_hex(&integrity::hash_reader(&mut &b"abc"[..]).unwrap()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        //Corrupted payloads are noticed
+        let datagram = Messages::Segment(SegmentMessage::new(7, vec![1, 2, 3])).to_datagram();
+        let mut corrupted = datagram.clone();
+        *corrupted.last_mut().unwrap() ^= 0x10;
+        for (datagram, intact) in [(datagram, true), (corrupted, false)] {
+            match Messages::from_datagram(&datagram) {
+                Some(Messages::Segment(msg)) => assert_eq!(msg.is_intact(), intact),
+                _ => panic!("Not a segment"),
+            }
+        }
+
+        let hash = integrity::hash_reader(&mut &b"file"[..]).unwrap();
+        let msg = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize: 4,
+            version: 3,
+            chunk_size: 1024,
+            hash,
+            root: [3; HASH_SIZE],
+        };
+        let msg = FileTransferRequestMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!(msg.hash, hash);
+
+        //A request without the hash of the file is refused
+        let data = msg.get_data();
+        let truncated = data[..data.len() - HASH_SIZE].to_vec();
+        assert!(FileTransferRequestMessage::from_bytes(truncated).is_none());
+    }
+
+    /// Reliable messages nested as deep as a single datagram allows
+    fn nested_datagram() -> Vec<u8> {
+        let mut datagram = MAGIC.to_vec();
+        for _ in 0..8000 {
+            datagram.extend([0u8; 8].iter());
+        }
+        datagram.extend(2u32.to_le_bytes().iter());
+        datagram
+    }
+
+    #[test]
+    fn truncated_datagrams() {
+        //Every message type, with payloads which claim more data than there is
+        for id in 0..=41u32 {
+            for fill in [0x00, 0x01, 0x20, 0xff] {
+                for size in 0..64 {
+                    let mut datagram = MAGIC.to_vec();
+                    datagram.extend(id.to_le_bytes().iter());
+                    datagram.extend(vec![fill; size]);
+                    Messages::from_datagram(&datagram);
+                    //The same, wrapped into a reliable message
+                    let mut datagram = MAGIC.to_vec();
+                    datagram.extend(0u32.to_le_bytes().iter());
+                    datagram.extend(7u32.to_le_bytes().iter());
+                    datagram.extend(id.to_le_bytes().iter());
+                    datagram.extend(vec![fill; size]);
+                    Messages::from_datagram(&datagram);
+                }
+            }
+        }
+        //Reliable messages inside reliable messages are refused, however deep they go
+        let mut datagram = MAGIC.to_vec();
+        for _ in 0..100_000 {
+            datagram.extend([0u8; 8].iter());
+        }
+        datagram.extend(2u32.to_le_bytes().iter());
+        assert!(Messages::from_datagram(&datagram).is_none());
+        let ping = Messages::Ping(PingMessage { sequence: 3 });
+        let once = Messages::Reliable(ReliableMessage {
+            packet_index: 1,
+            message: Box::new(ping),
+        });
+        assert!(Messages::from_datagram(&once.to_datagram()).is_some());
+        let twice = Messages::Reliable(ReliableMessage {
+            packet_index: 2,
+            message: Box::new(once),
+        });
+        assert!(Messages::from_datagram(&twice.to_datagram()).is_none());
+        //Valid messages cut off at every byte
+        let messages = vec![
+            Messages::Goodbye(GoodbyeMessage {
+                motd: "bye".to_string(),
+            }),
+            Messages::Manifest(ManifestMessage {
+                total: 1,
+                start: 0,
+                entries: vec![ManifestEntry {
+                    path: "a/b".to_string(),
+                    kind: EntryKind::File,
+                    size: 3,
+                }],
+            }),
+            Messages::SelectiveAck(SelectiveAckMessage {
+                cumulative: 3,
+                ranges: vec![(5, 7)],
+            }),
+        ];
+        for msg in messages {
+            let datagram = msg.to_datagram();
+            for size in 0..datagram.len() {
+                Messages::from_datagram(&datagram[..size]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn safe_paths() {
+        for name in [
+            "file.txt",
+            "dir/sub/file",
+            "name with spaces.tar.gz",
+            "..hidden",
+        ] {
+            assert!(destination::sanitize(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "../.bashrc",
+            "/etc/passwd",
+            "a/../../b",
+            "a//b",
+            "./a",
+            "C:\\Windows",
+            "a\\..\\b",
+            "con.txt",
+            "dir/NUL",
+            "trailing.",
+            "trailing ",
+            "new\nline",
+        ] {
+            assert!(destination::sanitize(name).is_err(), "{:?}", name);
+        }
+
+        let root = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("file.txt"), b"old").unwrap();
+        std::fs::write(root.join("file (1).txt"), b"old").unwrap();
+        for (collision, expected) in [
+            (Collision::Rename, Some(root.join("file (2).txt"))),
+            (Collision::Overwrite, Some(root.join("file.txt"))),
+            (Collision::Skip, None),
+        ] {
+            let destination = Destination::new(root.clone(), collision);
+            assert_eq!(destination.choose("file.txt").await.unwrap(), expected);
+        }
+        let destination = Destination::new(root.clone(), Collision::Skip);
+        assert_eq!(
+            destination.choose("dir/new").await.unwrap(),
+            Some(root.join("dir/new"))
+        );
+        assert_eq!(
+            destination::part_path(&root.join("dir/new")),
+            root.join("dir/new.part")
+        );
+        #[cfg(unix)]
+        {
+            //A link placed in the download directory must not lead out of it
+            std::os::unix::fs::symlink("/tmp", root.join("link")).unwrap();
+            assert!(destination.choose("link/file").await.is_err());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn manifest() {
+        let root = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        let tree = root.join("tree");
+        std::fs::create_dir_all(tree.join("sub/empty")).unwrap();
+        std::fs::write(tree.join("b.txt"), b"hello").unwrap();
+        std::fs::write(tree.join("sub/a.bin"), vec![7; 1000]).unwrap();
+        std::fs::write(root.join("single"), b"x").unwrap();
+        let manifest = Manifest::from_paths(&[&tree, &root.join("single")]).unwrap();
+        let paths: Vec<(&str, EntryKind)> = manifest
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.kind))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("tree", EntryKind::Directory),
+                ("tree/b.txt", EntryKind::File),
+                ("tree/sub", EntryKind::Directory),
+                ("tree/sub/a.bin", EntryKind::File),
+                ("tree/sub/empty", EntryKind::Directory),
+                ("single", EntryKind::File),
+            ]
+        );
+        assert_eq!(manifest.file_count(), 3);
+        assert_eq!(manifest.total_size(), 1006);
+        let (_, source) = manifest.files().nth(1).unwrap();
+        assert_eq!(source, tree.join("sub/a.bin"));
+        //The same name twice would end up in the same place
+        assert!(Manifest::from_paths(&[&tree, &tree]).is_err());
+
+        let msg = ManifestMessage {
+            total: 6,
+            start: 2,
+            entries: manifest.entries[2..4].to_vec(),
+        };
+        let msg = ManifestMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!((msg.total, msg.start), (6, 2));
+        assert_eq!(msg.entries, manifest.entries[2..4]);
+
+        //The receiver only knows the entries, and lists the files it never got
+        let received = Manifest::from_entries(manifest.entries.clone());
+        assert_eq!(received.file_count(), 3);
+        let handled: HashSet<String> = vec!["tree/b.txt".to_string()].into_iter().collect();
+        assert_eq!(
+            receiver::not_sent(&received, &handled),
+            vec!["tree/sub/a.bin", "single"]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn merkle_tree() {
+        assert_eq!(merkle::block_count(0), 1);
+        assert_eq!(merkle::block_count(BLOCK_SIZE), 1);
+        assert_eq!(merkle::block_count(BLOCK_SIZE + 1), 2);
+        let data: Vec<u8> = (0..BLOCK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let (blocks, hash) = BlockHashes::from_reader(&mut &data[..]).unwrap();
+        assert_eq!(blocks.leaves.len(), 3);
+        assert_eq!(hash, integrity::hash_reader(&mut &data[..]).unwrap());
+        let second = &data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize];
+        assert!(blocks.verify(1, second));
+        assert!(!blocks.verify(0, second));
+        //Every leaf is covered by the root
+        let root = blocks.root();
+        for i in 0..blocks.leaves.len() {
+            let mut changed = blocks.leaves.clone();
+            changed[i][0] ^= 1;
+            assert_ne!(merkle::root(&changed), root);
+        }
+        let (empty, _) = BlockHashes::from_reader(&mut &[][..]).unwrap();
+        assert_eq!(empty.leaves, vec![merkle::leaf_hash(&[])]);
+
+        let msg = BlockHashesMessage {
+            start: 24,
+            hashes: blocks.leaves.clone(),
+        };
+        let msg = BlockHashesMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!(msg.start, 24);
+        assert_eq!(msg.hashes, blocks.leaves);
+    }
+
+    /// Flips a byte the first time it is read, like a bad disk or memory
+    struct FlakyReader {
+        inner: std::io::Cursor<Vec<u8>>,
+        corrupt_at: Option<u64>,
+    }
+
+    impl std::io::Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let position = self.inner.position();
+            let size = std::io::Read::read(&mut self.inner, buf)?;
+            if let Some(at) = self.corrupt_at {
+                if (position..position + size as u64).contains(&at) {
+                    buf[(at - position) as usize] ^= 0xff;
+                    self.corrupt_at = None;
+                }
+            }
+            Ok(size)
+        }
+    }
+
+    impl std::io::Seek for FlakyReader {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[tokio::test]
+    async fn block_verification() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 1000)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect();
+        let (blocks, hash) = BlockHashes::from_reader(&mut &data[..]).unwrap();
+        let chunk_size = 1000;
+        let request = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize: data.len() as u64,
+            version: 3,
+            chunk_size,
+            hash,
+            root: blocks.root(),
+        };
+        let a = NetworkHandler::new(
+            "127.0.0.1:40144".parse().unwrap(),
+            "127.0.0.1:40145".parse().unwrap(),
+        );
+        let b = NetworkHandler::new(
+            "127.0.0.1:40145".parse().unwrap(),
+            "127.0.0.1:40144".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let path = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        //The second block is corrupted on the way, the receiver asks for it again
+        let mut reader = FlakyReader {
+            inner: std::io::Cursor::new(data.clone()),
+            corrupt_at: Some(BLOCK_SIZE + 5),
+        };
+        let mut controller = congestion::from_name("aimd").unwrap();
+        let segment_count = (data.len() as u64).div_ceil(chunk_size as u64) as u32;
+        let mut state = TransferState::new(&path, hash, data.len() as u64);
+        let mut receiver_a = a.subscribe();
+        let mut receiver_b = b.subscribe();
+        let sending = async {
+            transmitter::send_window(
+                &a,
+                a.subscribe(),
+                &mut reader,
+                chunk_size,
+                0..segment_count,
+                controller.as_mut(),
+            )
+            .await
+            .unwrap();
+            loop {
+                if let Messages::TransferSuccessful(_) = a.recv(&mut receiver_a).await.unwrap() {
+                    break;
+                }
+            }
+        };
+        let receiving = receiver::window_loop(
+            &b,
+            &mut file,
+            &request,
+            &blocks,
+            &mut state,
+            &mut receiver_b,
+        );
+        let (_, verified) = tokio::join!(sending, receiving);
+        assert!(verified.unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        state.remove().unwrap();
+        assert!(!resume::state_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumed_transfer() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 500)
+            .map(|i| (i * 13 % 251) as u8)
+            .collect();
+        let (blocks, hash) = BlockHashes::from_reader(&mut &data[..]).unwrap();
+        let filesize = data.len() as u64;
+        let path = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        //An earlier session stopped after two blocks, one of them was damaged on disk since
+        let mut partial = data[..(BLOCK_SIZE * 2) as usize].to_vec();
+        partial[10] ^= 0xff;
+        std::fs::write(&path, &partial).unwrap();
+        let mut state = TransferState::new(&path, hash, filesize);
+        state.complete(0).unwrap();
+        state.complete(1).unwrap();
+        state.complete(3).unwrap();
+        state.save().unwrap();
+        assert!(TransferState::load(&path, [0; 32], filesize).is_none());
+        assert!(TransferState::load(&path, hash, filesize + 1).is_none());
+        //Only the blocks at the start are resumed
+        let mut state = TransferState::load(&path, hash, filesize).unwrap();
+        assert_eq!(state.resume_offset(), BLOCK_SIZE * 2);
+        assert!(!state.blocks.get(3));
+
+        let chunk_size = 1000;
+        let request = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize,
+            version: 3,
+            chunk_size,
+            hash,
+            root: blocks.root(),
+        };
+        let a = NetworkHandler::new(
+            "127.0.0.1:40146".parse().unwrap(),
+            "127.0.0.1:40147".parse().unwrap(),
+        );
+        let b = NetworkHandler::new(
+            "127.0.0.1:40147".parse().unwrap(),
+            "127.0.0.1:40146".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut reader = std::io::Cursor::new(data.clone());
+        let mut controller = congestion::from_name("aimd").unwrap();
+        let first = (state.resume_offset() / chunk_size as u64) as u32;
+        let segment_count = filesize.div_ceil(chunk_size as u64) as u32;
+        let mut receiver_a = a.subscribe();
+        let mut receiver_b = b.subscribe();
+        let sending = async {
+            transmitter::send_window(
+                &a,
+                a.subscribe(),
+                &mut reader,
+                chunk_size,
+                first..segment_count,
+                controller.as_mut(),
+            )
+            .await
+            .unwrap();
+            loop {
+                if let Messages::TransferSuccessful(_) = a.recv(&mut receiver_a).await.unwrap() {
+                    break;
+                }
+            }
+        };
+        let receiving = receiver::window_loop(
+            &b,
+            &mut file,
+            &request,
+            &blocks,
+            &mut state,
+            &mut receiver_b,
+        );
+        let (_, verified) = tokio::join!(sending, receiving);
+        assert!(verified.unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        state.remove().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_file() {
+        let handler = NetworkHandler::new(
+            "127.0.0.1:40148".parse().unwrap(),
+            "127.0.0.1:40149".parse().unwrap(),
+        );
+        let mut receiver = handler.subscribe();
+        //Refused before any memory is set aside for the block hashes
+        let request = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize: u64::MAX,
+            version: 3,
+            chunk_size: 1000,
+            hash: [0; HASH_SIZE],
+            root: [0; HASH_SIZE],
+        };
+        let result = receiver::receive_block_hashes(&handler, &mut receiver, &request).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_segments() {
+        let data = vec![5u8; 2500];
+        let (blocks, hash) = BlockHashes::from_reader(&mut &data[..]).unwrap();
+        let mut request = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize: data.len() as u64,
+            version: 3,
+            chunk_size: 1000,
+            hash,
+            root: blocks.root(),
+        };
+        let a = NetworkHandler::new(
+            "127.0.0.1:40150".parse().unwrap(),
+            "127.0.0.1:40151".parse().unwrap(),
+        );
+        let b = NetworkHandler::new(
+            "127.0.0.1:40151".parse().unwrap(),
+            "127.0.0.1:40150".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let path = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut state = TransferState::new(&path, hash, request.filesize);
+        let mut receiver = b.subscribe();
+
+        //The last segment reaches past the end of the file
+        let msg = SegmentMessage::new(2, vec![5; 1000]);
+        a.get_sender().send(Messages::Segment(msg)).unwrap();
+        let result =
+            receiver::window_loop(&b, &mut file, &request, &blocks, &mut state, &mut receiver)
+                .await;
+        assert_eq!(result.err().unwrap().to_string(), "Invalid segment size");
+        assert_eq!(file.metadata().unwrap().len(), 0);
+
+        //Segment numbers have to fit into 32 bits
+        request.chunk_size = 1;
+        request.filesize = 1 << 33;
+        let result =
+            receiver::window_loop(&b, &mut file, &request, &blocks, &mut state, &mut receiver)
+                .await;
+        assert!(result.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        //Requests of version 1 peers lack the version and chunk size fields, they are refused
+        let mut legacy = Vec::new();
+        legacy.extend(4u32.to_le_bytes().iter());
+        legacy.extend(b"file");
+        legacy.extend(1234u64.to_le_bytes().iter());
+        assert!(FileTransferRequestMessage::from_bytes(legacy).is_none());
+
+        let msg = FileTransferRequestMessage {
+            filename: "file".to_string(),
+            filesize: 1234,
+            version: 3,
+            chunk_size: 1024,
+            hash: [7; HASH_SIZE],
+            root: [3; HASH_SIZE],
+        };
+        let msg = FileTransferRequestMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!(msg.version, 3);
+        assert_eq!(msg.chunk_size, 1024);
+        assert_eq!(msg.hash, [7; HASH_SIZE]);
+
+        //Version 2 peers do not offer to resume, their answer is refused
+        assert!(FileTransferAcceptMessage::from_bytes(2u32.to_le_bytes().to_vec()).is_none());
+        let msg = FileTransferAcceptMessage {
+            version: 3,
+            resume_from: 4096,
+        };
+        let msg = FileTransferAcceptMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!((msg.version, msg.resume_from), (3, 4096));
+
+        let msg = SelectiveAckMessage {
+            cumulative: 10,
+            ranges: vec![(12, 15), (20, 21)],
+        };
+        let msg = SelectiveAckMessage::from_bytes(msg.get_data()).unwrap();
+        assert_eq!(msg.cumulative, 10);
+        assert_eq!(msg.ranges, vec![(12, 15), (20, 21)]);
+    }
+
+    #[test]
+    fn rtt_estimation() {
+        let mut estimator = RttEstimator::new();
+        assert_eq!(estimator.smoothed(), None);
+        assert_eq!(estimator.rto(), Duration::from_secs(1));
+        estimator.update(Duration::from_millis(100));
+        assert_eq!(estimator.smoothed(), Some(Duration::from_millis(100)));
+        assert_eq!(estimator.rto(), Duration::from_millis(300));
+        for _ in 0..50 {
+            estimator.update(Duration::from_millis(100));
+        }
+        //Never goes below the minimum
+        assert_eq!(estimator.rto(), Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn reliable_timeout() {
+        let mut handler = NetworkHandler::new(
+            "127.0.0.1:40101".parse().unwrap(),
+            "127.0.0.1:40102".parse().unwrap(),
+        );
+        handler.set_max_retries(0);
+        handler.begin().await.unwrap();
+        let result = handler
+            .get_sender()
+            .send_reliable(Messages::Ping(PingMessage { sequence: 0 }))
+            .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Timeout);
+    }
+
+    #[tokio::test]
+    async fn connection_state() {
+        let mut handler = NetworkHandler::new(
+            "127.0.0.1:40110".parse().unwrap(),
+            "127.0.0.1:40111".parse().unwrap(),
+        );
+        handler.set_idle_timeout(Duration::from_millis(200));
+        handler.set_failure_timeout(Duration::from_millis(400));
+        let mut states = handler.watch_state();
+        assert_eq!(states.recv().await, Some(ConnectionState::Connecting));
+        handler.begin().await.unwrap();
+
+        //A single ping, then the peer vanishes
+        let mut peer = tokio::net::UdpSocket::bind("127.0.0.1:40111")
+            .await
+            .unwrap();
+        let ping = Messages::Ping(PingMessage { sequence: 1 }).to_datagram();
+        peer.send_to(&ping, "127.0.0.1:40110").await.unwrap();
+        let mut receiver = handler.subscribe();
+        assert!(handler.recv(&mut receiver).await.is_ok());
+        assert_eq!(states.recv().await, Some(ConnectionState::Established));
+        assert_eq!(states.recv().await, Some(ConnectionState::Idle));
+
+        //Waiting for messages has to end once the connection failed
+        let result = handler.recv(&mut receiver).await;
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(ErrorKind::Disconnected)
+        );
+        assert_eq!(handler.state(), ConnectionState::Failed);
+        let result = handler
+            .get_sender()
+            .send(Messages::Ping(PingMessage { sequence: 2 }));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Disconnected);
+    }
+
+    #[test]
+    fn secure_channel() {
+        let mut initiator = Handshake::new(true).unwrap();
+        let mut responder = Handshake::new(false).unwrap();
+        let first = initiator.write().unwrap();
+        responder.read(&first).unwrap();
+        let second = responder.write().unwrap();
+        //A forged message does not break the handshake
+        let mut forged = second.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(initiator.read(&forged).is_err());
+        initiator.read(&second).unwrap();
+        let third = initiator.write().unwrap();
+        assert!(initiator.is_finished());
+        responder.read(&third).unwrap();
+        let (mut sending, mut peer_receiving) = initiator.split().unwrap();
+        let (mut peer_sending, mut receiving) = responder.split().unwrap();
+
+        let sealed = sending.seal(b"hello").unwrap();
+        assert_eq!(sealed.len(), 5 + secure::OVERHEAD);
+        assert_eq!(receiving.open(&sealed).unwrap(), b"hello");
+        assert_eq!(
+            peer_receiving.open(&peer_sending.seal(b"hi").unwrap()),
+            Some(b"hi".to_vec())
+        );
+        //Replays, forgeries and datagrams of the other direction are dropped
+        assert!(receiving.open(&sealed).is_none());
+        let mut forged = sending.seal(b"hello").unwrap();
+        forged[20] ^= 1;
+        assert!(receiving.open(&forged).is_none());
+        assert!(peer_receiving.open(&sending.seal(b"x").unwrap()).is_none());
+        assert!(receiving.open(b"plaintext").is_none());
+        //Reordered datagrams are accepted once
+        let early = sending.seal(b"1").unwrap();
+        let late = sending.seal(b"2").unwrap();
+        assert_eq!(receiving.open(&late).unwrap(), b"2");
+        assert_eq!(receiving.open(&early).unwrap(), b"1");
+        assert!(receiving.open(&early).is_none());
+
+        //Only authentic datagrams move the receiver to the next keys
+        let old = sending.seal(b"old").unwrap();
+        let mut forged = sending.seal(b"forged").unwrap();
+        forged[4] = 1;
+        assert!(receiving.open(&forged).is_none());
+        sending.rekey();
+        let new = sending.seal(b"new").unwrap();
+        assert_eq!(receiving.open(&new).unwrap(), b"new");
+        //Late datagrams of the previous keys still arrive
+        assert_eq!(receiving.open(&old).unwrap(), b"old");
+        //Datagrams far behind the newest one are dropped
+        let stale = sending.seal(b"stale").unwrap();
+        for _ in 0..5000 {
+            receiving.open(&sending.seal(b"").unwrap()).unwrap();
+        }
+        assert!(receiving.open(&stale).is_none());
+    }
+
+    #[tokio::test]
+    async fn encrypted_connection() {
+        let mut a = NetworkHandler::new(
+            "127.0.0.1:40137".parse().unwrap(),
+            "127.0.0.1:40138".parse().unwrap(),
+        );
+        let mut b = NetworkHandler::new(
+            "127.0.0.1:40138".parse().unwrap(),
+            "127.0.0.1:40137".parse().unwrap(),
+        );
+        a.set_encryption(true).unwrap();
+        b.set_encryption(false).unwrap();
+        assert_eq!(a.max_datagram_size(), BASE_PLPMTU - secure::OVERHEAD);
+        let (began_a, began_b) = tokio::join!(a.begin(), b.begin());
+        began_a.unwrap();
+        began_b.unwrap();
+        let mut receiver = b.subscribe();
+        let mut sender = a.get_sender();
+        let goodbye = Messages::Goodbye(GoodbyeMessage {
+            motd: "encrypted".to_string(),
+        });
+        sender.send_reliable(goodbye).await.unwrap();
+        loop {
+            if let Messages::Goodbye(msg) = b.recv(&mut receiver).await.unwrap() {
+                assert_eq!(msg.motd, "encrypted");
+                break;
+            }
+        }
+        //The encrypted datagrams fit into the path MTU
+        assert_eq!(a.discover_mtu().await, MAX_PLPMTU - secure::OVERHEAD);
+        a.close().await.unwrap();
+    }
+
+    #[test]
+    fn pake() {
+        let exchange = |a: &[u8], b: &[u8]| {
+            let initiator = Pake::new(a, b"channel", true);
+            let responder = Pake::new(b, b"channel", false);
+            let (share_i, share_r) = (initiator.share(), responder.share());
+            (
+                initiator.finish(&share_r).unwrap(),
+                responder.finish(&share_i).unwrap(),
+            )
+        };
+        let (initiator, responder) = exchange(b"secret", b"secret");
+        assert_eq!(initiator.key, responder.key);
+        assert!(initiator.verify(&responder.confirmation));
+        assert!(responder.verify(&initiator.confirmation));
+        //The confirmations of the two sides differ, so they can't be reflected
+        assert!(!initiator.verify(&initiator.confirmation));
+        let (initiator, responder) = exchange(b"secret", b"guess");
+        assert_ne!(initiator.key, responder.key);
+        assert!(!initiator.verify(&responder.confirmation));
+        assert!(!responder.verify(&initiator.confirmation));
+        //Knowing the nameplate of a short code is not enough
+        let code: Code = "7-crossbow-tulip".parse().unwrap();
+        let guesses = [
+            "7".to_string(),
+            "7-acorn-acorn".to_string(),
+            code.words.clone(),
+        ];
+        for guess in guesses.iter() {
+            let (initiator, responder) = exchange(&code.password(), guess.as_bytes());
+            assert!(!initiator.verify(&responder.confirmation));
+            assert!(!responder.verify(&initiator.confirmation));
+        }
+        let (initiator, responder) = exchange(&code.password(), b"7-crossbow-tulip");
+        assert!(initiator.verify(&responder.confirmation));
+        assert!(responder.verify(&initiator.confirmation));
+        //The identity point would make the key independent of the password
+        let identity = [0; pake::SHARE_SIZE];
+        assert!(Pake::new(b"secret", b"channel", true)
+            .finish(&identity)
+            .is_err());
+    }
+
+    #[test]
+    fn identities() {
+        let dir = std::env::temp_dir().join(format!("p2p-test-{}", rand::random::<u32>()));
+        let identity = Identity::load_or_create(&dir).unwrap();
+        let key = identity.public_key();
+        assert_eq!(Identity::load_or_create(&dir).unwrap().public_key(), key);
+        //Signatures only hold for the connection they were made on
+        let signature = identity.sign(b"binding");
+        assert!(identity::verify(&key, b"binding", &signature));
+        assert!(!identity::verify(&key, b"other", &signature));
+        let other = Identity::generate().public_key();
+        assert!(!identity::verify(&other, b"binding", &signature));
+        assert_eq!(identity::fingerprint(&key), identity::fingerprint(&key));
+        assert_ne!(identity::fingerprint(&key), identity::fingerprint(&other));
+
+        //Trust on first use, a different key under a known nickname is refused
+        let mut known_peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(known_peers.check("alice", &key), Trust::Unknown);
+        known_peers.trust(Some("alice"), &key).unwrap();
+        known_peers.trust(None, &other).unwrap();
+        let mut known_peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(known_peers.check("alice", &key), Trust::Known);
+        assert_eq!(known_peers.nickname(&key), Some("alice"));
+        assert_eq!(known_peers.nickname(&other), None);
+        assert_eq!(known_peers.check("alice", &other), Trust::Changed(key));
+        let error = known_peers.trust(Some("alice"), &other).unwrap_err();
+        assert!(error.to_string().contains("identity of alice changed"));
+        assert!(known_peers.add("two words", other).is_err());
+        //Names with spaces are stored without them instead of aborting the transfer
+        known_peers.trust(Some(" Bob's\tlaptop "), &other).unwrap();
+        let known_peers = KnownPeers::load(&dir).unwrap();
+        assert_eq!(known_peers.nickname(&other), Some("Bob's-laptop"));
+        assert_eq!(identity::sanitize_nickname(" \n"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn authenticated_connection() {
+        let connect = |port_a: u16, port_b: u16| async move {
+            let address_a = SocketAddr::from(([127, 0, 0, 1], port_a));
+            let address_b = SocketAddr::from(([127, 0, 0, 1], port_b));
+            let mut a = NetworkHandler::new(address_a, address_b);
+            let mut b = NetworkHandler::new(address_b, address_a);
+            a.set_encryption(true).unwrap();
+            b.set_encryption(false).unwrap();
+            let (began_a, began_b) = tokio::join!(a.begin(), b.begin());
+            began_a.unwrap();
+            began_b.unwrap();
+            (a, b)
+        };
+        let (a, b) = connect(40139, 40141).await;
+        let (mut receiver_a, mut receiver_b) = (a.subscribe(), b.subscribe());
+        let (authenticated_a, authenticated_b) = tokio::join!(
+            a.authenticate(&mut receiver_a, b"code", true),
+            b.authenticate(&mut receiver_b, b"code", false)
+        );
+        authenticated_a.unwrap();
+        authenticated_b.unwrap();
+        let (identity_a, identity_b) = (Identity::generate(), Identity::generate());
+        let (key_b, key_a) = tokio::join!(
+            a.exchange_identity(&mut receiver_a, &identity_a, true),
+            b.exchange_identity(&mut receiver_b, &identity_b, false)
+        );
+        assert_eq!(key_a.unwrap(), identity_a.public_key());
+        assert_eq!(key_b.unwrap(), identity_b.public_key());
+        //Traffic flows in both directions with the mixed keys
+        let goodbye = |motd: &str| {
+            Messages::Goodbye(GoodbyeMessage {
+                motd: motd.to_string(),
+            })
+        };
+        a.get_sender().send_reliable(goodbye("a")).await.unwrap();
+        b.get_sender().send_reliable(goodbye("b")).await.unwrap();
+        for (handler, receiver, motd) in [(&b, &mut receiver_b, "a"), (&a, &mut receiver_a, "b")] {
+            loop {
+                if let Messages::Goodbye(msg) = handler.recv(receiver).await.unwrap() {
+                    assert_eq!(msg.motd, motd);
+                    break;
+                }
+            }
+        }
+        a.close().await.unwrap();
+
+        //Both sides notice a different code
+        let (a, b) = connect(40142, 40143).await;
+        let (mut receiver_a, mut receiver_b) = (a.subscribe(), b.subscribe());
+        let (authenticated_a, authenticated_b) = tokio::join!(
+            a.authenticate(&mut receiver_a, b"code", true),
+            b.authenticate(&mut receiver_b, b"guess", false)
+        );
+        assert!(authenticated_a
+            .unwrap_err()
+            .to_string()
+            .contains("different code"));
+        assert!(authenticated_b
+            .unwrap_err()
+            .to_string()
+            .contains("different code"));
+        //An empty password is no proof
+        assert!(a.authenticate(&mut receiver_a, b"", true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn close_handshake() {
+        let a = NetworkHandler::new(
+            "127.0.0.1:40112".parse().unwrap(),
+            "127.0.0.1:40113".parse().unwrap(),
+        );
+        let b = NetworkHandler::new(
+            "127.0.0.1:40113".parse().unwrap(),
+            "127.0.0.1:40112".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let mut receiver = b.subscribe();
+        let mut states = b.watch_state();
+
+        //Outstanding reliable messages are delivered before the connection closes
+        let mut sender = a.get_sender();
+        let goodbye = Messages::Goodbye(GoodbyeMessage {
+            motd: "closing".to_string(),
+        });
+        let (sent, closed) = futures::join!(sender.send_reliable(goodbye), a.close());
+        sent.unwrap();
+        closed.unwrap();
+        assert_eq!(a.state(), ConnectionState::Closed);
+        let mut delivered = false;
+        while let Ok(msg) = receiver.try_recv() {
+            delivered |= matches!(msg, Messages::Goodbye(_));
+        }
+        assert!(delivered);
+
+        //The socket has to be released
+        std::net::UdpSocket::bind("127.0.0.1:40112").unwrap();
+
+        //The peer lingers for a while, then closes as well
+        while let Some(state) = states.recv().await {
+            if state.is_terminal() {
+                assert_eq!(state, ConnectionState::Closed);
+                break;
+            }
+        }
+        b.close().await.unwrap();
+        std::net::UdpSocket::bind("127.0.0.1:40113").unwrap();
+    }
+
+    #[test]
+    fn stun_codec() {
+        //Sample IPv4 response of RFC 5769 section 2.2
+        let response = hex(concat!(
+            "0101003c2112a442b7e7a701bc34d686fa87dfae",
+            "8022000b7465737420766563746f7220",
+            "002000080001a147e112a643",
+            "000800142b91f599fd9e90c38c7489f92af9ba53f06be7d7",
+            "80280004c07d4c96"
+        ));
+        let parsed = stun::parse_binding_response(&response).unwrap();
+        let transaction = parsed.transaction;
+        assert_eq!(transaction.to_vec(), hex("b7e7a701bc34d686fa87dfae"));
+        assert_eq!(parsed.mapped, "192.0.2.1:32853".parse().unwrap());
+        assert!(matches!(demux::demultiplex(&response), Packet::Stun(_)));
+
+        let request = stun::binding_request(transaction);
+        assert!(stun::is_stun(&request));
+        assert!(stun::parse_binding_response(&request).is_none());
+        let parsed = stun::parse_binding_request(&request).unwrap();
+        assert!(parsed.transaction == transaction && !parsed.change_port);
+        let request = stun::change_port_request(transaction);
+        assert!(stun::parse_binding_request(&request).unwrap().change_port);
+        let error = stun::ErrorCode {
+            code: stun::UNKNOWN_ATTRIBUTE,
+            reason: "Unknown attribute".to_string(),
+        };
+        let response = stun::error_response(transaction, &error);
+        assert_eq!(
+            stun::parse_error_response(&response),
+            Some((transaction, error))
+        );
+
+        let mapped = "[2001:db8::1]:40000".parse().unwrap();
+        let other = "192.0.2.2:3479".parse().unwrap();
+        let response = stun::binding_response(transaction, mapped, Some(other));
+        let parsed = stun::parse_binding_response(&response).unwrap();
+        assert_eq!(parsed.mapped, mapped);
+        assert_eq!(parsed.other_address, Some(other));
+
+        let ping = Messages::Ping(PingMessage { sequence: 1 }).to_datagram();
+        assert!(!stun::is_stun(&ping));
+        assert!(matches!(
+            demux::demultiplex(&ping),
+            Packet::Message(Messages::Ping(_))
+        ));
+        //Messages without the magic header are not ours
+        let unframed = Messages::Ping(PingMessage { sequence: 1 }).get_bytes();
+        assert!(matches!(demux::demultiplex(&unframed), Packet::Unknown));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nat_classification() {
+        let hosts = vec!["10.0.0.2:5000".parse().unwrap()];
+        let classify = |ports: &[u16]| {
+            let mapped = ports
+                .iter()
+                .map(|port| SocketAddr::new("198.51.100.1".parse().unwrap(), *port))
+                .collect();
+            NatBehavior::from_mapped(mapped, &hosts, Filtering::Unknown)
+        };
+        assert_eq!(
+            classify(&[7000, 7000, 7000]).mapping,
+            Mapping::EndpointIndependent
+        );
+        assert_eq!(classify(&[7000]).mapping, Mapping::Unknown);
+        assert!(classify(&[7000, 7000]).predicted_addresses().is_empty());
+        let random = classify(&[7000, 31000, 12000]);
+        assert_eq!(
+            random.mapping,
+            Mapping::EndpointDependent(PortAllocation::Random)
+        );
+        assert!(random.predicted_addresses().is_empty());
+
+        let incrementing = classify(&[7000, 7002, 7004]);
+        assert_eq!(
+            incrementing.mapping,
+            Mapping::EndpointDependent(PortAllocation::Incrementing(2))
+        );
+        let predicted = incrementing.predicted_addresses();
+        assert_eq!(predicted.len(), 8);
+        assert_eq!(predicted[0].port(), 7006);
+        assert_eq!(predicted[7].port(), 7020);
+
+        //Predicted ports are sent as a range
+        let mut candidates = vec![Candidate::new(CandidateKind::Host, hosts[0], 0)];
+        for address in predicted {
+            candidates.push(Candidate::new(
+                CandidateKind::Predicted,
+                address,
+                candidates.len(),
+            ));
+        }
+        let code = AddressInfo::new(candidates.clone()).to_string();
+        let single = AddressInfo::new(candidates[..2].to_vec()).to_string();
+        assert!(code.len() < single.len() + 8);
+        let info: AddressInfo = code.parse().unwrap();
+        assert_eq!(info.candidates, candidates);
+
+        let open = NatBehavior::from_mapped(hosts.clone(), &hosts, Filtering::Unknown);
+        assert_eq!(open.mapping, Mapping::Open);
+    }
+
+    #[tokio::test]
+    async fn nat_detection() {
+        let mut servers = Vec::new();
+        for &(listen, alternate) in
+            [("127.0.0.1:40116", Some(40117)), ("127.0.0.1:40118", None)].iter()
+        {
+            let server = StunServer::bind(listen.parse().unwrap(), alternate);
+            let server = server.await.unwrap();
+            servers.push(server.local_addr().unwrap());
+            tokio::spawn(server.run());
+        }
+        servers.push(servers[0]);
+        let mut socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let hosts = vec![socket.local_addr().unwrap()];
+        let behavior = nat::detect(&mut socket, &servers, &hosts).await.unwrap();
+        assert_eq!(behavior.mapping, Mapping::Open);
+        //Loopback does not filter anything
+        assert_eq!(behavior.filtering, Filtering::AddressDependent);
+        println!("NAT type: {}", behavior);
+    }
+
+    #[tokio::test]
+    async fn stun_failover() {
+        let server = StunServer::bind("127.0.0.1:40119".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let live = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let dead = "127.0.0.1:40120".parse().unwrap();
+        let mut socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+        let addresses = stun::external_addresses(&mut socket, &[dead, live]).await;
+        assert_eq!(addresses, vec![(local, live)]);
+
+        //Port changes need an alternate port
+        let refused = stun::query(&mut socket, live, true).await;
+        assert_eq!(refused.err().map(|e| e.kind()), Some(ErrorKind::Other));
+    }
+
+    #[tokio::test]
+    async fn shared_socket() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:40114")
+            .await
+            .unwrap();
+        let a = NetworkHandler::with_socket(socket, "127.0.0.1:40115".parse().unwrap()).unwrap();
+        let b = NetworkHandler::new(
+            "127.0.0.1:40115".parse().unwrap(),
+            "127.0.0.1:40114".parse().unwrap(),
+        );
+        a.begin().await.unwrap();
+        b.begin().await.unwrap();
+        let mut receiver = a.subscribe();
+
+        //Late STUN answers and strangers are dropped
+        let mut stranger = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let intruder = Messages::Goodbye(GoodbyeMessage {
+            motd: "stranger".to_string(),
+        });
+        let target: SocketAddr = "127.0.0.1:40114".parse().unwrap();
+        stranger
+            .send_to(&intruder.to_datagram(), &target)
+            .await
+            .unwrap();
+        let request = stun::binding_request(stun::new_transaction());
+        stranger.send_to(&request, &target).await.unwrap();
+        let goodbye = Messages::Goodbye(GoodbyeMessage {
+            motd: "shared".to_string(),
+        });
+        b.get_sender().send_reliable(goodbye).await.unwrap();
+        loop {
+            if let Messages::Goodbye(msg) = a.recv(&mut receiver).await.unwrap() {
+                assert_eq!(msg.motd, "shared");
+                break;
+            }
+        }
+        let (closed_a, closed_b) = futures::join!(a.close(), b.close());
+        closed_a.unwrap();
+        closed_b.unwrap();
+    }
+
+    #[test]
+    fn mtu_search() {
+        for &path_mtu in [1300, 1400, MAX_PLPMTU].iter() {
+            let mut search = MtuSearch::new(MAX_PLPMTU);
+            let mut probes = 0;
+            while let Some(size) = search.next_probe() {
+                probes += 1;
+                if size <= path_mtu {
+                    search.acknowledged(size);
+                } else {
+                    for _ in 0..MAX_PROBES {
+                        search.timed_out(size);
+                    }
+                }
+            }
+            assert!(search.plpmtu() <= path_mtu);
+            assert!(search.plpmtu() + 8 > path_mtu);
+            assert!(probes <= 8);
+        }
+
+        let handler = NetworkHandler::new(
+            "127.0.0.1:40103".parse().unwrap(),
+            "127.0.0.1:40104".parse().unwrap(),
+        );
+        assert_eq!(handler.max_datagram_size(), BASE_PLPMTU);
+        let msg = Messages::Segment(SegmentMessage::new(0, vec![0; BASE_PLPMTU]));
+        assert!(handler.get_sender().send(msg).is_err());
+    }
+
+    /// External address the fake gateways hand out
+    const GATEWAY_IP: [u8; 4] = [198, 51, 100, 7];
+
+    fn update_mapping(mappings: &Mutex<HashSet<u16>>, port: u16, lifetime: u32) {
+        let mut mappings = mappings.lock().unwrap();
+        if lifetime == 0 {
+            mappings.remove(&port);
+        } else {
+            mappings.insert(port);
+        }
+    }
+
+    /// Gateway forwarding every requested port. Without `pcp` it only speaks NAT-PMP
+    async fn fake_pcp_gateway(address: &str, pcp: bool) -> Arc<Mutex<HashSet<u16>>> {
+        let mut socket = tokio::net::UdpSocket::bind(address).await.unwrap();
+        let mappings = Arc::new(Mutex::new(HashSet::new()));
+        let mapped = Arc::clone(&mappings);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1100];
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+                let b = &buf[..size];
+                let answer = match (b[0], b[1]) {
+                    (2, 1) if pcp => {
+                        let port = u16::from_be_bytes([b[42], b[43]]);
+                        let lifetime = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+                        update_mapping(&mapped, port, lifetime);
+                        let mut answer = vec![2, 0x81, 0, 0];
+                        answer.extend_from_slice(&b[4..8]);
+                        answer.extend_from_slice(&[0; 16]);
+                        //Nonce, protocol and internal port
+                        answer.extend_from_slice(&b[24..42]);
+                        answer.extend_from_slice(&port.to_be_bytes());
+                        answer.extend_from_slice(
+                            &Ipv4Addr::from(GATEWAY_IP).to_ipv6_mapped().octets(),
+                        );
+                        answer
+                    }
+                    (2, opcode) => vec![0, 0x80 | opcode, 0, 1, 0, 0, 0, 0],
+                    (0, 0) => {
+                        let mut answer = vec![0, 0x80, 0, 0, 0, 0, 0, 0];
+                        answer.extend_from_slice(&GATEWAY_IP);
+                        answer
+                    }
+                    (0, 1) => {
+                        let internal = u16::from_be_bytes([b[4], b[5]]);
+                        let lifetime = u32::from_be_bytes([b[8], b[9], b[10], b[11]]);
+                        update_mapping(&mapped, internal, lifetime);
+                        let mut answer = vec![0, 0x81, 0, 0, 0, 0, 0, 0];
+                        answer.extend_from_slice(&b[4..6]);
+                        answer.extend_from_slice(&b[4..6]);
+                        answer.extend_from_slice(&b[8..12]);
+                        answer
+                    }
+                    _ => continue,
+                };
+                socket.send_to(&answer, &from).await.ok();
+            }
+        });
+        mappings
+    }
+
+    async fn read_http_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let size = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..size]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let end = match text.find("\r\n\r\n") {
+                Some(end) => end + 4,
+                None if size > 0 => continue,
+                None => return text,
+            };
+            let length = text[..end]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |(_, value)| value.trim().parse().unwrap());
+            if size == 0 || request.len() >= end + length {
+                return text;
+            }
+        }
+    }
+
+    fn upnp_answer(request: &str, mappings: &Mutex<HashSet<u16>>) -> String {
+        const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+        let value = |name: &str| {
+            let start = request.find(&format!("<{}>", name)).unwrap() + name.len() + 2;
+            let end = start + request[start..].find('<').unwrap();
+            request[start..end].to_string()
+        };
+        let action = |name: &str, arguments: &[&str]| {
+            let arguments: String = arguments
+                .iter()
+                .map(|argument| {
+                    format!(
+                        "<argument><name>{}</name><direction>in</direction></argument>",
+                        argument
+                    )
+                })
+                .collect();
+            format!(
+                "<action><name>{}</name><argumentList>{}</argumentList></action>",
+                name, arguments
+            )
+        };
+        let envelope = |body: String| {
+            format!(
+                "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>{}</s:Body></s:Envelope>",
+                body
+            )
+        };
+        if request.starts_with("GET /rootDesc.xml") {
+            format!(
+                "<?xml version=\"1.0\"?><root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType><serviceList><service><serviceType>{}</serviceType><SCPDURL>/scpd.xml</SCPDURL><controlURL>/ctl</controlURL></service></serviceList></device></root>",
+                SERVICE
+            )
+        } else if request.starts_with("GET /scpd.xml") {
+            let add = action(
+                "AddPortMapping",
+                &[
+                    "NewRemoteHost",
+                    "NewExternalPort",
+                    "NewProtocol",
+                    "NewInternalPort",
+                    "NewInternalClient",
+                    "NewEnabled",
+                    "NewPortMappingDescription",
+                    "NewLeaseDuration",
+                ],
+            );
+            let delete = action(
+                "DeletePortMapping",
+                &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+            );
+            format!(
+                "<?xml version=\"1.0\"?><scpd xmlns=\"urn:schemas-upnp-org:service-1-0\"><actionList>{}{}</actionList></scpd>",
+                add, delete
+            )
+        } else if request.contains("#AddPortMapping") {
+            update_mapping(mappings, value("NewExternalPort").parse().unwrap(), 1);
+            envelope(format!(
+                "<u:AddPortMappingResponse xmlns:u=\"{}\"/>",
+                SERVICE
+            ))
+        } else if request.contains("#DeletePortMapping") {
+            update_mapping(mappings, value("NewExternalPort").parse().unwrap(), 0);
+            envelope(format!(
+                "<u:DeletePortMappingResponse xmlns:u=\"{}\"/>",
+                SERVICE
+            ))
+        } else {
+            envelope(format!(
+                "<u:GetExternalIPAddressResponse xmlns:u=\"{}\"><NewExternalIPAddress>{}</NewExternalIPAddress></u:GetExternalIPAddressResponse>",
+                SERVICE,
+                Ipv4Addr::from(GATEWAY_IP)
+            ))
+        }
+    }
+
+    /// UPnP gateway answering SSDP searches on `ssdp` and serving its description and
+    /// control requests over HTTP on `http`
+    async fn fake_upnp_gateway(ssdp: &str, http: &str) -> Arc<Mutex<HashSet<u16>>> {
+        let mut search = tokio::net::UdpSocket::bind(ssdp).await.unwrap();
+        let mut listener = TcpListener::bind(http).await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", http);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            loop {
+                let (_, from) = search.recv_from(&mut buf).await.unwrap();
+                let answer = format!(
+                    "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLOCATION: {}\r\n\r\n",
+                    location
+                );
+                search.send_to(answer.as_bytes(), &from).await.ok();
+            }
+        });
+        let mappings = Arc::new(Mutex::new(HashSet::new()));
+        let mapped = Arc::clone(&mappings);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mapped = Arc::clone(&mapped);
+                tokio::spawn(async move {
+                    let request = read_http_request(&mut stream).await;
+                    let body = upnp_answer(&request, &mapped);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+        mappings
+    }
+
+    #[tokio::test]
+    async fn port_mapping() {
+        let local: SocketAddrV4 = "127.0.0.1:40130".parse().unwrap();
+        let external = SocketAddr::new(Ipv4Addr::from(GATEWAY_IP).into(), 40130);
+        //Nothing answers there, so the UPnP fallback finds no gateway
+        let nowhere: SocketAddr = "127.0.0.1:40129".parse().unwrap();
+        for &(gateway, pcp, method) in [
+            ("127.0.0.1:40121", true, Method::Pcp),
+            ("127.0.0.1:40122", false, Method::NatPmp),
+        ]
+        .iter()
+        {
+            let mappings = fake_pcp_gateway(gateway, pcp).await;
+            let mapper = PortMapper::with_gateway(gateway.parse().unwrap(), nowhere);
+            let mapping = mapper.map(local).await.unwrap();
+            assert_eq!(mapping.method(), method);
+            assert_eq!(mapping.external, external);
+            assert!(mappings.lock().unwrap().contains(&40130));
+            let lease = mapping.keep_alive();
+            assert_eq!(lease.external, external);
+            lease.release().await.unwrap();
+            assert!(mappings.lock().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "tokio 0.2 TCP sockets need net2 0.2.36 or newer on current toolchains"]
+    async fn upnp_port_mapping() {
+        let local: SocketAddrV4 = "127.0.0.1:40130".parse().unwrap();
+        let external = SocketAddr::new(Ipv4Addr::from(GATEWAY_IP).into(), 40130);
+        //Gateways without NAT-PMP and PCP are asked with UPnP
+        let nowhere: SocketAddr = "127.0.0.1:40125".parse().unwrap();
+        let mappings = fake_upnp_gateway("127.0.0.1:40123", "127.0.0.1:40124").await;
+        let mapper = PortMapper::with_gateway(nowhere, "127.0.0.1:40123".parse().unwrap());
+        let mapping = mapper.map(local).await.unwrap();
+        assert_eq!(mapping.method(), Method::Upnp);
+        assert_eq!(mapping.external, external);
+        assert!(mappings.lock().unwrap().contains(&40130));
+        mapping.keep_alive().release().await.unwrap();
+        assert!(mappings.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lan_discovery() {
+        let group: SocketAddrV4 = "239.255.80.81:40126".parse().unwrap();
+        let mut receiver = tokio::net::UdpSocket::bind("127.0.0.1:40127")
+            .await
+            .unwrap();
+        let host = Candidate::new(CandidateKind::Host, receiver.local_addr().unwrap(), 0);
+        let mut browser = discovery::Browser::bind(group).unwrap();
+        let other = Candidate::new(CandidateKind::Host, "127.0.0.1:40129".parse().unwrap(), 0);
+        let other = AddressInfo::new(vec![other]);
+        //Names can't control the terminal of whoever lists them
+        let _other = discovery::announce("bob-\x1b[2Jdesktop\r\n", other, group).unwrap();
+        let info = AddressInfo::new(vec![host]);
+        let _announcer = discovery::announce("alice-laptop", info.clone(), group).unwrap();
+        let peer = tokio::time::timeout(Duration::from_secs(3), browser.find("alice-laptop"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.info.candidates[0].address, host.address);
+        assert_eq!(peer.info.session, info.session);
+        let names: Vec<String> = browser
+            .browse(Duration::from_millis(1500))
+            .await
+            .into_iter()
+            .map(|peer| peer.name)
+            .collect();
+        assert_eq!(names, vec!["alice-laptop", "bob-[2Jdesktop"]);
+        assert_eq!(
+            message::printable("evil\u{202e}txt.exe\u{7}"),
+            "eviltxt.exe"
+        );
+
+        //The receiver answers the request with its first connectivity check
+        let mut sender = tokio::net::UdpSocket::bind("127.0.0.1:40128")
+            .await
+            .unwrap();
+        let ours = AddressInfo::new(vec![Candidate::new(
+            CandidateKind::Host,
+            sender.local_addr().unwrap(),
+            0,
+        )]);
+        let answer = async {
+            let mut buf = vec![0; 1500];
+            let (size, from) = receiver.recv_from(&mut buf).await.unwrap();
+            match Messages::from_datagram(&buf[..size]) {
+                Some(Messages::ConnectRequest(msg)) => {
+                    assert_eq!(msg.name, "carol");
+                    assert_eq!(msg.info.candidates[0].address, from);
+                }
+                _ => panic!("Expected a connection request"),
+            }
+            receiver.send_to(b"check", &from).await.unwrap();
+        };
+        let (requested, _) = tokio::join!(
+            discovery::request_connection(&mut sender, &peer, "carol", &ours),
+            answer
+        );
+        requested.unwrap();
+
+        //Only requests from the local network are considered
+        for ip in [
+            "192.168.1.7",
+            "10.0.0.1",
+            "169.254.3.4",
+            "fe80::1",
+            "fd00::5",
+            "::1",
+        ]
+        .iter()
+        {
+            assert!(discovery::is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.64.0.1", "2001:db8::1", "::ffff:1.2.3.4"].iter() {
+            assert!(!discovery::is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn rendezvous() {
+        let server = RendezvousServer::bind("127.0.0.1:40131".parse().unwrap())
+            .await
+            .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let mut sender = tokio::net::UdpSocket::bind("127.0.0.1:40132")
+            .await
+            .unwrap();
+        let mut receiver = tokio::net::UdpSocket::bind("127.0.0.1:40133")
+            .await
+            .unwrap();
+        let host = |socket: &tokio::net::UdpSocket| {
+            AddressInfo::new(vec![Candidate::new(
+                CandidateKind::Host,
+                socket.local_addr().unwrap(),
+                0,
+            )])
+        };
+        let sender_info = host(&sender);
+        let receiver_info = host(&receiver);
+        //Truncated and deeply nested requests are ignored, the server keeps running
+        for id in [26u32, 28].iter() {
+            for size in 0..8 {
+                let mut datagram = MAGIC.to_vec();
+                datagram.extend(id.to_le_bytes().iter());
+                datagram.extend(vec![0xff; size]);
+                sender.send_to(&datagram, &server_addr).await.unwrap();
+            }
+        }
+        let nested = nested_datagram();
+        sender.send_to(&nested, &server_addr).await.unwrap();
+        let nameplate = rendezvous::register(&mut sender, server_addr, &sender_info)
+            .await
+            .unwrap();
+        assert_eq!(nameplate, 1);
+        let words = Code::random_words();
+        //Typed codes may have different case and spaces around them
+        let typed = format!(" {}-{} ", nameplate, words.to_uppercase());
+        let code: Code = typed.parse().unwrap();
+        assert_eq!(code.to_string(), format!("1-{}", words));
+        assert!("1-crossbow".parse::<Code>().is_err());
+        assert!("x-crossbow-tulip".parse::<Code>().is_err());
+        assert!("1-crossbow-qwerty".parse::<Code>().is_err());
+
+        let remote = rendezvous::claim(&mut receiver, server_addr, &code, &receiver_info)
+            .await
+            .unwrap();
+        assert_eq!(remote.candidates[0].address, sender.local_addr().unwrap());
+        assert_eq!(remote.session, sender_info.session);
+        //The sender gets the code of the receiver without typing anything
+        let mut buf = vec![0; 1500];
+        let (size, _) = sender.recv_from(&mut buf).await.unwrap();
+        match Messages::from_datagram(&buf[..size]) {
+            Some(Messages::RendezvousPeer(msg)) => {
+                assert_eq!(
+                    msg.info.candidates[0].address,
+                    receiver.local_addr().unwrap()
+                );
+                assert_eq!(msg.info.session, receiver_info.session);
+            }
+            _ => panic!("Expected the code of the receiver"),
+        }
+
+        //The words never reach the server
+        let code = Code {
+            nameplate,
+            words: words.clone(),
+        };
+        let claim = RendezvousClaimMessage {
+            nameplate,
+            info: receiver_info.clone(),
+        };
+        let datagram = Messages::RendezvousClaim(claim).to_datagram();
+        let register = RendezvousRegisterMessage { info: sender_info };
+        for datagram in [
+            datagram,
+            Messages::RendezvousRegister(register).to_datagram(),
+        ]
+        .iter()
+        {
+            for word in code.words.split('-') {
+                assert!(!datagram
+                    .windows(word.len())
+                    .any(|window| window == word.as_bytes()));
+            }
+        }
+
+        //Once a mailbox is claimed, nobody else gets it
+        let mut other = tokio::net::UdpSocket::bind("127.0.0.1:40134")
+            .await
+            .unwrap();
+        let other_info = host(&other);
+        assert!(
+            rendezvous::claim(&mut other, server_addr, &code, &other_info)
+                .await
+                .is_err()
+        );
+        //Retransmitted claims are answered again
+        rendezvous::claim(&mut receiver, server_addr, &code, &receiver_info)
+            .await
+            .unwrap();
+
+        //One address can't take up all of the nameplates
+        let mut clients = Vec::new();
+        for _ in 1..rendezvous::MAX_CLIENT_MAILBOXES {
+            clients.push(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            let info = host(client);
+            let nameplate = rendezvous::register(client, server_addr, &info)
+                .await
+                .unwrap();
+            assert_eq!(nameplate as usize, i + 2);
+        }
+        let mut greedy = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let greedy_info = host(&greedy);
+        assert!(rendezvous::register(&mut greedy, server_addr, &greedy_info)
+            .await
+            .is_err());
+        //Existing mailboxes can still be refreshed
+        let info = host(&clients[0]);
+        let nameplate = rendezvous::register(&mut clients[0], server_addr, &info)
+            .await
+            .unwrap();
+        assert_eq!(nameplate, 2);
+    }
+
+    #[test]
+    fn word_codes() {
+        let address = "192.168.0.111:6543".parse().unwrap();
+        let session = Session {
+            nonce: [1, 2, 3, 4, 5, 6, 7, 8],
+            ..Session::new()
+        };
+        let candidates = vec![Candidate::new(CandidateKind::Host, address, 0)];
+        let info = AddressInfo::with_session(candidates, session);
+        //The version, the session, no secret and the candidates, followed by their CRC-32
+        let bytes = info.to_bytes();
+        assert_eq!(bytes[..2], [2, CODE_VERSION]);
+        assert_eq!(bytes[2..6], session.created.to_le_bytes());
+        assert_eq!(bytes[6..14], session.nonce);
+        assert_eq!(bytes[14], 0);
+        assert_eq!(bytes[15..], [0, 4, 192, 168, 0, 111, 143, 25]);
+        let expected: Vec<&str> = bytes.iter().map(|&b| words::WORDS[b as usize]).collect();
+        let code = info.to_words();
+        assert!(code.starts_with(&expected.join(" ")));
+        assert_eq!(code.split(' ').count(), bytes.len() + 4);
+        //Both encodings are accepted
+        let typed = [
+            code.clone(),
+            code.replace(' ', "-").to_uppercase(),
+            info.to_string(),
+        ];
+        for typed in typed.iter() {
+            let parsed: AddressInfo = typed.parse().unwrap();
+            assert_eq!(parsed.candidates[0].address, address);
+        }
+
+        let error = |typed: &[String]| {
+            let error = typed.join(" ").parse::<AddressInfo>().err().unwrap();
+            error.to_string()
+        };
+        let words: Vec<String> = code.split(' ').map(String::from).collect();
+        let mut typed = words.clone();
+        typed[7] = format!("{}x", words[7]);
+        let message = error(&typed);
+        assert!(message.starts_with("Word 8"));
+        assert!(message.contains(&format!("did you mean \"{}\"", words[7])));
+        //Other words of the list are found through the checksum
+        typed[7] = words::WORDS[bytes[7] as usize + 1].into();
+        let message = error(&typed);
+        assert!(message.starts_with("Word 8"));
+        assert!(message.contains(&format!("did you mean \"{}\"", words[7])));
+        let mut typed = words.clone();
+        typed.swap(2, 3);
+        assert_eq!(error(&typed), "Words 3 and 4 are probably swapped");
+        assert!(error(&words[1..]).contains("missing or extra"));
+    }
 }
@@ -1,148 +1,498 @@
 use std::{
-    fs::File, io::BufRead, io::BufReader, io::Read, iter, path::Path, slice, thread::sleep,
-    time::Duration,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    fs::File,
+    io::BufReader,
+    io::Read,
+    io::{Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+use tokio::{
+    sync::broadcast::Receiver,
+    time::{Duration, Instant},
 };
-//TODO: More errors, instead of Option
 
 use crate::{
-    message::ChunkMessage,
+    congestion::CongestionController,
+    error::Error,
+    integrity::FileHash,
+    manifest::{Manifest, MANIFEST_MESSAGE_SIZE},
+    merkle::{BlockHashes, HASHES_PER_MESSAGE},
     message::FileTransferRequestMessage,
-    message::Messages,
-    message::PartBeginMessage,
-    message::{GoodbyeMessage, PartEndMessage},
-    networking::NetworkHandler,
+    message::{printable, Messages},
+    message::{BlockHashesMessage, RefetchMessage},
+    message::{GoodbyeMessage, ManifestMessage},
+    message::{SegmentMessage, SelectiveAckMessage, PROTOCOL_VERSION},
+    networking::{Introduction, NetworkHandler},
 };
 
-pub async fn begin(handler: NetworkHandler, path: &Path) {
-    let file = File::open(path).unwrap();
-    let mut partno = 0u32;
+/// Sleeps shorter than this are not worth it, the chunk is sent right away instead
+const PACING_GRANULARITY: Duration = Duration::from_millis(1);
+/// Maximum distance between the first unacknowledged and the next new segment
+const MAX_WINDOW: u32 = 4096;
+/// A segment is lost if this many later segments were acknowledged before it
+const REORDERING_THRESHOLD: u32 = 3;
+
+pub async fn begin(
+    handler: NetworkHandler,
+    paths: &[&Path],
+    mut controller: Box<dyn CongestionController>,
+    introduction: Introduction<'_>,
+) -> Result<(), Error> {
+    let manifest = Manifest::from_paths(paths)?;
+    //Hashed before connecting, so the receiver doesn't have to wait for it
+    let hashes = manifest
+        .files()
+        .map(|(_, source)| BlockHashes::from_reader(&mut BufReader::new(File::open(source)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
 
-    handler.wait_for_connection().await;
+    handler.wait_for_connection().await?;
     println!("Connected!");
+    {
+        let mut receiver = handler.subscribe();
+        handler.introduce(&mut receiver, introduction, true).await?;
+    }
+    let mtu = handler.discover_mtu().await;
+    println!("Path MTU: {} bytes", mtu);
+    send_manifest(&handler, &manifest).await?;
+    let mut failed = Vec::new();
+    for ((entry, source), (blocks, hash)) in manifest.files().zip(hashes) {
+        let file = TransferFile {
+            source,
+            name: &entry.path,
+            blocks,
+            hash,
+        };
+        if !send_file(&handler, file, mtu, controller.as_mut()).await? {
+            failed.push(entry.path.as_str());
+        }
+    }
+    println!("Finished sending the files!");
+    {
+        let mut sender = handler.get_sender();
+        let msg = GoodbyeMessage {
+            motd: "Thank you for using our service!".to_string(),
+        };
+        sender.send_reliable(Messages::Goodbye(msg)).await?;
+    }
+    //The files are complete, a lost close handshake doesn't matter anymore
+    handler.close().await.ok();
+    if !failed.is_empty() {
+        return Err(Error::new(&format!(
+            "{} of {} files failed: {}",
+            failed.len(),
+            manifest.file_count(),
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// A file of the manifest, with the hashes computed before the connection
+struct TransferFile<'a> {
+    source: &'a Path,
+    /// Path of the file in the manifest
+    name: &'a str,
+    blocks: BlockHashes,
+    hash: FileHash,
+}
+
+/// Announces all files and directories, before the first file is requested
+async fn send_manifest(handler: &NetworkHandler, manifest: &Manifest) -> Result<(), Error> {
+    let total = manifest.entries.len() as u32;
+    let mut messages = Vec::new();
+    let mut msg = ManifestMessage {
+        total,
+        start: 0,
+        entries: Vec::new(),
+    };
+    let mut size = 0;
+    for (i, entry) in manifest.entries.iter().enumerate() {
+        if size + entry.encoded_size() > MANIFEST_MESSAGE_SIZE && !msg.entries.is_empty() {
+            let next = ManifestMessage {
+                total,
+                start: i as u32,
+                entries: Vec::new(),
+            };
+            messages.push(std::mem::replace(&mut msg, next));
+            size = 0;
+        }
+        size += entry.encoded_size();
+        msg.entries.push(entry.clone());
+    }
+    messages.push(msg);
+    let sends = messages.into_iter().map(|msg| {
+        let mut sender = handler.get_sender();
+        async move { sender.send_reliable(Messages::Manifest(msg)).await }
+    });
+    futures::future::try_join_all(sends).await?;
+    Ok(())
+}
+
+/// Sends a single file. Returns false if the receiver reported that it failed,
+/// a file the receiver skipped did not fail
+async fn send_file(
+    handler: &NetworkHandler,
+    file: TransferFile<'_>,
+    mtu: usize,
+    controller: &mut dyn CongestionController,
+) -> Result<bool, Error> {
+    let source = File::open(file.source)?;
+    let filesize = source.metadata()?.len();
+    let segment_size = payload_size(mtu, Messages::Segment(SegmentMessage::new(0, Vec::new())));
     let mut receiver = handler.subscribe();
     let mut sender = handler.get_sender();
-    println!("Sending file request!");
+    println!("Sending {}", file.name);
     //Send Transfer Request
     {
         let msg = FileTransferRequestMessage {
-            filename: path.file_name().unwrap().to_str().unwrap().to_string(),
-            filesize: file.metadata().unwrap().len(),
+            filename: file.name.to_string(),
+            filesize,
+            version: PROTOCOL_VERSION,
+            chunk_size: segment_size,
+            hash: file.hash,
+            root: file.blocks.root(),
         };
         sender
             .send_reliable(Messages::FileTransferRequest(msg))
-            .await
-            .unwrap();
+            .await?;
     }
 
-    println!("Waiting for response...!");
     //Waiting for the request to be accepted
-    while let Ok(msg) = receiver.recv().await {
-        if let Messages::FileTransferAccept(_) = msg {
-            break;
+    let (version, resume_from) = loop {
+        match handler.recv(&mut receiver).await? {
+            Messages::FileTransferAccept(msg) => break (msg.version, msg.resume_from),
+            Messages::FileTransferDecline(msg) => {
+                println!(
+                    "The receiver skipped {}: {}",
+                    file.name,
+                    printable(&msg.reason)
+                );
+                return Ok(true);
+            }
+            _ => continue,
         }
+    };
+    if version < PROTOCOL_VERSION {
+        return Err(Error::new(&format!(
+            "The receiver only supports protocol version {}",
+            version
+        )));
     }
-    let mut reader = BufReader::new(file);
-    println!("Sending parts...!");
-    while let Some(_) = send_part(&handler, &mut reader, 1024, 512, partno).await {
-        //TODO: change hardwritten values
-        partno += 1
+    let mut reader = BufReader::new(source);
+    //Subscribed before the hashes are sent, the receiver may ask for blocks right after them
+    let window_receiver = handler.subscribe();
+    send_block_hashes(handler, &file.blocks).await?;
+    let segment_count = u32::try_from(filesize.div_ceil(segment_size as u64))
+        .map_err(|_| Error::new("The file has too many segments"))?;
+    let first = u32::try_from(resume_from / segment_size as u64)?.min(segment_count);
+    if first > 0 {
+        println!("Resuming at byte {} of {}", resume_from, filesize);
     }
-    println!("Finished sending the file!");
-    {
-        let msg = GoodbyeMessage {
-            motd: "Thank you for using our service!".to_string(),
-        };
-        sender.send_reliable(Messages::Goodbye(msg)).await.unwrap();
+    send_window(
+        handler,
+        window_receiver,
+        &mut reader,
+        segment_size,
+        first..segment_count,
+        controller,
+    )
+    .await?;
+    //The receiver checks the hash of the whole file before it moves on
+    loop {
+        match handler.recv(&mut receiver).await? {
+            Messages::TransferSuccessful(_) => break,
+            Messages::TransferFailed(msg) => {
+                println!(
+                    "The receiver failed on {}: {}",
+                    file.name,
+                    printable(&msg.reason)
+                );
+                return Ok(false);
+            }
+            _ => continue,
+        }
     }
+    Ok(true)
 }
 
-pub async fn send_part<T: BufRead>(
-    handler: &NetworkHandler,
-    file: &mut T,
-    chunk_size: u32,
-    chunk_count: u32,
-    partno: u32,
-) -> Option<()> {
-    let mut sender = handler.get_sender();
-    let mut receiver = handler.subscribe();
+/// Sends the leaves of the Merkle tree, which the receiver checks against the root of the request
+async fn send_block_hashes(handler: &NetworkHandler, blocks: &BlockHashes) -> Result<(), Error> {
+    let sends = blocks
+        .leaves
+        .chunks(HASHES_PER_MESSAGE)
+        .enumerate()
+        .map(|(i, hashes)| {
+            let mut sender = handler.get_sender();
+            let msg = BlockHashesMessage {
+                start: (i * HASHES_PER_MESSAGE) as u32,
+                hashes: hashes.to_vec(),
+            };
+            async move { sender.send_reliable(Messages::BlockHashes(msg)).await }
+        });
+    futures::future::try_join_all(sends).await?;
+    Ok(())
+}
+
+/// Number of payload bytes which fit into a datagram next to the header of `empty`
+fn payload_size(mtu: usize, empty: Messages) -> u32 {
+    (mtu - empty.to_datagram().len()) as u32
+}
 
-    let mut chunk_count = chunk_count;
-    let chunks = split(file, chunk_size as usize, &mut chunk_count)?;
-    if chunk_count <= 0 {
-        return None;
+/// Spaces out sends according to the pacing rate of a congestion controller
+struct Pacer {
+    next_send: Instant,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Self {
+            next_send: Instant::now(),
+        }
     }
 
-    {
-        let msg = Messages::PartBegin(PartBeginMessage {
-            part_size: chunks.iter().map(|chunk| chunk.len()).sum::<usize>() as u32,
-            chunk_size,
-            part_number: partno,
-            chunk_count,
-        });
-        sender.send_reliable(msg).await.unwrap();
+    /// Waits until the pacing rate allows the next send
+    async fn wait(&mut self, controller: &mut dyn CongestionController) {
+        if self.next_send > Instant::now() + PACING_GRANULARITY {
+            tokio::time::delay_until(self.next_send).await;
+        }
+        self.next_send = self.next_send.max(Instant::now()) + controller.pacing_interval();
     }
+}
 
-    send_chunks(handler, &chunks, &mut iter::repeat(false), partno);
+struct Segment {
+    data: Vec<u8>,
+    sent_at: Instant,
+    retransmitted: bool,
+}
 
-    {
-        let msg = Messages::PartEnd(PartEndMessage {});
-        sender.send_reliable(msg).await.unwrap();
+/// Segments which were sent, but not acknowledged yet
+struct Window {
+    segments: BTreeMap<u32, Segment>,
+    /// Segments waiting to be retransmitted
+    lost: BTreeSet<u32>,
+    cumulative: u32,
+    /// Losses below this sequence number were already reported to the controller
+    recovery: u32,
+    rtt: Duration,
+    /// Segments are considered lost when they are not acknowledged for this long
+    rto: Duration,
+    /// The receiver reported the outcome of the file, nothing has to be sent anymore
+    finished: bool,
+}
+
+impl Window {
+    fn new(rto: Duration, first: u32) -> Self {
+        Self {
+            segments: BTreeMap::new(),
+            lost: BTreeSet::new(),
+            cumulative: first,
+            recovery: first,
+            rtt: rto,
+            rto,
+            finished: false,
+        }
     }
 
-    while let Ok(msg) = receiver.recv().await {
-        match msg {
-            Messages::TransferIncomplete(msg) => {
-                send_chunks(handler, &chunks, &mut msg.bitfield.iter(), partno);
-                {
-                    let msg = Messages::PartEnd(PartEndMessage {});
-                    sender.send_reliable(msg).await.unwrap();
-                }
+    /// Number of segments which are actually travelling through the network
+    fn pipe(&self) -> usize {
+        self.segments.len() - self.lost.len()
+    }
+
+    fn acknowledge(
+        &mut self,
+        msg: &SelectiveAckMessage,
+        next_sequence: u32,
+        controller: &mut dyn CongestionController,
+    ) {
+        let mut acked: Vec<u32> = self
+            .segments
+            .range(..msg.cumulative)
+            .map(|(&k, _)| k)
+            .collect();
+        for &(start, end) in msg.ranges.iter() {
+            if start < end {
+                acked.extend(self.segments.range(start..end).map(|(&k, _)| k));
             }
-            Messages::TransferSuccessful(_) => break,
-            _ => continue,
         }
+        let mut sample = None;
+        for sequence in acked.iter() {
+            let segment = self.segments.remove(sequence).unwrap();
+            self.lost.remove(sequence);
+            //Karn's algorithm: retransmitted segments give ambiguous samples
+            if !segment.retransmitted {
+                sample = Some(segment.sent_at.elapsed());
+            }
+        }
+        self.cumulative = self.cumulative.max(msg.cumulative);
+        if !acked.is_empty() {
+            self.rtt = sample.unwrap_or(self.rtt);
+            controller.on_ack(acked.len() as u32, self.rtt);
+        }
+
+        //Detect losses using the highest acknowledged segment
+        let highest = msg
+            .ranges
+            .iter()
+            .map(|&(_, end)| end)
+            .fold(msg.cumulative, u32::max);
+        let newly_lost: Vec<u32> = self
+            .segments
+            .range(..highest)
+            .filter(|(sequence, segment)| {
+                !self.lost.contains(sequence)
+                    && ((!segment.retransmitted && **sequence + REORDERING_THRESHOLD < highest)
+                        || segment.sent_at.elapsed() > self.rto)
+            })
+            .map(|(&k, _)| k)
+            .collect();
+        self.mark_lost(newly_lost, next_sequence, controller);
+    }
+
+    /// Handles a message of the receiver about the segments
+    fn on_message<T: Read + Seek>(
+        &mut self,
+        msg: Messages,
+        file: &mut T,
+        chunk_size: u32,
+        next_sequence: u32,
+        controller: &mut dyn CongestionController,
+    ) -> Result<(), Error> {
+        match msg {
+            Messages::SelectiveAck(msg) => self.acknowledge(&msg, next_sequence, controller),
+            Messages::Refetch(msg) => self.refetch(&msg, file, chunk_size, next_sequence)?,
+            //The last acknowledgements may be lost, the outcome is sent reliably
+            Messages::TransferSuccessful(_) | Messages::TransferFailed(_) => self.finished = true,
+            _ => {}
+        }
+        Ok(())
     }
 
-    Some(())
-}
+    /// The receiver threw away segments of a corrupted block, they are read from the file again
+    fn refetch<T: Read + Seek>(
+        &mut self,
+        msg: &RefetchMessage,
+        file: &mut T,
+        chunk_size: u32,
+        next_sequence: u32,
+    ) -> Result<(), Error> {
+        for sequence in msg.start..msg.end.min(next_sequence) {
+            file.seek(SeekFrom::Start(sequence as u64 * chunk_size as u64))?;
+            let mut data = Vec::with_capacity(chunk_size as usize);
+            file.by_ref()
+                .take(chunk_size as u64)
+                .read_to_end(&mut data)?;
+            self.segments.insert(
+                sequence,
+                Segment {
+                    data,
+                    sent_at: Instant::now(),
+                    retransmitted: true,
+                },
+            );
+            self.lost.insert(sequence);
+        }
+        self.cumulative = self.cumulative.min(msg.start);
+        //New segments go on where they left off
+        file.seek(SeekFrom::Start(next_sequence as u64 * chunk_size as u64))?;
+        Ok(())
+    }
 
-fn send_chunks<T: Iterator<Item = bool>>(
-    handler: &NetworkHandler,
-    chunks: &Vec<Vec<u8>>,
-    mask: &mut T,
-    partno: u32,
-) -> Option<()> {
-    let sender = handler.get_sender();
-    for (i, chunk) in (*chunks).iter().enumerate() {
-        if !(mask.next()?) {
-            //This chunk was NOT skipped
-            let msg = ChunkMessage {
-                data: chunk.to_owned(),
-                index: i as u32,
-                part_number: partno,
-            };
-            sender.send(Messages::Chunk(msg)).unwrap();
+    /// No acknowledgement arrived in time, everything in flight is lost
+    fn timeout(&mut self, next_sequence: u32, controller: &mut dyn CongestionController) {
+        let newly_lost: Vec<u32> = self
+            .segments
+            .keys()
+            .filter(|sequence| !self.lost.contains(sequence))
+            .cloned()
+            .collect();
+        self.recovery = 0;
+        self.mark_lost(newly_lost, next_sequence, controller);
+    }
+
+    fn mark_lost(
+        &mut self,
+        sequences: Vec<u32>,
+        next_sequence: u32,
+        controller: &mut dyn CongestionController,
+    ) {
+        //Only react once per window of data
+        if sequences.iter().any(|&sequence| sequence >= self.recovery) {
+            controller.on_loss(sequences.len() as u32);
+            self.recovery = next_sequence;
         }
+        self.lost.extend(sequences);
     }
-    Some(())
 }
 
-fn split<T: BufRead>(
+/// Sends the `segments` of the file with a selective repeat window.
+/// Earlier segments are only sent again if the receiver asks for them
+pub async fn send_window<T: Read + Seek>(
+    handler: &NetworkHandler,
+    mut receiver: Receiver<Messages>,
     file: &mut T,
-    chunk_size: usize,
-    chunk_count: &mut u32,
-) -> Option<Vec<Vec<u8>>> {
-    let mut v = Vec::new();
-    for i in 0..(*chunk_count) {
-        let mut buf = vec![0; chunk_size];
-        let slice = buf.as_mut_slice();
-        let c = file.read(slice).unwrap();
-        if c <= 0 {
+    chunk_size: u32,
+    segments: Range<u32>,
+    controller: &mut dyn CongestionController,
+) -> Result<(), Error> {
+    let sender = handler.get_sender();
+    let segment_count = segments.end;
+    let mut window = Window::new(handler.rto(), segments.start);
+    let mut pacer = Pacer::new();
+    let mut next_sequence = segments.start;
+    file.seek(SeekFrom::Start(next_sequence as u64 * chunk_size as u64))?;
+
+    loop {
+        //Process the acknowledgements which have already arrived
+        while let Ok(msg) = receiver.try_recv() {
+            window.on_message(msg, file, chunk_size, next_sequence, controller)?;
+        }
+        if window.cumulative >= segment_count || window.finished {
             break;
         }
-        buf.truncate(c);
-        v.push(buf);
+        window.rto = handler.rto();
+        let can_send_new =
+            next_sequence < segment_count && next_sequence < window.cumulative + MAX_WINDOW;
+        if window.pipe() >= controller.window() as usize
+            || (window.lost.is_empty() && !can_send_new)
+        {
+            //Nothing can be sent right now, wait for acknowledgements
+            match tokio::time::timeout(handler.rto(), handler.recv(&mut receiver)).await {
+                Ok(Ok(msg)) => {
+                    window.on_message(msg, file, chunk_size, next_sequence, controller)?
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => window.timeout(next_sequence, controller),
+            }
+            continue;
+        }
+
+        pacer.wait(controller).await;
+        let sequence = if let Some(&sequence) = window.lost.iter().next() {
+            window.lost.remove(&sequence);
+            let segment = window.segments.get_mut(&sequence).unwrap();
+            segment.sent_at = Instant::now();
+            segment.retransmitted = true;
+            sequence
+        } else {
+            let mut data = Vec::with_capacity(chunk_size as usize);
+            file.by_ref()
+                .take(chunk_size as u64)
+                .read_to_end(&mut data)?;
+            window.segments.insert(
+                next_sequence,
+                Segment {
+                    data,
+                    sent_at: Instant::now(),
+                    retransmitted: false,
+                },
+            );
+            next_sequence += 1;
+            next_sequence - 1
+        };
+        let msg = SegmentMessage::new(sequence, window.segments[&sequence].data.clone());
+        sender.send(Messages::Segment(msg))?;
     }
-    *chunk_count = v.len() as u32;
-    Some(v)
+    Ok(())
 }
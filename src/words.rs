@@ -0,0 +1,63 @@
+/// Words of the short rendezvous codes and word codes, sorted, one per byte
+pub const WORDS: [&str; 256] = [
+    "acorn", "agate", "alarm", "album", "alder", "amber", "anchor", "angel", "ankle", "apple",
+    "apron", "arch", "arrow", "aspen", "atlas", "attic", "autumn", "badge", "bagel", "baker",
+    "bamboo", "banjo", "barn", "basil", "basket", "beacon", "beaver", "bell", "berry", "birch",
+    "bison", "blade", "blanket", "blossom", "boat", "bonnet", "book", "boulder", "bramble",
+    "brass", "bread", "brick", "bridge", "brook", "broom", "bucket", "buckle", "bugle", "butter",
+    "button", "cabin", "cactus", "camel", "candle", "canoe", "canyon", "captain", "carpet",
+    "carrot", "castle", "cedar", "cellar", "chalk", "cherry", "chess", "chimney", "cider",
+    "circus", "clay", "cliff", "clock", "cloud", "clover", "cobra", "comet", "compass", "copper",
+    "coral", "cotton", "cougar", "crane", "crayon", "cricket", "crossbow", "crown", "crystal",
+    "cupboard", "daisy", "dancer", "delta", "desert", "diamond", "dolphin", "dragon", "drum",
+    "eagle", "easel", "echo", "elbow", "ember", "falcon", "feather", "fern", "ferry", "fiddle",
+    "finch", "flag", "flute", "forest", "fossil", "fountain", "fox", "galaxy", "garden", "garlic",
+    "geyser", "ginger", "glacier", "globe", "goblet", "granite", "grape", "gravel", "guitar",
+    "hammer", "harbor", "harp", "hazel", "helmet", "heron", "hickory", "honey", "horizon", "igloo",
+    "island", "ivory", "jacket", "jaguar", "jasmine", "jelly", "jigsaw", "jungle", "kayak",
+    "kettle", "kitten", "ladder", "lagoon", "lantern", "laurel", "lemon", "lily", "linen",
+    "lizard", "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "meteor", "mirror", "mitten", "monkey", "moss", "mountain", "mustard", "needle",
+    "nest", "nutmeg", "oasis", "ocean", "olive", "onion", "orbit", "orchid", "otter", "owl",
+    "paddle", "palace", "panda", "parrot", "peach", "pebble", "pepper", "piano", "pillow", "pine",
+    "planet", "plum", "pocket", "pony", "poppy", "prism", "pumpkin", "puzzle", "quartz", "quill",
+    "rabbit", "radish", "raven", "reef", "ribbon", "river", "robin", "rocket", "saddle", "salmon",
+    "sapphire", "satchel", "scarf", "shell", "silver", "sketch", "sparrow", "spider", "spruce",
+    "squid", "star", "stone", "sunset", "swan", "tablet", "teapot", "thimble", "thistle",
+    "thunder", "tiger", "timber", "toast", "tomato", "topaz", "torch", "tractor", "trumpet",
+    "tulip", "tundra", "turtle", "umbrella", "valley", "velvet", "violet", "volcano", "wagon",
+    "walnut", "walrus", "whale", "willow", "window", "winter", "wizard", "wolf", "yacht", "zebra",
+];
+
+/// Index of `word` in the list
+pub fn index(word: &str) -> Option<u8> {
+    WORDS.binary_search(&word).ok().map(|i| i as u8)
+}
+
+/// Word of the list which is most similar to `word`, if any is similar enough
+pub fn closest(word: &str) -> Option<&'static str> {
+    let (distance, closest) = WORDS
+        .iter()
+        .map(|candidate| (distance(word, candidate), *candidate))
+        .min()?;
+    if distance <= 2 {
+        Some(closest)
+    } else {
+        None
+    }
+}
+
+/// Edit distance between two words
+pub fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != *cb) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}